@@ -0,0 +1,387 @@
+use std::process;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use metrics::ErrorKind;
+use queue::message::Message;
+use queue::queue_info::QueueInfo;
+use Client;
+
+const DEFAULT_CONCURRENCY: usize = 4;
+const DEFAULT_BATCH_SIZE: u8 = 10;
+const DEFAULT_BODY_SIZE: usize = 256;
+const DEFAULT_DURATION: u64 = 10;
+const RESERVATION_TIMEOUT: u32 = 60;
+const RESERVATION_WAIT: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Push,
+    Consume,
+    RoundTrip,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BenchConfig {
+    pub queue: String,
+    pub concurrency: usize,
+    pub batch_size: u8,
+    pub body_size: usize,
+    pub duration: Duration,
+    pub phases: Vec<Phase>,
+    #[serde(default)]
+    pub clear: bool,
+}
+
+impl BenchConfig {
+    pub fn new(queue: &str) -> BenchConfig {
+        BenchConfig {
+            queue: String::from(queue),
+            concurrency: DEFAULT_CONCURRENCY,
+            batch_size: DEFAULT_BATCH_SIZE,
+            body_size: DEFAULT_BODY_SIZE,
+            duration: Duration::from_secs(DEFAULT_DURATION),
+            phases: vec![Phase::Push, Phase::Consume, Phase::RoundTrip],
+            clear: false,
+        }
+    }
+
+    // A queue of a name no one else uses, created for the run and deleted
+    // after it.
+    pub fn temporary() -> BenchConfig {
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+
+        BenchConfig::new(&format!("bench-{}-{}", started.as_secs(), process::id()))
+    }
+
+    pub fn concurrency(&mut self, concurrency: usize) -> &mut BenchConfig {
+        self.concurrency = concurrency;
+
+        self
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut BenchConfig {
+        self.batch_size = batch_size;
+
+        self
+    }
+
+    pub fn body_size(&mut self, body_size: usize) -> &mut BenchConfig {
+        self.body_size = body_size;
+
+        self
+    }
+
+    pub fn duration(&mut self, duration: Duration) -> &mut BenchConfig {
+        self.duration = duration;
+
+        self
+    }
+
+    pub fn phases(&mut self, phases: Vec<Phase>) -> &mut BenchConfig {
+        self.phases = phases;
+
+        self
+    }
+
+    // Allows running against a queue that already exists, deleting all its
+    // messages before and after the run.
+    pub fn clear(&mut self, clear: bool) -> &mut BenchConfig {
+        self.clear = clear;
+
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Latency {
+    pub mean: f64,
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OperationReport {
+    pub operation: String,
+    pub requests: usize,
+    pub messages: usize,
+    pub errors: usize,
+    pub requests_per_sec: f64,
+    pub messages_per_sec: f64,
+    pub latency_ms: Latency,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BenchReport {
+    pub client_version: String,
+    pub config: BenchConfig,
+    pub operations: Vec<OperationReport>,
+}
+
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    messages: usize,
+    errors: usize,
+}
+
+impl Samples {
+    fn record(&mut self, started: Instant, result: Result<usize, String>) {
+        match result {
+            Ok(messages) => {
+                self.latencies.push(started.elapsed());
+                self.messages += messages;
+            }
+            Err(_) => self.errors += 1,
+        }
+    }
+
+    fn merge(&mut self, other: Samples) {
+        self.latencies.extend(other.latencies);
+        self.messages += other.messages;
+        self.errors += other.errors;
+    }
+
+    fn report(mut self, operation: &str, elapsed: Duration) -> OperationReport {
+        self.latencies.sort();
+        let secs = as_secs(elapsed).max(0.001);
+        let requests = self.latencies.len();
+
+        let mean = if requests == 0 {
+            0.0
+        } else {
+            self.latencies.iter().map(|d| as_millis(*d)).sum::<f64>() / requests as f64
+        };
+
+        OperationReport {
+            operation: String::from(operation),
+            requests,
+            messages: self.messages,
+            errors: self.errors,
+            requests_per_sec: requests as f64 / secs,
+            messages_per_sec: self.messages as f64 / secs,
+            latency_ms: Latency {
+                mean,
+                p50: percentile(&self.latencies, 50.0),
+                p90: percentile(&self.latencies, 90.0),
+                p99: percentile(&self.latencies, 99.0),
+                max: self.latencies.last().map(|d| as_millis(*d)).unwrap_or(0.0),
+            },
+        }
+    }
+}
+
+// Runs every configured phase against `config.queue`. Each worker thread
+// gets its own client from `client`, since a `Client` drives its own reactor.
+//
+// A queue that doesn't exist is created and deleted afterwards. One that
+// does is only used if `clear` is set, as its messages are deleted.
+pub fn run<F>(config: &BenchConfig, client: F) -> Result<BenchReport, String>
+where
+    F: Fn() -> Client + Sync,
+{
+    let mut setup = client();
+    let created = match setup.queue(config.queue.clone()).get_info() {
+        Ok(_) if config.clear => false,
+        Ok(_) => return Err(format!("Queue {} exists, bench it with clear set or use another queue", config.queue)),
+        Err(ref e) if ErrorKind::classify(e) == ErrorKind::NotFound => {
+            setup.try_create_queue_with_config(&config.queue, &QueueInfo::new(config.queue.clone()))?;
+            true
+        }
+        Err(e) => return Err(e),
+    };
+    if !created {
+        setup.queue(config.queue.clone()).clear();
+    }
+
+    let mut operations = Vec::new();
+
+    for phase in &config.phases {
+        let results: Mutex<Vec<Vec<Samples>>> = Mutex::new(Vec::new());
+        let started = Instant::now();
+
+        thread::scope(|scope| {
+            for _ in 0..config.concurrency {
+                scope.spawn(|| {
+                    let mut client = client();
+                    let samples = run_phase(&mut client, config, *phase);
+                    results.lock().unwrap().push(samples);
+                });
+            }
+        });
+
+        let elapsed = started.elapsed();
+        let names: &[&str] = match *phase {
+            Phase::Push => &["push"],
+            Phase::Consume => &["reserve", "delete"],
+            Phase::RoundTrip => &["round_trip"],
+        };
+
+        let mut merged: Vec<Samples> = names.iter().map(|_| Samples::default()).collect();
+        for samples in results.into_inner().unwrap() {
+            for (total, samples) in merged.iter_mut().zip(samples) {
+                total.merge(samples);
+            }
+        }
+        for (name, samples) in names.iter().zip(merged) {
+            operations.push(samples.report(name, elapsed));
+        }
+    }
+
+    if created {
+        setup.queue(config.queue.clone()).try_delete()?;
+    } else {
+        setup.queue(config.queue.clone()).clear();
+    }
+
+    Ok(BenchReport {
+        client_version: String::from(env!("CARGO_PKG_VERSION")),
+        config: config.clone(),
+        operations,
+    })
+}
+
+fn run_phase(client: &mut Client, config: &BenchConfig, phase: Phase) -> Vec<Samples> {
+    let mut queue = client.queue(config.queue.clone());
+    let body: String = (0..config.body_size).map(|_| 'x').collect();
+    let batch = || (0..config.batch_size).map(|_| Message::with_body(&body)).collect::<Vec<Message>>();
+    let deadline = Instant::now() + config.duration;
+
+    let mut first = Samples::default();
+    let mut second = Samples::default();
+
+    while Instant::now() < deadline {
+        match phase {
+            Phase::Push => {
+                let started = Instant::now();
+                let result = queue.push_messages(batch()).map(|ids| ids.len());
+                first.record(started, result);
+            }
+            Phase::Consume => {
+                let started = Instant::now();
+                let messages = queue.long_poll(config.batch_size, RESERVATION_TIMEOUT, RESERVATION_WAIT, false);
+                let messages = match messages {
+                    Ok(messages) => {
+                        first.record(started, Ok(messages.len()));
+                        messages
+                    }
+                    Err(e) => {
+                        first.record(started, Err(e));
+                        continue;
+                    }
+                };
+
+                if !messages.is_empty() {
+                    let count = messages.len();
+                    let started = Instant::now();
+                    let msg = queue.delete_messages(messages);
+                    second.record(started, deleted(msg, count));
+                }
+            }
+            Phase::RoundTrip => {
+                let started = Instant::now();
+                let result = round_trip(&mut queue, batch(), config.batch_size);
+                first.record(started, result);
+            }
+        }
+    }
+
+    vec![first, second]
+}
+
+fn round_trip(queue: &mut ::queue::Queue, messages: Vec<Message>, count: u8) -> Result<usize, String> {
+    queue.push_messages(messages)?;
+
+    let mut received = 0;
+    while received < count as usize {
+        let messages = queue.long_poll(count - received as u8, RESERVATION_TIMEOUT, RESERVATION_WAIT, false)?;
+        if messages.is_empty() {
+            return Err("No messages reserved".to_string());
+        }
+
+        let batch = messages.len();
+        deleted(queue.delete_messages(messages), batch)?;
+        received += batch;
+    }
+
+    Ok(received)
+}
+
+fn deleted(msg: String, count: usize) -> Result<usize, String> {
+    if msg.contains("Deleted") {
+        Ok(count)
+    } else {
+        Err(msg)
+    }
+}
+
+fn percentile(sorted: &[Duration], percentile: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+
+    let rank = (percentile / 100.0 * sorted.len() as f64).ceil() as usize;
+    as_millis(sorted[rank.max(1).min(sorted.len()) - 1])
+}
+
+fn as_secs(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    as_secs(duration) * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{client, FakeServer, Response};
+
+    fn quick(queue: &str) -> BenchConfig {
+        let mut config = BenchConfig::new(queue);
+        config.phases(vec![Phase::Push]).concurrency(1).duration(Duration::from_millis(10));
+        config
+    }
+
+    #[test]
+    fn creates_and_deletes_its_queue() {
+        let server = FakeServer::start(|request| match (request.method.as_str(), request.path.ends_with("/queues/bench")) {
+            ("GET", true) => Response::status("404 Not Found", r#"{"msg":"Queue not found"}"#),
+            ("PUT", true) => Response::ok(r#"{"queue":{"name":"bench"}}"#),
+            ("DELETE", true) => Response::ok(r#"{"msg":"Deleted"}"#),
+            _ => Response::ok(r#"{"ids":["1"]}"#),
+        });
+        let addr = server.addr();
+
+        let report = run(&quick("bench"), || client(&addr)).unwrap();
+
+        assert_eq!(report.operations[0].errors, 0);
+        let lines = server.lines();
+        assert_eq!(lines[1], "PUT /3/projects/project/queues/bench");
+        assert_eq!(lines.last().unwrap(), "DELETE /3/projects/project/queues/bench");
+        assert!(!lines.contains(&"DELETE /3/projects/project/queues/bench/messages".to_string()));
+    }
+
+    #[test]
+    fn leaves_existing_queues_alone() {
+        let server = FakeServer::constant("200 OK", r#"{"queue":{"name":"orders","size":3}}"#);
+        let addr = server.addr();
+
+        assert!(run(&quick("orders"), || client(&addr)).is_err());
+        assert_eq!(server.lines(), vec!["GET /3/projects/project/queues/orders"]);
+    }
+
+    #[test]
+    fn percentiles() {
+        let latencies: Vec<Duration> = (1..101).map(Duration::from_millis).collect();
+
+        assert_eq!(percentile(&latencies, 50.0), 50.0);
+        assert_eq!(percentile(&latencies, 90.0), 90.0);
+        assert_eq!(percentile(&latencies, 99.0), 99.0);
+        assert_eq!(percentile(&[], 99.0), 0.0);
+    }
+}
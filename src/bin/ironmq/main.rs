@@ -0,0 +1,630 @@
+extern crate clap;
+extern crate iron_mq_rust;
+extern crate serde;
+#[macro_use]
+extern crate serde_json;
+
+mod output;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+
+use iron_mq_rust::Client;
+use iron_mq_rust::config;
+use iron_mq_rust::bench::{self, BenchConfig};
+use iron_mq_rust::queue::backup::ExportMode;
+use iron_mq_rust::queue::message::Message;
+use iron_mq_rust::queue::queue_info::{QueueInfo, QueueSubscriber, QueueType};
+use iron_mq_rust::queue::tail::{BodyFilter, TailMode, TailOptions};
+
+use output::Format;
+
+const PUSH_BATCH_SIZE: usize = 100;
+
+fn main() {
+    let queue_arg = || Arg::with_name("queue").required(true);
+    let id_arg = || Arg::with_name("id").required(true);
+    let reservation_arg = || {
+        Arg::with_name("reservation-id")
+            .long("reservation-id")
+            .short("r")
+            .takes_value(true)
+            .required(true)
+    };
+    let config_args = || {
+        vec![
+            Arg::with_name("type")
+                .long("type")
+                .takes_value(true)
+                .possible_values(&["pull", "unicast", "multicast"]),
+            Arg::with_name("message-timeout").long("message-timeout").takes_value(true),
+            Arg::with_name("message-expiration").long("message-expiration").takes_value(true),
+        ]
+    };
+    let subscriber_args = || {
+        vec![
+            queue_arg(),
+            Arg::with_name("subscribers")
+                .required(true)
+                .multiple(true)
+                .help("Subscribers as name=url"),
+        ]
+    };
+
+    let matches = App::new("ironmq")
+        .about("Command-line client for IronMQ")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .global(true)
+                .takes_value(true)
+                .possible_values(&["table", "json"])
+                .default_value("table"),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .short("p")
+                .global(true)
+                .takes_value(true)
+                .help("Profile from iron.json, overrides IRON_PROFILE"),
+        )
+        .subcommand(SubCommand::with_name("profiles").about("Lists profiles of iron.json"))
+        .subcommand(
+            SubCommand::with_name("queues")
+                .about("Manages queues")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Lists queues")
+                        .arg(Arg::with_name("prefix").long("prefix").takes_value(true))
+                        .arg(Arg::with_name("prev").long("prev").takes_value(true))
+                        .arg(Arg::with_name("per-page").long("per-page").takes_value(true)),
+                )
+                .subcommand(SubCommand::with_name("info").about("Shows queue info").arg(queue_arg()))
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Creates a queue")
+                        .arg(queue_arg())
+                        .args(&config_args()),
+                )
+                .subcommand(
+                    SubCommand::with_name("update")
+                        .about("Updates queue configuration")
+                        .arg(queue_arg())
+                        .args(&config_args()),
+                )
+                .subcommand(SubCommand::with_name("delete").about("Deletes a queue").arg(queue_arg()))
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .about("Deletes all messages of a queue")
+                        .arg(queue_arg()),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("push")
+                .about("Pushes a message, or every line of a file or stdin")
+                .arg(queue_arg())
+                .arg(Arg::with_name("body").help("Message body, stdin if omitted"))
+                .arg(Arg::with_name("file").long("file").short("f").takes_value(true))
+                .arg(Arg::with_name("delay").long("delay").takes_value(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("reserve")
+                .about("Reserves messages")
+                .arg(queue_arg())
+                .arg(Arg::with_name("n").short("n").takes_value(true).default_value("1"))
+                .arg(Arg::with_name("timeout").long("timeout").takes_value(true).default_value("60"))
+                .arg(Arg::with_name("wait").long("wait").takes_value(true).default_value("0"))
+                .arg(Arg::with_name("delete").long("delete")),
+        )
+        .subcommand(
+            SubCommand::with_name("peek")
+                .about("Shows messages without reserving them")
+                .arg(queue_arg())
+                .arg(Arg::with_name("n").short("n").takes_value(true).default_value("1")),
+        )
+        .subcommand(
+            SubCommand::with_name("get")
+                .about("Gets a message by id")
+                .arg(queue_arg())
+                .arg(id_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("delete")
+                .about("Deletes a reserved message")
+                .arg(queue_arg())
+                .arg(id_arg())
+                .arg(reservation_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("release")
+                .about("Releases a reserved message")
+                .arg(queue_arg())
+                .arg(id_arg())
+                .arg(reservation_arg())
+                .arg(Arg::with_name("delay").long("delay").takes_value(true).default_value("0")),
+        )
+        .subcommand(
+            SubCommand::with_name("touch")
+                .about("Extends the reservation of a message")
+                .arg(queue_arg())
+                .arg(id_arg())
+                .arg(reservation_arg())
+                .arg(Arg::with_name("timeout").long("timeout").takes_value(true).default_value("60")),
+        )
+        .subcommand(
+            SubCommand::with_name("subscribers")
+                .about("Manages push queue subscribers")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("add").args(&subscriber_args()))
+                .subcommand(SubCommand::with_name("replace").args(&subscriber_args()))
+                .subcommand(SubCommand::with_name("remove").args(&subscriber_args())),
+        )
+        .subcommand(
+            SubCommand::with_name("push-status")
+                .about("Shows push statuses of a message")
+                .arg(queue_arg())
+                .arg(id_arg()),
+        )
+        .subcommand(
+            SubCommand::with_name("tail")
+                .about("Streams messages of a queue as NDJSON")
+                .arg(queue_arg())
+                .arg(
+                    Arg::with_name("consume")
+                        .long("consume")
+                        .help("Reserves messages instead of peeking at them"),
+                )
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .requires("consume")
+                        .help("Deletes consumed messages"),
+                )
+                .arg(
+                    Arg::with_name("filter")
+                        .long("filter")
+                        .takes_value(true)
+                        .help("JSON path the body must match, e.g. $.status=failed"),
+                )
+                .arg(Arg::with_name("interval").long("interval").takes_value(true).default_value("1"))
+                .arg(Arg::with_name("n").short("n").takes_value(true).default_value("100")),
+        )
+        .subcommand(
+            SubCommand::with_name("bench")
+                .about("Measures throughput and latency against a queue, reports JSON")
+                .arg(Arg::with_name("queue").help("Queue to create for the run, a temporary one if omitted"))
+                .arg(
+                    Arg::with_name("clear")
+                        .long("clear")
+                        .help("Allows benching an existing queue, deleting all its messages"),
+                )
+                .arg(Arg::with_name("concurrency").long("concurrency").short("c").takes_value(true))
+                .arg(Arg::with_name("batch-size").long("batch-size").short("b").takes_value(true))
+                .arg(Arg::with_name("body-size").long("body-size").takes_value(true))
+                .arg(
+                    Arg::with_name("duration")
+                        .long("duration")
+                        .short("d")
+                        .takes_value(true)
+                        .help("Seconds per phase"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Writes a queue and its messages to an NDJSON backup")
+                .arg(queue_arg())
+                .arg(Arg::with_name("file").help("Output file, stdout if omitted"))
+                .arg(
+                    Arg::with_name("drain")
+                        .long("drain")
+                        .help("Deletes exported messages from the queue"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Recreates a queue from an NDJSON backup")
+                .arg(Arg::with_name("file").help("Input file, stdin if omitted"))
+                .arg(
+                    Arg::with_name("queue")
+                        .long("queue")
+                        .takes_value(true)
+                        .help("Imports into a queue with another name"),
+                ),
+        )
+        .get_matches();
+
+    let format = Format::from_name(matches.value_of("output").unwrap_or("table"));
+
+    let result = match matches.subcommand() {
+        ("profiles", Some(_)) => profiles(format),
+        ("queues", Some(args)) => queues(format, args),
+        ("push", Some(args)) => push(format, args),
+        ("reserve", Some(args)) => reserve(format, args),
+        ("peek", Some(args)) => peek(format, args),
+        ("get", Some(args)) => get(format, args),
+        ("delete", Some(args)) => delete(format, args),
+        ("release", Some(args)) => release(format, args),
+        ("touch", Some(args)) => touch(format, args),
+        ("subscribers", Some(args)) => subscribers(format, args),
+        ("push-status", Some(args)) => push_status(format, args),
+        ("tail", Some(args)) => tail(args),
+        ("bench", Some(args)) => bench(args),
+        ("export", Some(args)) => export(args),
+        ("import", Some(args)) => import(args),
+        _ => unreachable!(),
+    };
+
+    if let Err(e) = result {
+        eprintln!("ironmq: {}", e.trim_matches('"'));
+        process::exit(1);
+    }
+}
+
+// The profile named with --profile or IRON_PROFILE, or the IRON_*
+// variables when there is no iron.json.
+fn client(args: &ArgMatches) -> Result<Client, String> {
+    let profile = match args.value_of("profile") {
+        Some(profile) => profile.to_string(),
+        None if Path::new(config::CONFIG_FILE).exists() => {
+            env::var("IRON_PROFILE").unwrap_or_else(|_| config::DEFAULT_PROFILE.to_string())
+        }
+        None => return Ok(Client::from_env()),
+    };
+
+    Client::try_from_profile(&profile)
+}
+
+// Prints the `msg` of a response, or fails with it unless it says `success`.
+fn checked(format: Format, msg: &str, success: &str) -> Result<(), String> {
+    if !msg.contains(success) {
+        return Err(msg.to_string());
+    }
+    output::msg(format, msg);
+
+    Ok(())
+}
+
+fn queue_name(args: &ArgMatches) -> String {
+    args.value_of("queue").unwrap().to_string()
+}
+
+fn number<T: std::str::FromStr>(args: &ArgMatches, name: &str) -> Result<Option<T>, String> {
+    match args.value_of(name) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("Invalid value for --{}: {}", name, value)),
+        None => Ok(None),
+    }
+}
+
+fn reserved_message(args: &ArgMatches) -> Message {
+    let mut message = Message::with_body("");
+    message.id = args.value_of("id").map(String::from);
+    message.reservation_id = args.value_of("reservation-id").map(String::from);
+    message
+}
+
+fn queue_config(args: &ArgMatches, create: bool) -> Result<QueueInfo, String> {
+    let mut config = QueueInfo::new(queue_name(args));
+    if !create {
+        config.queue_type = None;
+    }
+
+    match args.value_of("type") {
+        Some("unicast") => { config.queue_type(QueueType::Unicast); }
+        Some("multicast") => { config.queue_type(QueueType::Multicast); }
+        Some(_) => { config.queue_type(QueueType::Pull); }
+        None => {}
+    }
+    if let Some(timeout) = number(args, "message-timeout")? {
+        config.message_timeout(timeout);
+    }
+    if let Some(expiration) = number(args, "message-expiration")? {
+        config.message_expiration(expiration);
+    }
+
+    Ok(config)
+}
+
+fn profiles(format: Format) -> Result<(), String> {
+    let profiles = Client::profiles();
+
+    if format == Format::Json {
+        output::json(&profiles);
+    } else {
+        for profile in profiles {
+            println!("{}", profile);
+        }
+    }
+
+    Ok(())
+}
+
+fn queues(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let mut client = client(args)?;
+
+    match args.subcommand() {
+        ("list", Some(args)) => {
+            let prefix = args.value_of("prefix").unwrap_or("");
+            let prev = args.value_of("prev").unwrap_or("");
+            let per_page = number(args, "per-page")?.unwrap_or(30);
+            output::queues(format, &client.queue_list(prefix, prev, per_page));
+        }
+        ("info", Some(args)) => {
+            output::queue_info(format, &client.queue(queue_name(args)).get_info()?);
+        }
+        ("create", Some(args)) => {
+            let config = queue_config(args, true)?;
+            output::queue_info(format, &client.try_create_queue_with_config(&config.name, &config)?);
+        }
+        ("update", Some(args)) => {
+            let config = queue_config(args, false)?;
+            output::queue_info(format, &client.queue(queue_name(args)).update(&config)?);
+        }
+        ("delete", Some(args)) => {
+            client.queue(queue_name(args)).try_delete()?;
+            output::msg(format, "Deleted");
+        }
+        ("clear", Some(args)) => {
+            let msg = client.queue(queue_name(args)).clear();
+            checked(format, &msg, "Cleared")?;
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(())
+}
+
+fn push(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let delay: Option<u32> = number(args, "delay")?;
+
+    let bodies: Vec<String> = match (args.value_of("body"), args.value_of("file")) {
+        (Some(body), _) => vec![body.to_string()],
+        (None, Some(path)) => read_lines(BufReader::new(File::open(path).map_err(|e| e.to_string())?))?,
+        (None, None) => {
+            let mut input = String::new();
+            io::stdin().read_to_string(&mut input).map_err(|e| e.to_string())?;
+            read_lines(input.as_bytes())?
+        }
+    };
+
+    let mut client = client(args)?;
+    let mut queue = client.queue(queue_name(args));
+    let mut ids = Vec::new();
+
+    for chunk in bodies.chunks(PUSH_BATCH_SIZE) {
+        let messages = chunk
+            .iter()
+            .map(|body| {
+                let mut message = Message::with_body(body);
+                message.delay = delay;
+                message
+            })
+            .collect();
+        ids.extend(queue.push_messages(messages)?);
+    }
+
+    output::ids(format, &ids);
+
+    Ok(())
+}
+
+fn read_lines<R: BufRead>(reader: R) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    for line in reader.lines() {
+        let line = line.map_err(|e| e.to_string())?;
+        if !line.trim().is_empty() {
+            lines.push(line);
+        }
+    }
+
+    Ok(lines)
+}
+
+fn reserve(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let count = number(args, "n")?.unwrap_or(1);
+    let timeout = number(args, "timeout")?.unwrap_or(60);
+    let wait = number(args, "wait")?.unwrap_or(0);
+
+    let mut client = client(args)?;
+    let messages = client
+        .queue(queue_name(args))
+        .long_poll(count, timeout, wait, args.is_present("delete"))?;
+    output::messages(format, &messages);
+
+    Ok(())
+}
+
+fn peek(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let count = number(args, "n")?.unwrap_or(1);
+
+    let mut client = client(args)?;
+    let messages = client.queue(queue_name(args)).peek_messages(count)?;
+    output::messages(format, &messages);
+
+    Ok(())
+}
+
+fn get(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let id = args.value_of("id").unwrap().to_string();
+
+    let mut client = client(args)?;
+    let message = client.queue(queue_name(args)).get_message(&id)?;
+    output::messages(format, &[message]);
+
+    Ok(())
+}
+
+fn delete(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let mut client = client(args)?;
+    let msg = client.queue(queue_name(args)).delete_message(reserved_message(args));
+
+    checked(format, &msg, "Deleted")
+}
+
+fn release(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let delay = number(args, "delay")?.unwrap_or(0);
+
+    let mut client = client(args)?;
+    let msg = client
+        .queue(queue_name(args))
+        .release_message(reserved_message(args), delay);
+
+    checked(format, &msg, "Released")
+}
+
+fn touch(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let timeout = number(args, "timeout")?.unwrap_or(60);
+
+    let mut client = client(args)?;
+    let reservation_id = client
+        .queue(queue_name(args))
+        .touch_message_with_timeout(reserved_message(args), timeout)?;
+
+    if format == Format::Json {
+        output::json(&json!({ "reservation_id": reservation_id }));
+    } else {
+        println!("{}", reservation_id);
+    }
+
+    Ok(())
+}
+
+fn subscribers(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let (action, args) = match args.subcommand() {
+        (action, Some(args)) => (action, args),
+        _ => unreachable!(),
+    };
+
+    let mut subscribers = Vec::new();
+    for value in args.values_of("subscribers").unwrap() {
+        let mut parts = value.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(url)) => subscribers.push(QueueSubscriber::new(name, url)),
+            _ => return Err(format!("Subscriber must be name=url: {}", value)),
+        }
+    }
+
+    let mut client = client(args)?;
+    let mut queue = client.queue(queue_name(args));
+    let msg = match action {
+        "add" => queue.add_subscribers(subscribers),
+        "replace" => queue.replace_subscribers(subscribers),
+        "remove" => queue.remove_subscribers(subscribers),
+        _ => unreachable!(),
+    };
+
+    checked(format, &msg, "Updated")
+}
+
+fn push_status(format: Format, args: &ArgMatches) -> Result<(), String> {
+    let id = args.value_of("id").unwrap().to_string();
+
+    let mut client = client(args)?;
+    let statuses = client.queue(queue_name(args)).get_push_statuses(id)?;
+    output::push_statuses(format, &statuses);
+
+    Ok(())
+}
+
+fn tail(args: &ArgMatches) -> Result<(), String> {
+    let mode = if args.is_present("consume") {
+        TailMode::Reserve { delete: args.is_present("delete") }
+    } else {
+        TailMode::Peek
+    };
+
+    let mut options = TailOptions::new(mode);
+    options
+        .batch_size(number(args, "n")?.unwrap_or(100))
+        .interval(Duration::from_secs(number(args, "interval")?.unwrap_or(1)));
+    if let Some(expression) = args.value_of("filter") {
+        options.filter(BodyFilter::parse(expression)?);
+    }
+
+    let mut client = client(args)?;
+    let mut queue = client.queue(queue_name(args));
+    let stdout = io::stdout();
+
+    for message in queue.tail(options) {
+        let line = serde_json::to_string(&message?).map_err(|e| e.to_string())?;
+        let mut out = stdout.lock();
+        writeln!(out, "{}", line).and_then(|_| out.flush()).map_err(|e| e.to_string())?;
+    }
+
+    Ok(())
+}
+
+fn bench(args: &ArgMatches) -> Result<(), String> {
+    let mut config = match args.value_of("queue") {
+        Some(queue) => BenchConfig::new(queue),
+        None => BenchConfig::temporary(),
+    };
+    config.clear(args.is_present("clear"));
+    if let Some(concurrency) = number(args, "concurrency")? {
+        config.concurrency(concurrency);
+    }
+    if let Some(batch_size) = number(args, "batch-size")? {
+        config.batch_size(batch_size);
+    }
+    if let Some(body_size) = number(args, "body-size")? {
+        config.body_size(body_size);
+    }
+    if let Some(duration) = number(args, "duration")? {
+        config.duration(Duration::from_secs(duration));
+    }
+
+    // Every worker thread builds its own client, once the profile is known
+    // to load.
+    client(args)?;
+    output::json(&bench::run(&config, || client(args).unwrap())?);
+
+    Ok(())
+}
+
+fn export(args: &ArgMatches) -> Result<(), String> {
+    let mode = if args.is_present("drain") {
+        ExportMode::Drain
+    } else {
+        ExportMode::Copy
+    };
+
+    let writer: Box<dyn Write> = match args.value_of("file") {
+        Some(path) => Box::new(File::create(path).map_err(|e| e.to_string())?),
+        None => Box::new(io::stdout()),
+    };
+
+    let mut client = client(args)?;
+    let mut queue = client.queue(queue_name(args));
+    let count = queue.export(BufWriter::new(writer), mode)?;
+    eprintln!("Exported {} messages", count);
+
+    Ok(())
+}
+
+fn import(args: &ArgMatches) -> Result<(), String> {
+    let reader: Box<dyn BufRead> = match args.value_of("file") {
+        Some(path) => Box::new(BufReader::new(File::open(path).map_err(|e| e.to_string())?)),
+        None => Box::new(BufReader::new(io::stdin())),
+    };
+
+    let mut client = client(args)?;
+    let count = match args.value_of("queue") {
+        Some(name) => client.import_as(reader, name)?,
+        None => client.import(reader)?,
+    };
+    eprintln!("Imported {} messages", count);
+
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use serde::Serialize;
+use serde_json;
+
+use iron_mq_rust::queue::message::Message;
+use iron_mq_rust::queue::queue_info::{PushStatus, QueueInfo, QueueType};
+
+const BODY_WIDTH: usize = 60;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Format {
+        match name {
+            "json" => Format::Json,
+            _ => Format::Table,
+        }
+    }
+}
+
+pub fn json<T: Serialize>(value: &T) {
+    println!("{}", serde_json::to_string_pretty(value).expect("Json serialization error"));
+}
+
+pub fn queues(format: Format, queues: &[QueueInfo]) {
+    if format == Format::Json {
+        return json(&queues);
+    }
+
+    let rows = queues
+        .iter()
+        .map(|q| vec![q.name.clone(), optional(&q.size), optional(&q.total_messages)])
+        .collect();
+    table(&["NAME", "SIZE", "TOTAL"], rows);
+}
+
+pub fn queue_info(format: Format, info: &QueueInfo) {
+    if format == Format::Json {
+        return json(info);
+    }
+
+    let queue_type = match info.queue_type {
+        Some(QueueType::Pull) => "pull",
+        Some(QueueType::Unicast) => "unicast",
+        Some(QueueType::Multicast) => "multicast",
+        None => "",
+    };
+
+    let rows = vec![
+        vec!["name".to_string(), info.name.clone()],
+        vec!["type".to_string(), queue_type.to_string()],
+        vec!["size".to_string(), optional(&info.size)],
+        vec!["total_messages".to_string(), optional(&info.total_messages)],
+        vec!["message_timeout".to_string(), optional(&info.message_timeout)],
+        vec!["message_expiration".to_string(), optional(&info.message_expiration)],
+        vec![
+            "subscribers".to_string(),
+            info.push.as_ref().map(|p| p.subscribers.len().to_string()).unwrap_or_default(),
+        ],
+        vec![
+            "alerts".to_string(),
+            info.alerts.as_ref().map(|a| a.len().to_string()).unwrap_or_default(),
+        ],
+    ];
+    table(&["FIELD", "VALUE"], rows);
+}
+
+pub fn messages(format: Format, messages: &[Message]) {
+    if format == Format::Json {
+        return json(&messages);
+    }
+
+    let rows = messages
+        .iter()
+        .map(|m| {
+            vec![
+                m.id.clone().unwrap_or_default(),
+                m.reservation_id.clone().unwrap_or_default(),
+                optional(&m.reserved_count),
+                truncate(&m.body),
+            ]
+        })
+        .collect();
+    table(&["ID", "RESERVATION", "RESERVED", "BODY"], rows);
+}
+
+pub fn ids(format: Format, ids: &[String]) {
+    if format == Format::Json {
+        return json(&json!({ "ids": ids }));
+    }
+
+    for id in ids {
+        println!("{}", id);
+    }
+}
+
+pub fn msg(format: Format, msg: &str) {
+    // Queue methods hand back the raw JSON string of the response `msg`.
+    let msg = msg.trim_matches('"');
+
+    if format == Format::Json {
+        return json(&json!({ "msg": msg }));
+    }
+
+    println!("{}", msg);
+}
+
+pub fn push_statuses(format: Format, statuses: &[PushStatus]) {
+    if format == Format::Json {
+        return json(&statuses);
+    }
+
+    let rows = statuses
+        .iter()
+        .map(|s| {
+            vec![
+                s.subscriber_name.clone(),
+                optional(&s.status_code),
+                s.tries.to_string(),
+                s.retries_remaining.to_string(),
+                s.msg.clone().unwrap_or_default(),
+            ]
+        })
+        .collect();
+    table(&["SUBSCRIBER", "STATUS", "TRIES", "RETRIES LEFT", "MSG"], rows);
+}
+
+fn optional<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+fn truncate(body: &str) -> String {
+    let body = body.replace('\n', " ");
+    if body.chars().count() <= BODY_WIDTH {
+        return body;
+    }
+
+    let mut short: String = body.chars().take(BODY_WIDTH - 3).collect();
+    short.push_str("...");
+    short
+}
+
+fn table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+
+    let headers: Vec<String> = headers.iter().map(|h| h.to_string()).collect();
+    print_row(&headers, &widths);
+    for row in &rows {
+        print_row(row, &widths);
+    }
+}
+
+fn print_row(cells: &[String], widths: &[usize]) {
+    let line: Vec<String> = cells
+        .iter()
+        .zip(widths)
+        .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+        .collect();
+    println!("{}", line.join("  ").trim_end());
+}
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::fs::File;
+
+use serde_json;
+
+use http_client::keystone::KeystoneConfig;
+
+pub const CONFIG_FILE: &str = "iron.json";
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Config {
+    pub project_id: String,
+    pub host: String,
+    #[serde(skip_serializing_if = "Option::is_none")] pub token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub keystone: Option<KeystoneConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")] pub alternates: Option<Vec<String>>,
+}
+
+// Top-level keys of iron.json form the default profile. Named profiles only
+// need the keys that differ from it:
+//
+//     {
+//         "project_id": "...", "token": "...", "host": "mq-aws-eu-west-1-1.iron.io",
+//         "profiles": {
+//             "staging": { "project_id": "..." }
+//         }
+//     }
+//
+// `alternates` lists further hosts of the same cluster to fail over to.
+//
+// On-premise installations authenticate with Keystone instead of a token:
+//
+//     "keystone": { "server": "...", "tenant": "...", "username": "...", "password": "..." }
+#[derive(Debug, Deserialize, Default, Clone)]
+struct Profile {
+    project_id: Option<String>,
+    token: Option<String>,
+    host: Option<String>,
+    keystone: Option<KeystoneConfig>,
+    alternates: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(flatten)]
+    default: Profile,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+impl ConfigFile {
+    fn open(path: &str) -> Result<ConfigFile, String> {
+        let reader = File::open(path).map_err(|_| format!("{} not found", path))?;
+        serde_json::from_reader(reader).map_err(|e| format!("Json parsing error: {}", e))
+    }
+
+    fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.profiles.keys().cloned().collect();
+        names.sort();
+        if !names.iter().any(|n| n == DEFAULT_PROFILE) {
+            names.insert(0, DEFAULT_PROFILE.to_string());
+        }
+
+        names
+    }
+
+    fn resolve(&self, profile: &str) -> Result<Config, String> {
+        let selected = match self.profiles.get(profile) {
+            Some(selected) => selected.clone(),
+            None if profile == DEFAULT_PROFILE => Profile::default(),
+            None => return Err(format!("Unknown profile {}", profile)),
+        };
+
+        let value = |field: Option<String>, default: &Option<String>, name: &str| {
+            field
+                .or_else(|| default.clone())
+                .ok_or_else(|| format!("Missed {} in profile {}", name, profile))
+        };
+
+        // Credentials are taken as a whole from the profile if it has any,
+        // so a token of its own isn't overridden by a default Keystone block.
+        let (token, keystone) = if selected.token.is_some() || selected.keystone.is_some() {
+            (selected.token, selected.keystone)
+        } else {
+            (self.default.token.clone(), self.default.keystone.clone())
+        };
+        let token = match keystone {
+            Some(_) => token,
+            None => Some(value(token, &None, "token")?),
+        };
+
+        Ok(Config {
+            project_id: value(selected.project_id, &self.default.project_id, "project_id")?,
+            host: value(selected.host, &self.default.host, "host")?,
+            token,
+            keystone,
+            alternates: selected.alternates.or_else(|| self.default.alternates.clone()),
+        })
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &str, profile: &str) -> Result<Config, String> {
+        ConfigFile::open(path)?.resolve(profile)
+    }
+
+    pub fn profiles(path: &str) -> Result<Vec<String>, String> {
+        Ok(ConfigFile::open(path)?.names())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles_inherit_from_default() {
+        let file: ConfigFile = serde_json::from_str(
+            r#"{
+                "project_id": "default-project",
+                "token": "default-token",
+                "host": "mq-aws-eu-west-1-1.iron.io",
+                "profiles": {
+                    "staging": { "project_id": "staging-project" },
+                    "prod": { "project_id": "prod-project", "token": "prod-token" }
+                }
+            }"#,
+        ).unwrap();
+
+        assert_eq!(file.names(), vec!["default", "prod", "staging"]);
+
+        let default = file.resolve(DEFAULT_PROFILE).unwrap();
+        assert_eq!(default.project_id, "default-project");
+
+        let staging = file.resolve("staging").unwrap();
+        assert_eq!(staging.project_id, "staging-project");
+        assert_eq!(staging.token.unwrap(), "default-token");
+        assert_eq!(staging.host, "mq-aws-eu-west-1-1.iron.io");
+
+        let prod = file.resolve("prod").unwrap();
+        assert_eq!(prod.token.unwrap(), "prod-token");
+
+        assert!(file.resolve("dev").is_err());
+    }
+
+    #[test]
+    fn keystone_profile() {
+        let file: ConfigFile = serde_json::from_str(
+            r#"{
+                "project_id": "project",
+                "host": "mq.example.com",
+                "keystone": {
+                    "server": "http://keystone.example.com:5000/v2.0/",
+                    "tenant": "tenant",
+                    "username": "user",
+                    "password": "secret"
+                }
+            }"#,
+        ).unwrap();
+
+        let config = file.resolve(DEFAULT_PROFILE).unwrap();
+        assert!(config.token.is_none());
+        assert_eq!(config.keystone.unwrap().tenant, "tenant");
+    }
+
+    #[test]
+    fn profile_credentials_override_default_keystone() {
+        let file: ConfigFile = serde_json::from_str(
+            r#"{
+                "project_id": "project",
+                "host": "mq.example.com",
+                "keystone": {
+                    "server": "http://keystone.example.com:5000/v2.0/",
+                    "tenant": "tenant",
+                    "username": "user",
+                    "password": "secret"
+                },
+                "profiles": {
+                    "direct": { "token": "direct-token" },
+                    "staging": { "project_id": "staging-project" }
+                }
+            }"#,
+        ).unwrap();
+
+        let direct = file.resolve("direct").unwrap();
+        assert_eq!(direct.token.unwrap(), "direct-token");
+        assert!(direct.keystone.is_none());
+
+        let staging = file.resolve("staging").unwrap();
+        assert!(staging.token.is_none());
+        assert_eq!(staging.keystone.unwrap().tenant, "tenant");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use hyper::Uri;
+
+const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+const DEFAULT_COOLDOWN: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    // Requests go through.
+    Closed,
+    // Requests fail fast until the cool-down is over.
+    Open,
+    // A single request is let through as a probe: it closes the circuit if
+    // it succeeds and opens it again if it fails. Other requests fail fast
+    // until then.
+    HalfOpen,
+}
+
+type StateCallback = Box<dyn Fn(&str, CircuitState, CircuitState)>;
+
+struct Circuit {
+    state: CircuitState,
+    failures: u32,
+    opened_at: Option<Instant>,
+    probe_sent_at: Option<Instant>,
+}
+
+// Tracks every endpoint (`host:port`) separately. A circuit opens after
+// `failure_threshold` consecutive failures (connection errors, timeouts and
+// 5xx responses) and lets a probe through once `cooldown` has passed.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    circuits: HashMap<String, Circuit>,
+    on_state_change: Option<StateCallback>,
+}
+
+impl CircuitBreaker {
+    pub fn new() -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            cooldown: Duration::from_secs(DEFAULT_COOLDOWN),
+            circuits: HashMap::new(),
+            on_state_change: None,
+        }
+    }
+
+    pub fn failure_threshold(&mut self, failure_threshold: u32) -> &mut CircuitBreaker {
+        self.failure_threshold = failure_threshold.max(1);
+
+        self
+    }
+
+    pub fn cooldown(&mut self, cooldown: Duration) -> &mut CircuitBreaker {
+        self.cooldown = cooldown;
+
+        self
+    }
+
+    // Called with the endpoint, the previous state and the new one.
+    pub fn on_state_change<F>(&mut self, callback: F) -> &mut CircuitBreaker
+    where
+        F: Fn(&str, CircuitState, CircuitState) + 'static,
+    {
+        self.on_state_change = Some(Box::new(callback));
+
+        self
+    }
+
+    pub fn state(&self, endpoint: &str) -> CircuitState {
+        self.circuits.get(endpoint).map_or(CircuitState::Closed, |c| c.state)
+    }
+
+    // Whether a request to `endpoint` may be sent now. A probe that wasn't
+    // reported back within the cool-down is taken as lost, and another one
+    // is let through.
+    pub fn allow(&mut self, endpoint: &str) -> bool {
+        let cooldown = self.cooldown;
+        let circuit = match self.circuits.get_mut(endpoint) {
+            Some(circuit) => circuit,
+            None => return true,
+        };
+
+        let since = match circuit.state {
+            CircuitState::Closed => return true,
+            CircuitState::Open => circuit.opened_at,
+            CircuitState::HalfOpen => circuit.probe_sent_at,
+        };
+        if since.is_some_and(|at| at.elapsed() < cooldown) {
+            return false;
+        }
+
+        circuit.probe_sent_at = Some(Instant::now());
+        self.transition(endpoint, CircuitState::HalfOpen);
+        true
+    }
+
+    pub fn success(&mut self, endpoint: &str) {
+        if let Some(circuit) = self.circuits.get_mut(endpoint) {
+            circuit.failures = 0;
+            circuit.probe_sent_at = None;
+        }
+        self.transition(endpoint, CircuitState::Closed);
+    }
+
+    pub fn failure(&mut self, endpoint: &str) {
+        let threshold = self.failure_threshold;
+        let circuit = self.circuits.entry(endpoint.to_string()).or_insert(Circuit {
+            state: CircuitState::Closed,
+            failures: 0,
+            opened_at: None,
+            probe_sent_at: None,
+        });
+        circuit.failures += 1;
+
+        if circuit.state == CircuitState::HalfOpen || circuit.failures >= threshold {
+            circuit.opened_at = Some(Instant::now());
+            circuit.probe_sent_at = None;
+            self.transition(endpoint, CircuitState::Open);
+        }
+    }
+
+    fn transition(&mut self, endpoint: &str, state: CircuitState) {
+        let previous = match self.circuits.get_mut(endpoint) {
+            Some(circuit) => {
+                let previous = circuit.state;
+                circuit.state = state;
+                previous
+            }
+            None => CircuitState::Closed,
+        };
+
+        if previous != state {
+            if let Some(ref callback) = self.on_state_change {
+                callback(endpoint, previous, state);
+            }
+        }
+    }
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker {
+        CircuitBreaker::new()
+    }
+}
+
+pub fn endpoint(url: &str) -> String {
+    match url.parse::<Uri>() {
+        Ok(uri) => {
+            let https = uri.scheme() == Some("https");
+            let port = uri.port().unwrap_or(if https { 443 } else { 80 });
+            format!("{}:{}", uri.host().unwrap_or(""), port)
+        }
+        Err(_) => url.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use std::thread;
+
+    #[test]
+    fn opens_and_recovers() {
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let recorded = changes.clone();
+
+        let mut breaker = CircuitBreaker::new();
+        breaker
+            .failure_threshold(2)
+            .cooldown(Duration::from_millis(50))
+            .on_state_change(move |endpoint, from, to| recorded.borrow_mut().push((endpoint.to_string(), from, to)));
+
+        breaker.failure("mq:443");
+        assert!(breaker.allow("mq:443"));
+        breaker.failure("mq:443");
+        assert!(!breaker.allow("mq:443"));
+        assert!(breaker.allow("other:443"));
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow("mq:443"));
+        breaker.failure("mq:443");
+        assert_eq!(breaker.state("mq:443"), CircuitState::Open);
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow("mq:443"));
+        breaker.success("mq:443");
+
+        use self::CircuitState::*;
+        let mq = "mq:443".to_string();
+        assert_eq!(
+            *changes.borrow(),
+            vec![
+                (mq.clone(), Closed, Open),
+                (mq.clone(), Open, HalfOpen),
+                (mq.clone(), HalfOpen, Open),
+                (mq.clone(), Open, HalfOpen),
+                (mq, HalfOpen, Closed),
+            ]
+        );
+    }
+
+    #[test]
+    fn lets_one_probe_through() {
+        let mut breaker = CircuitBreaker::new();
+        breaker.failure_threshold(1).cooldown(Duration::from_millis(50));
+        breaker.failure("mq:443");
+
+        thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow("mq:443"));
+        assert!(!breaker.allow("mq:443"));
+        assert!(!breaker.allow("mq:443"));
+        assert_eq!(breaker.state("mq:443"), CircuitState::HalfOpen);
+
+        // The probe got lost.
+        thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow("mq:443"));
+        assert!(!breaker.allow("mq:443"));
+
+        breaker.success("mq:443");
+        assert!(breaker.allow("mq:443"));
+        assert!(breaker.allow("mq:443"));
+    }
+
+    #[test]
+    fn endpoints() {
+        assert_eq!(endpoint("https://mq.iron.io/3/projects/p/queues"), "mq.iron.io:443");
+        assert_eq!(endpoint("http://127.0.0.1:8080/"), "127.0.0.1:8080");
+    }
+}
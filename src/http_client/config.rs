@@ -0,0 +1,114 @@
+extern crate num_cpus;
+
+use std::time::Duration;
+
+use super::proxy::ProxyConfig;
+use super::tls::TlsConfig;
+
+const DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+const DEFAULT_REQUEST_TIMEOUT: u64 = 60;
+const DEFAULT_POOL_IDLE_TIMEOUT: u64 = 90;
+const DEFAULT_LOG_BODY_LIMIT: usize = 1024;
+
+// Settings of the underlying hyper client. hyper 0.11 doesn't cap the number
+// of idle connections, so the pool is tuned by `pool_idle_timeout` (how long
+// an idle connection is kept) and `keep_alive` (whether it is kept at all).
+// As requests are sent one at a time, at most one connection per host is
+// idle.
+#[derive(Debug, Clone)]
+pub struct HttpConfig {
+    pub connect_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub pool_idle_timeout: Option<Duration>,
+    pub keep_alive: bool,
+    pub dns_threads: usize,
+    pub user_agent: String,
+    pub headers: Vec<(String, String)>,
+    pub proxy: ProxyConfig,
+    pub tls: TlsConfig,
+    pub log_body_limit: Option<usize>,
+}
+
+impl HttpConfig {
+    pub fn new() -> HttpConfig {
+        HttpConfig {
+            connect_timeout: Some(Duration::from_secs(DEFAULT_CONNECT_TIMEOUT)),
+            request_timeout: Some(Duration::from_secs(DEFAULT_REQUEST_TIMEOUT)),
+            pool_idle_timeout: Some(Duration::from_secs(DEFAULT_POOL_IDLE_TIMEOUT)),
+            keep_alive: true,
+            dns_threads: num_cpus::get(),
+            user_agent: format!("iron_mq_rust/{}", env!("CARGO_PKG_VERSION")),
+            headers: Vec::new(),
+            proxy: ProxyConfig::new(),
+            tls: TlsConfig::new(),
+            log_body_limit: Some(DEFAULT_LOG_BODY_LIMIT),
+        }
+    }
+
+    pub fn connect_timeout(&mut self, timeout: Option<Duration>) -> &mut HttpConfig {
+        self.connect_timeout = timeout;
+
+        self
+    }
+
+    pub fn request_timeout(&mut self, timeout: Option<Duration>) -> &mut HttpConfig {
+        self.request_timeout = timeout;
+
+        self
+    }
+
+    pub fn pool_idle_timeout(&mut self, timeout: Option<Duration>) -> &mut HttpConfig {
+        self.pool_idle_timeout = timeout;
+
+        self
+    }
+
+    pub fn keep_alive(&mut self, keep_alive: bool) -> &mut HttpConfig {
+        self.keep_alive = keep_alive;
+
+        self
+    }
+
+    pub fn dns_threads(&mut self, dns_threads: usize) -> &mut HttpConfig {
+        self.dns_threads = dns_threads;
+
+        self
+    }
+
+    pub fn user_agent(&mut self, user_agent: &str) -> &mut HttpConfig {
+        self.user_agent = String::from(user_agent);
+
+        self
+    }
+
+    pub fn header(&mut self, name: &str, value: &str) -> &mut HttpConfig {
+        self.headers.push((String::from(name), String::from(value)));
+
+        self
+    }
+
+    pub fn proxy(&mut self, proxy: ProxyConfig) -> &mut HttpConfig {
+        self.proxy = proxy;
+
+        self
+    }
+
+    pub fn tls(&mut self, tls: TlsConfig) -> &mut HttpConfig {
+        self.tls = tls;
+
+        self
+    }
+
+    // Bodies are only logged at trace level, cut to this many bytes.
+    pub fn log_body_limit(&mut self, limit: Option<usize>) -> &mut HttpConfig {
+        self.log_body_limit = limit;
+
+        self
+    }
+}
+
+impl Default for HttpConfig {
+    fn default() -> HttpConfig {
+        HttpConfig::new()
+    }
+}
@@ -0,0 +1,84 @@
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+extern crate tokio_service;
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use futures::Future;
+use futures::future::Either;
+use hyper::Uri;
+use hyper::client::Connect;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+
+// Fails a connection attempt (including the TLS handshake of the wrapped
+// connector) that takes longer than `timeout`.
+pub struct TimeoutConnector<C> {
+    connector: C,
+    timeout: Option<Duration>,
+    handle: Handle,
+}
+
+impl<C: Connect> TimeoutConnector<C> {
+    pub fn new(connector: C, timeout: Option<Duration>, handle: &Handle) -> TimeoutConnector<C> {
+        TimeoutConnector {
+            connector,
+            timeout,
+            handle: handle.clone(),
+        }
+    }
+}
+
+impl<C: Connect> Service for TimeoutConnector<C> {
+    type Request = Uri;
+    type Response = C::Output;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = C::Output, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Box<dyn Future<Item = C::Output, Error = io::Error>> {
+        let connecting = self.connector.connect(uri).map_err(ConnectError::wrap);
+
+        let timeout = match self.timeout {
+            Some(timeout) => timeout,
+            None => return Box::new(connecting),
+        };
+
+        let timer = match Timeout::new(timeout, &self.handle) {
+            Ok(timer) => timer,
+            Err(e) => return Box::new(futures::future::err(ConnectError::wrap(e))),
+        };
+
+        Box::new(connecting.select2(timer).then(|res| match res {
+            Ok(Either::A((io, _))) => Ok(io),
+            Ok(Either::B(_)) => Err(ConnectError::wrap(io::Error::new(io::ErrorKind::TimedOut, "Connect timed out"))),
+            Err(Either::A((e, _))) => Err(e),
+            Err(Either::B((e, _))) => Err(ConnectError::wrap(e)),
+        }))
+    }
+}
+
+// Marks errors raised while connecting, before any of the request was sent.
+#[derive(Debug)]
+pub struct ConnectError(io::Error);
+
+impl ConnectError {
+    fn wrap(error: io::Error) -> io::Error {
+        io::Error::new(error.kind(), ConnectError(error))
+    }
+
+    pub fn is(error: &io::Error) -> bool {
+        error.get_ref().is_some_and(|inner| inner.is::<ConnectError>())
+    }
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl Error for ConnectError {}
@@ -0,0 +1,227 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime};
+
+use serde_json::{self, Value};
+
+use super::config::HttpConfig;
+
+// Source of the OAuth token sent with every request. `token` is called before
+// each request and may return a cached value; `refresh` is called once the
+// server answered 401 and must not return the rejected token from its cache.
+pub trait CredentialProvider {
+    fn token(&mut self) -> Result<String, String>;
+
+    fn refresh(&mut self) -> Result<String, String> {
+        self.token()
+    }
+
+    // Called with the settings of the client the provider is set on, and
+    // whenever they change, for providers that send requests of their own.
+    fn http_config(&mut self, _config: &HttpConfig) {}
+}
+
+pub struct StaticCredentials {
+    token: String,
+}
+
+impl StaticCredentials {
+    pub fn new(token: String) -> StaticCredentials {
+        StaticCredentials { token }
+    }
+}
+
+impl CredentialProvider for StaticCredentials {
+    fn token(&mut self) -> Result<String, String> {
+        Ok(self.token.clone())
+    }
+}
+
+pub struct EnvCredentials {
+    variable: String,
+}
+
+impl EnvCredentials {
+    pub fn new(variable: &str) -> EnvCredentials {
+        EnvCredentials {
+            variable: String::from(variable),
+        }
+    }
+}
+
+impl Default for EnvCredentials {
+    fn default() -> EnvCredentials {
+        EnvCredentials::new("IRON_TOKEN")
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn token(&mut self) -> Result<String, String> {
+        env::var(&self.variable).map_err(|_| format!("Missed {} environment variable!", self.variable))
+    }
+}
+
+// Reads the token from a file holding either the bare token or JSON with a
+// `token` key (such as iron.json). The file is read again whenever its
+// modification time changes.
+pub struct FileCredentials {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    token: Option<String>,
+}
+
+impl FileCredentials {
+    pub fn new<P: AsRef<Path>>(path: P) -> FileCredentials {
+        FileCredentials {
+            path: path.as_ref().to_path_buf(),
+            modified: None,
+            token: None,
+        }
+    }
+
+    fn read(&mut self) -> Result<String, String> {
+        let mut content = String::new();
+        File::open(&self.path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .map_err(|e| format!("{}: {}", self.path.display(), e))?;
+
+        let content = content.trim();
+        let token = if content.starts_with('{') {
+            let v: Value = serde_json::from_str(content).map_err(|e| e.to_string())?;
+            v["token"]
+                .as_str()
+                .map(String::from)
+                .ok_or_else(|| format!("Missed token in {}", self.path.display()))?
+        } else {
+            content.to_string()
+        };
+
+        self.token = Some(token.clone());
+
+        Ok(token)
+    }
+
+    fn modified(&self) -> Option<SystemTime> {
+        fs::metadata(&self.path).and_then(|m| m.modified()).ok()
+    }
+}
+
+impl CredentialProvider for FileCredentials {
+    fn token(&mut self) -> Result<String, String> {
+        let modified = self.modified();
+
+        match self.token {
+            Some(ref token) if modified == self.modified => Ok(token.clone()),
+            _ => {
+                self.modified = modified;
+                self.read()
+            }
+        }
+    }
+
+    fn refresh(&mut self) -> Result<String, String> {
+        self.modified = self.modified();
+        self.read()
+    }
+}
+
+// Runs an external command and uses its trimmed stdout as the token, for
+// example a secrets manager CLI. The token is kept for `ttl`, or until the
+// server rejects it when no ttl is set.
+pub struct CommandCredentials {
+    program: String,
+    args: Vec<String>,
+    ttl: Option<Duration>,
+    cached: Option<(String, Instant)>,
+}
+
+impl CommandCredentials {
+    pub fn new(program: &str, args: Vec<String>) -> CommandCredentials {
+        CommandCredentials {
+            program: String::from(program),
+            args,
+            ttl: None,
+            cached: None,
+        }
+    }
+
+    pub fn ttl(&mut self, ttl: Duration) -> &mut CommandCredentials {
+        self.ttl = Some(ttl);
+
+        self
+    }
+
+    fn run(&mut self) -> Result<String, String> {
+        let output = Command::new(&self.program)
+            .args(&self.args)
+            .output()
+            .map_err(|e| format!("{}: {}", self.program, e))?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "{} exited with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let token = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if token.is_empty() {
+            return Err(format!("{} printed no token", self.program));
+        }
+        self.cached = Some((token.clone(), Instant::now()));
+
+        Ok(token)
+    }
+}
+
+impl CredentialProvider for CommandCredentials {
+    fn token(&mut self) -> Result<String, String> {
+        let expired = match (&self.cached, self.ttl) {
+            (&Some((_, fetched)), Some(ttl)) => fetched.elapsed() >= ttl,
+            (&Some(_), None) => false,
+            (&None, _) => true,
+        };
+
+        match self.cached {
+            Some((ref token, _)) if !expired => Ok(token.clone()),
+            _ => self.run(),
+        }
+    }
+
+    fn refresh(&mut self) -> Result<String, String> {
+        self.run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn file_credentials() {
+        let path = env::temp_dir().join(format!("iron-token-{}", ::std::process::id()));
+        File::create(&path).unwrap().write_all(b"{\"token\": \"first\"}\n").unwrap();
+
+        let mut credentials = FileCredentials::new(&path);
+        assert_eq!(credentials.token().unwrap(), "first");
+
+        File::create(&path).unwrap().write_all(b"second\n").unwrap();
+        assert_eq!(credentials.refresh().unwrap(), "second");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn command_credentials() {
+        let mut credentials = CommandCredentials::new("echo", vec!["secret-token".to_string()]);
+
+        assert_eq!(credentials.token().unwrap(), "secret-token");
+        assert!(CommandCredentials::new("false", vec![]).token().is_err());
+    }
+}
@@ -0,0 +1,137 @@
+use std::time::{Duration, Instant};
+
+use hyper::Uri;
+
+const DEFAULT_COOLDOWN: u64 = 30;
+
+struct Host {
+    name: String,
+    failures: u32,
+    down_until: Option<Instant>,
+}
+
+impl Host {
+    fn is_up(&self, now: Instant) -> bool {
+        match self.down_until {
+            Some(until) => now >= until,
+            None => true,
+        }
+    }
+}
+
+// Ordered list of hosts serving the same project. Requests go to the first
+// healthy host; a host that fails with a connection error or a 5xx response
+// is skipped for `cooldown`, after which it is probed again by the next
+// request. That way traffic falls back to the primary once it recovers.
+pub struct HostPool {
+    hosts: Vec<Host>,
+    cooldown: Duration,
+    last: Option<usize>,
+}
+
+impl HostPool {
+    pub fn new(hosts: Vec<String>) -> Result<HostPool, String> {
+        if hosts.is_empty() {
+            return Err("No hosts given".to_string());
+        }
+
+        Ok(HostPool {
+            hosts: hosts
+                .into_iter()
+                .map(|name| Host {
+                    name,
+                    failures: 0,
+                    down_until: None,
+                })
+                .collect(),
+            cooldown: Duration::from_secs(DEFAULT_COOLDOWN),
+            last: None,
+        })
+    }
+
+    pub fn cooldown(&mut self, cooldown: Duration) -> &mut HostPool {
+        self.cooldown = cooldown;
+
+        self
+    }
+
+    pub fn hosts(&self) -> Vec<String> {
+        self.hosts.iter().map(|h| h.name.clone()).collect()
+    }
+
+    pub fn last_host(&self) -> Option<&str> {
+        self.last.map(|i| self.hosts[i].name.as_str())
+    }
+
+    pub fn failures(&self, host: &str) -> Option<u32> {
+        self.hosts.iter().find(|h| h.name == host).map(|h| h.failures)
+    }
+
+    // Hosts to try for the next request: healthy ones in priority order,
+    // then the ones still cooling down as a last resort.
+    pub fn candidates(&self) -> Vec<usize> {
+        let now = Instant::now();
+        let (mut up, down): (Vec<usize>, Vec<usize>) =
+            (0..self.hosts.len()).partition(|&i| self.hosts[i].is_up(now));
+        up.extend(down);
+
+        up
+    }
+
+    pub fn url(&self, index: usize, url: &str) -> Result<String, String> {
+        let uri: Uri = url.parse().map_err(|_| format!("Invalid url {}", url))?;
+        let scheme = uri.scheme().unwrap_or("https");
+        let path = match uri.query() {
+            Some(query) => format!("{}?{}", uri.path(), query),
+            None => uri.path().to_string(),
+        };
+
+        Ok(format!("{}://{}{}", scheme, self.hosts[index].name, path))
+    }
+
+    pub fn success(&mut self, index: usize) {
+        let host = &mut self.hosts[index];
+        host.failures = 0;
+        host.down_until = None;
+        self.last = Some(index);
+    }
+
+    pub fn failure(&mut self, index: usize) {
+        let host = &mut self.hosts[index];
+        host.failures += 1;
+        host.down_until = Some(Instant::now() + self.cooldown);
+        self.last = Some(index);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn fails_over_and_back() {
+        let mut pool = HostPool::new(vec!["primary".to_string(), "secondary".to_string()]).unwrap();
+        pool.cooldown(Duration::from_millis(50));
+        assert_eq!(pool.candidates(), vec![0, 1]);
+
+        pool.failure(0);
+        assert_eq!(pool.candidates(), vec![1, 0]);
+        pool.success(1);
+        assert_eq!(pool.last_host(), Some("secondary"));
+        assert_eq!(pool.failures("primary"), Some(1));
+
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(pool.candidates(), vec![0, 1]);
+    }
+
+    #[test]
+    fn rewrites_host() {
+        let pool = HostPool::new(vec!["mq-2.iron.io:8443".to_string()]).unwrap();
+        let url = pool.url(0, "https://mq-1.iron.io/3/projects/id/queues?prefix=a");
+
+        assert_eq!(url.unwrap(), "https://mq-2.iron.io:8443/3/projects/id/queues?prefix=a");
+        assert!(pool.url(0, "http://[mq").is_err());
+        assert!(HostPool::new(vec![]).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+extern crate futures;
+extern crate hyper;
+extern crate tokio_core;
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hyper::{Client, Method, Request, StatusCode};
+use hyper::header::ContentType;
+use futures::{Future, Stream};
+use futures::future::Either;
+use serde_json::{self, Value};
+use tokio_core::reactor::Timeout;
+
+use super::config::HttpConfig;
+use super::credentials::CredentialProvider;
+
+// Tokens are renewed this long before Keystone says they expire.
+const EXPIRY_MARGIN: u64 = 60;
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct KeystoneConfig {
+    pub server: String,
+    pub tenant: String,
+    pub username: String,
+    pub password: String,
+}
+
+// Exchanges Keystone (identity v2.0) credentials for a token, as the other
+// Iron clients do for on-premise installations. Keystone is reached with
+// the TLS, proxy and timeout settings of the client the credentials are
+// set on.
+pub struct KeystoneCredentials {
+    config: KeystoneConfig,
+    token: Option<(String, SystemTime)>,
+    http: HttpConfig,
+}
+
+impl KeystoneCredentials {
+    pub fn new(config: KeystoneConfig) -> KeystoneCredentials {
+        KeystoneCredentials {
+            config,
+            token: None,
+            http: HttpConfig::new(),
+        }
+    }
+
+    fn authenticate(&mut self) -> Result<String, String> {
+        let url = format!("{}/tokens", self.config.server.trim_end_matches('/'));
+        let body = json!({
+            "auth": {
+                "tenantName": self.config.tenant,
+                "passwordCredentials": {
+                    "username": self.config.username,
+                    "password": self.config.password
+                }
+            }
+        });
+
+        let (status, res) = post(&url, body.to_string(), &self.http)?;
+        let v: Value = serde_json::from_slice(&res).map_err(|e| e.to_string())?;
+
+        if !status.is_success() {
+            return Err(format!("Keystone authentication failed: {} {}", status, v["error"]["message"]));
+        }
+
+        let token = v["access"]["token"]["id"]
+            .as_str()
+            .ok_or_else(|| "Missed token in Keystone response".to_string())?
+            .to_string();
+        let expires = v["access"]["token"]["expires"]
+            .as_str()
+            .and_then(parse_timestamp)
+            .ok_or_else(|| "Missed token expiration in Keystone response".to_string())?;
+
+        self.token = Some((token.clone(), expires));
+
+        Ok(token)
+    }
+}
+
+impl CredentialProvider for KeystoneCredentials {
+    fn token(&mut self) -> Result<String, String> {
+        let refresh_at = SystemTime::now() + Duration::from_secs(EXPIRY_MARGIN);
+
+        match self.token {
+            Some((ref token, expires)) if refresh_at < expires => Ok(token.clone()),
+            _ => self.authenticate(),
+        }
+    }
+
+    fn refresh(&mut self) -> Result<String, String> {
+        self.authenticate()
+    }
+
+    fn http_config(&mut self, config: &HttpConfig) {
+        self.http = config.clone();
+    }
+}
+
+fn post(url: &str, body: String, config: &HttpConfig) -> Result<(StatusCode, hyper::Chunk), String> {
+    let mut core = tokio_core::reactor::Core::new().map_err(|e| e.to_string())?;
+    let handle = core.handle();
+    let client = Client::configure().connector(super::connector(config, &handle)?).build(&handle);
+
+    let mut req = Request::new(Method::Post, url.parse().map_err(|_| format!("Invalid Keystone url {}", url))?);
+    req.headers_mut().set(ContentType::json());
+    req.set_body(body);
+
+    let work = client
+        .request(req)
+        .and_then(|res| {
+            let status = res.status();
+            res.body().concat2().map(move |chunk| (status, chunk))
+        })
+        .map_err(|e| e.to_string());
+
+    let timeout = match config.request_timeout {
+        Some(timeout) => timeout,
+        None => return core.run(work),
+    };
+
+    let timer = Timeout::new(timeout, &handle).map_err(|e| e.to_string())?;
+    let work = work.select2(timer).then(|res| match res {
+        Ok(Either::A((response, _))) => Ok(response),
+        Ok(Either::B(_)) => Err("Keystone request timed out".to_string()),
+        Err(Either::A((e, _))) => Err(e),
+        Err(Either::B((e, _))) => Err(e.to_string()),
+    });
+
+    core.run(work)
+}
+
+// Parses the UTC timestamps Keystone returns, e.g. `2015-04-16T20:24:17Z` or
+// `2015-04-16T20:24:17.000000Z`.
+fn parse_timestamp(value: &str) -> Option<SystemTime> {
+    let value = value.trim_end_matches('Z').trim_end_matches("+00:00");
+    let (date, time) = (value.get(..10)?, value.get(11..19)?);
+
+    let mut date = date.split('-').map(|p| p.parse::<i64>());
+    let (year, month, day) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let mut time = time.split(':').map(|p| p.parse::<u64>());
+    let (hour, minute, second) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+
+    // Days since the unix epoch of a proleptic Gregorian date.
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146_097 + doe - 719_468;
+
+    if days < 0 {
+        return None;
+    }
+
+    let seconds = days as u64 * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{FakeServer, Response};
+
+    fn fake_keystone(expires: &'static str) -> FakeServer {
+        let mut issued = 0;
+
+        FakeServer::start(move |_| {
+            let body = format!(
+                r#"{{"access": {{"token": {{"id": "token-{}", "expires": "{}"}}}}}}"#,
+                issued, expires
+            );
+            issued += 1;
+            Response::ok(&body)
+        })
+    }
+
+    // Every request asked for a token of the tenant with the password.
+    fn assert_token_requests(keystone: &FakeServer, count: usize) {
+        let requests = keystone.requests();
+        assert_eq!(requests.len(), count);
+        for request in requests {
+            assert_eq!(request.line(), "POST /v2.0/tokens");
+            assert_eq!(request.json()["auth"]["tenantName"], "tenant");
+            assert_eq!(request.json()["auth"]["passwordCredentials"]["password"], "secret");
+        }
+    }
+
+    fn credentials(server: String) -> KeystoneCredentials {
+        KeystoneCredentials::new(KeystoneConfig {
+            server,
+            tenant: "tenant".to_string(),
+            username: "user".to_string(),
+            password: "secret".to_string(),
+        })
+    }
+
+    #[test]
+    fn caches_token_until_expiration() {
+        let server = fake_keystone("2999-01-01T00:00:00.000000Z");
+        let mut keystone = credentials(format!("http://{}/v2.0/", server.addr()));
+
+        assert_eq!(keystone.token().unwrap(), "token-0");
+        assert_eq!(keystone.token().unwrap(), "token-0");
+        assert_token_requests(&server, 1);
+
+        assert_eq!(keystone.refresh().unwrap(), "token-1");
+    }
+
+    #[test]
+    fn renews_expired_token() {
+        let server = fake_keystone("2015-04-16T20:24:17Z");
+        let mut keystone = credentials(format!("http://{}/v2.0/", server.addr()));
+
+        assert_eq!(keystone.token().unwrap(), "token-0");
+        assert_eq!(keystone.token().unwrap(), "token-1");
+        assert_token_requests(&server, 2);
+    }
+
+    #[test]
+    fn times_out_with_client_settings() {
+        let server = FakeServer::start(|_| Response::ok("{}").delayed(Duration::from_secs(5)));
+        let mut keystone = credentials(format!("http://{}/v2.0/", server.addr()));
+        let mut config = HttpConfig::new();
+        config.request_timeout(Some(Duration::from_millis(200)));
+        keystone.http_config(&config);
+
+        assert_eq!(keystone.token(), Err("Keystone request timed out".to_string()));
+    }
+
+    #[test]
+    fn timestamps() {
+        let parsed = parse_timestamp("2015-04-16T20:24:17Z").unwrap();
+        assert_eq!(parsed.duration_since(UNIX_EPOCH).unwrap().as_secs(), 1_429_215_857);
+        assert!(parse_timestamp("yesterday").is_none());
+    }
+}
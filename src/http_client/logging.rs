@@ -0,0 +1,162 @@
+use std::time::Duration;
+
+use hyper::{Method, StatusCode};
+
+pub const TARGET: &str = "iron_mq_rust::http";
+const REDACTED: &str = "[REDACTED]";
+
+pub struct RequestLog<'a> {
+    pub method: &'a Method,
+    pub url: &'a str,
+    pub result: Result<StatusCode, &'a str>,
+    pub latency: Duration,
+    pub request_bytes: usize,
+    pub response_bytes: usize,
+    pub retries: u32,
+}
+
+impl<'a> RequestLog<'a> {
+    fn failed(&self) -> bool {
+        match self.result {
+            Ok(status) => status.is_server_error(),
+            Err(_) => true,
+        }
+    }
+
+    fn outcome(&self) -> String {
+        match self.result {
+            Ok(status) => status.as_u16().to_string(),
+            Err(e) => format!("error: {}", e),
+        }
+    }
+
+    fn latency_ms(&self) -> u64 {
+        self.latency.as_secs() * 1000 + u64::from(self.latency.subsec_millis())
+    }
+}
+
+// Hides the token wherever it shows up, including `oauth=` query
+// parameters such as those of queue webhook URLs.
+pub fn redact(text: &str, token: &str) -> String {
+    let mut text = if token.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(token, REDACTED)
+    };
+
+    let mut from = 0;
+    while let Some(found) = text[from..].find("oauth=") {
+        let start = from + found + "oauth=".len();
+        let end = text[start..]
+            .find(|c: char| c == '&' || c == '"' || c == '#' || c.is_whitespace())
+            .map_or(text.len(), |end| start + end);
+        text.replace_range(start..end, REDACTED);
+        from = start + REDACTED.len();
+    }
+
+    text
+}
+
+pub fn truncate(body: &str, limit: Option<usize>) -> String {
+    match limit {
+        Some(limit) if body.len() > limit => {
+            let mut end = limit;
+            while !body.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}... ({} bytes)", &body[..end], body.len())
+        }
+        _ => body.to_string(),
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn request(entry: &RequestLog) {
+    let level = if entry.failed() { ::log::Level::Warn } else { ::log::Level::Debug };
+
+    ::log::log!(
+        target: TARGET,
+        level,
+        "{} {} {} latency={}ms request_bytes={} response_bytes={} retries={}",
+        entry.method,
+        entry.url,
+        entry.outcome(),
+        entry.latency_ms(),
+        entry.request_bytes,
+        entry.response_bytes,
+        entry.retries
+    );
+}
+
+#[cfg(feature = "tracing")]
+pub fn request(entry: &RequestLog) {
+    let method = entry.method.as_ref();
+    let outcome = entry.outcome();
+    let latency_ms = entry.latency_ms();
+
+    if entry.failed() {
+        ::tracing::warn!(
+            target: TARGET,
+            method,
+            url = entry.url,
+            outcome = outcome.as_str(),
+            latency_ms,
+            request_bytes = entry.request_bytes,
+            response_bytes = entry.response_bytes,
+            retries = entry.retries,
+            "request failed"
+        );
+    } else {
+        ::tracing::debug!(
+            target: TARGET,
+            method,
+            url = entry.url,
+            outcome = outcome.as_str(),
+            latency_ms,
+            request_bytes = entry.request_bytes,
+            response_bytes = entry.response_bytes,
+            retries = entry.retries,
+            "request"
+        );
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn body_enabled() -> bool {
+    ::log::log_enabled!(target: TARGET, ::log::Level::Trace)
+}
+
+#[cfg(feature = "tracing")]
+pub fn body_enabled() -> bool {
+    ::tracing::enabled!(target: TARGET, ::tracing::Level::TRACE)
+}
+
+#[cfg(not(feature = "tracing"))]
+pub fn body(direction: &str, body: &str) {
+    ::log::trace!(target: TARGET, "{} body: {}", direction, body);
+}
+
+#[cfg(feature = "tracing")]
+pub fn body(direction: &str, body: &str) {
+    ::tracing::trace!(target: TARGET, direction, body, "body");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_tokens() {
+        let url = "https://mq.iron.io/3/projects/p/queues/q/webhook?oauth=abc123&x=1";
+
+        assert_eq!(redact(url, ""), "https://mq.iron.io/3/projects/p/queues/q/webhook?oauth=[REDACTED]&x=1");
+        assert_eq!(redact("{\"token\":\"abc123\"}", "abc123"), "{\"token\":\"[REDACTED]\"}");
+    }
+
+    #[test]
+    fn truncates_bodies() {
+        assert_eq!(truncate("0123456789", Some(4)), "0123... (10 bytes)");
+        assert_eq!(truncate("0123456789", None), "0123456789");
+        assert_eq!(truncate("ééé", Some(3)), "é... (6 bytes)");
+    }
+}
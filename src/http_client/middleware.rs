@@ -0,0 +1,60 @@
+use hyper::{Method, StatusCode};
+
+#[derive(Debug, Clone)]
+pub struct HttpRequest {
+    pub method: Method,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl HttpRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .rev()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Replaces any header with the same name.
+    pub fn set_header(&mut self, name: &str, value: &str) {
+        self.headers.retain(|(n, _)| !n.eq_ignore_ascii_case(name));
+        self.headers.push((name.to_string(), value.to_string()));
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    pub status: StatusCode,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: StatusCode, body: &str) -> HttpResponse {
+        HttpResponse {
+            status,
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+}
+
+// A layer around every HTTP request `HttpClient` sends (each failover
+// attempt and retry included). `on_request` runs in registration order and
+// may modify the request or answer it itself by returning a response, in
+// which case the request isn't sent and later layers are skipped.
+// `on_response` runs in reverse order, only for the layers whose
+// `on_request` ran. Transport errors go to `on_error` instead. The
+// futures-based queue streams and producers send through `HttpClient` as
+// well, so they run the same layers.
+pub trait Middleware {
+    fn on_request(&mut self, _request: &mut HttpRequest) -> Option<HttpResponse> {
+        None
+    }
+
+    fn on_response(&mut self, _request: &HttpRequest, _response: &mut HttpResponse) {}
+
+    fn on_error(&mut self, _request: &HttpRequest, _error: &str) {}
+}
@@ -1,71 +1,746 @@
 extern crate futures;
 extern crate hyper;
-extern crate hyper_tls;
-extern crate num_cpus;
 extern crate tokio_core;
 
+pub mod circuit_breaker;
+pub mod config;
+pub mod connector;
+pub mod credentials;
+pub mod hosts;
+pub mod keystone;
+pub mod logging;
+pub mod middleware;
+pub mod proxy;
+pub mod rate_limit;
+pub mod tls;
+
+use std::cmp;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
 use hyper::{
     Client,
     Method,
     Request,
+    StatusCode,
     client:: {
          HttpConnector 
-    },
-    header::{
-        Authorization,
-        ContentType
     }
 };
 
-use hyper_tls::HttpsConnector;
 use futures::{Future, Stream};
+use futures::future::Either;
+use tokio_core::reactor::Timeout;
+
+use self::circuit_breaker::CircuitBreaker;
+use self::config::HttpConfig;
+use self::connector::{ConnectError, TimeoutConnector};
+use self::credentials::{CredentialProvider, StaticCredentials};
+use self::hosts::HostPool;
+use self::logging::RequestLog;
+use self::middleware::{HttpRequest, HttpResponse, Middleware};
+use self::proxy::ProxyConnector;
+use self::rate_limit::RateLimiter;
+use self::tls::HttpsConnector;
+
+// Long-poll requests get this much more time than the server may hold them.
+const LONG_POLL_MARGIN: u64 = 10;
+
+// A request that got no response. It wasn't `sent` if it failed while
+// connecting.
+struct Failure {
+    error: String,
+    sent: bool,
+}
 
 pub struct HttpClient {
     core: tokio_core::reactor::Core,
-    client: Client<HttpsConnector<HttpConnector>>,
-    token: String
+    client: Client<TimeoutConnector<HttpsConnector<ProxyConnector>>>,
+    config: HttpConfig,
+    credentials: Box<dyn CredentialProvider>,
+    hosts: Option<HostPool>,
+    middleware: Vec<Box<dyn Middleware>>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    circuit_breaker: Option<CircuitBreaker>,
+    last_status: Option<StatusCode>
 }
 
 impl HttpClient {
     pub fn new(token: String) -> HttpClient {
-        let num_cpus = num_cpus::get();
-        let core = tokio_core::reactor::Core::new().expect("Tokio core initialization error");
-        let handle = core.handle();
-        let connector = HttpsConnector::new(num_cpus, &handle).expect("Https connector initialization error");
+        HttpClient::with_credentials(Box::new(StaticCredentials::new(token)))
+    }
 
-        let client = Client::configure()
-            .connector(connector)
-            .build(&handle);
+    pub fn with_credentials(credentials: Box<dyn CredentialProvider>) -> HttpClient {
+        HttpClient::with_config(credentials, HttpConfig::new())
+    }
+
+    pub fn with_config(mut credentials: Box<dyn CredentialProvider>, config: HttpConfig) -> HttpClient {
+        let core = tokio_core::reactor::Core::new().expect("Tokio core initialization error");
+        let client = HttpClient::build(&core, &config);
+        credentials.http_config(&config);
 
         HttpClient {
             core,
             client,
-            token
+            config,
+            credentials,
+            hosts: None,
+            middleware: Vec::new(),
+            rate_limiter: None,
+            circuit_breaker: None,
+            last_status: None
         }
     }
 
+    fn build(core: &tokio_core::reactor::Core, config: &HttpConfig) -> Client<TimeoutConnector<HttpsConnector<ProxyConnector>>> {
+        let handle = core.handle();
+        let connector = connector(config, &handle).unwrap_or_else(|e| panic!("TLS configuration error: {}", e));
+
+        Client::configure()
+            .connector(connector)
+            .keep_alive(config.keep_alive)
+            .keep_alive_timeout(config.pool_idle_timeout)
+            .build(&handle)
+    }
+
+    pub fn config(&self) -> &HttpConfig {
+        &self.config
+    }
+
+    pub fn set_config(&mut self, config: HttpConfig) {
+        self.client = HttpClient::build(&self.core, &config);
+        self.credentials.http_config(&config);
+        self.config = config;
+    }
+
+    pub fn set_hosts(&mut self, hosts: HostPool) {
+        self.hosts = Some(hosts);
+    }
+
+    pub fn hosts(&mut self) -> Option<&mut HostPool> {
+        self.hosts.as_mut()
+    }
+
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.middleware.push(middleware);
+    }
+
+    pub fn set_rate_limiter(&mut self, rate_limiter: Option<Arc<RateLimiter>>) {
+        self.rate_limiter = rate_limiter;
+    }
+
+    pub fn set_circuit_breaker(&mut self, circuit_breaker: Option<CircuitBreaker>) {
+        self.circuit_breaker = circuit_breaker;
+    }
+
+    pub fn circuit_breaker(&mut self) -> Option<&mut CircuitBreaker> {
+        self.circuit_breaker.as_mut()
+    }
+
+    pub fn last_host(&self) -> Option<String> {
+        self.hosts.as_ref().and_then(|h| h.last_host()).map(String::from)
+    }
+
+    // Status of the response to the last request, `None` if it got none.
+    pub fn last_status(&self) -> Option<StatusCode> {
+        self.last_status
+    }
+
+    // Transport errors are reported like API errors, as a JSON body with a
+    // `msg`, so they surface through the `Err`/`msg` results of `Queue`.
     pub fn request(&mut self, method: Method, path: String, body: String) -> hyper::Chunk {
-        let mut req = Request::new(method, path.parse().expect("Request error"));
+        let timeout = self.config.request_timeout;
+        self.request_with_timeout(method, path, body, timeout)
+    }
+
+    // For requests the server may hold for up to `wait` before answering,
+    // such as long-polling reservations.
+    pub fn request_with_wait(&mut self, method: Method, path: String, body: String, wait: Duration) -> hyper::Chunk {
+        let timeout = self.config
+            .request_timeout
+            .map(|timeout| cmp::max(timeout, wait + Duration::from_secs(LONG_POLL_MARGIN)));
+        self.request_with_timeout(method, path, body, timeout)
+    }
+
+    fn request_with_timeout(&mut self, method: Method, path: String, body: String, timeout: Option<Duration>) -> hyper::Chunk {
+        self.last_status = None;
+        let (project, queue) = rate_limit::target(&path);
+        if let Some(ref limiter) = self.rate_limiter {
+            if !limiter.acquire(project.as_deref(), queue.as_deref()) {
+                return hyper::Chunk::from(json!({ "msg": "Rate limited" }).to_string());
+            }
+        }
+
+        let mut retries = 0;
+        let token = match self.credentials.token() {
+            Ok(token) => token,
+            Err(e) => return hyper::Chunk::from(json!({ "msg": format!("Credentials error: {}", e) }).to_string()),
+        };
+        let response = self.dispatch(&method, &path, &body, &token, timeout, &mut retries);
+
+        let response = match response {
+            Ok((StatusCode::Unauthorized, _)) => match self.credentials.refresh() {
+                Ok(token) => self.dispatch(&method, &path, &body, &token, timeout, &mut retries),
+                Err(e) => return hyper::Chunk::from(json!({ "msg": format!("Credentials error: {}", e) }).to_string()),
+            },
+            response => response,
+        };
+
+        if let (Ok((StatusCode::TooManyRequests, _)), Some(limiter)) = (&response, self.rate_limiter.as_ref()) {
+            limiter.throttled(project.as_deref(), queue.as_deref());
+        }
+
+        match response {
+            Ok((status, chunk)) => {
+                self.last_status = Some(status);
+                chunk
+            }
+            Err(e) => hyper::Chunk::from(json!({ "msg": format!("Request error: {}", e) }).to_string()),
+        }
+    }
+
+    // Sends the request to the first host that doesn't fail with a
+    // connection error or a 5xx response, skipping hosts whose circuit is
+    // open. Requests that push messages or reserve them are only sent to
+    // the next host if they couldn't connect: after a timeout or a 5xx they
+    // may have been carried out already.
+    fn dispatch(&mut self, method: &Method, path: &str, body: &str, token: &str, timeout: Option<Duration>, retries: &mut u32) -> Result<(StatusCode, hyper::Chunk), String> {
+        let urls: Vec<(Option<usize>, String)> = match self.hosts {
+            Some(ref hosts) => hosts
+                .candidates()
+                .into_iter()
+                .map(|i| hosts.url(i, path).map(|url| (Some(i), url)))
+                .collect::<Result<_, _>>()?,
+            None => vec![(None, path.to_string())],
+        };
+
+        let idempotent = idempotent(method, path);
+        let mut last = Err("No hosts".to_string());
 
-        let authorization_header = format!("OAuth {}", self.token);
+        for (index, url) in urls {
+            let endpoint = circuit_breaker::endpoint(&url);
+            if let Some(ref mut breaker) = self.circuit_breaker {
+                if !breaker.allow(&endpoint) {
+                    last = Err("Circuit open".to_string());
+                    continue;
+                }
+            }
 
-        req.headers_mut().set(ContentType::json());
-        req.headers_mut().set(Authorization(authorization_header));
+            let response = self.send(method.clone(), &url, body.to_string(), token, timeout, *retries);
+            *retries += 1;
+            let (failed, sent) = match response {
+                Ok((status, _)) => (status.is_server_error(), true),
+                Err(ref failure) => (true, failure.sent),
+            };
 
-        req.set_body(body);
+            if let Some(ref mut breaker) = self.circuit_breaker {
+                if failed {
+                    breaker.failure(&endpoint);
+                } else {
+                    breaker.success(&endpoint);
+                }
+            }
+
+            if let (Some(index), Some(hosts)) = (index, self.hosts.as_mut()) {
+                if failed {
+                    hosts.failure(index);
+                } else {
+                    hosts.success(index);
+                }
+            }
+
+            let response = response.map_err(|failure| failure.error);
+            if !failed || (sent && !idempotent) {
+                return response;
+            }
+            last = response;
+        }
+
+        last
+    }
+
+    // Sends a single HTTP request through the middleware and logs it;
+    // `retries` counts the attempts made before this one for the same call.
+    fn send(&mut self, method: Method, path: &str, body: String, token: &str, timeout: Option<Duration>, retries: u32) -> Result<(StatusCode, hyper::Chunk), Failure> {
+        let started = Instant::now();
+
+        let mut headers = self.config.headers.clone();
+        headers.push(("Content-Type".to_string(), "application/json".to_string()));
+        headers.push(("User-Agent".to_string(), self.config.user_agent.clone()));
+        headers.push(("Authorization".to_string(), format!("OAuth {}", token)));
+        let mut request = HttpRequest {
+            method,
+            url: path.to_string(),
+            headers,
+            body,
+        };
+
+        let mut layers = 0;
+        let mut short_circuit = None;
+        for middleware in &mut self.middleware {
+            layers += 1;
+            short_circuit = middleware.on_request(&mut request);
+            if short_circuit.is_some() {
+                break;
+            }
+        }
+
+        if logging::body_enabled() && !request.body.is_empty() {
+            logging::body("request", &logging::truncate(&logging::redact(&request.body, token), self.config.log_body_limit));
+        }
+
+        let mut response = match short_circuit {
+            Some(response) => Ok(response),
+            None => self.send_request(&request, timeout),
+        };
+
+        for middleware in self.middleware[..layers].iter_mut().rev() {
+            match response {
+                Ok(ref mut response) => middleware.on_response(&request, response),
+                Err(ref failure) => middleware.on_error(&request, &failure.error),
+            }
+        }
+
+        logging::request(&RequestLog {
+            method: &request.method,
+            url: &logging::redact(&request.url, token),
+            result: match response {
+                Ok(ref response) => Ok(response.status),
+                Err(ref failure) => Err(&failure.error),
+            },
+            latency: started.elapsed(),
+            request_bytes: request.body.len(),
+            response_bytes: response.as_ref().map_or(0, |response| response.body.len()),
+            retries,
+        });
+
+        if let Ok(ref response) = response {
+            if logging::body_enabled() && !response.body.is_empty() {
+                let body = logging::redact(&String::from_utf8_lossy(&response.body), token);
+                logging::body("response", &logging::truncate(&body, self.config.log_body_limit));
+            }
+        }
+
+        response.map(|response| (response.status, hyper::Chunk::from(response.body)))
+    }
+
+    fn send_request(&mut self, request: &HttpRequest, timeout: Option<Duration>) -> Result<HttpResponse, Failure> {
+        let uri = request.url.parse().map_err(|_| Failure {
+            error: format!("Invalid url {}", request.url),
+            sent: false,
+        })?;
+        let mut req = Request::new(request.method.clone(), uri);
+
+        for (name, value) in &request.headers {
+            req.headers_mut().set_raw(name.clone(), value.clone());
+        }
+
+        req.set_body(request.body.clone());
 
         let work = self
             .client
             .request(req)
-            .and_then(|res| res.body().concat2());
+            .and_then(|res| {
+                let status = res.status();
+                let headers = res
+                    .headers()
+                    .iter()
+                    .map(|header| (header.name().to_string(), header.value_string()))
+                    .collect();
+                res.body().concat2().map(move |chunk| HttpResponse {
+                    status,
+                    headers,
+                    body: chunk.to_vec(),
+                })
+            })
+            .map_err(|e| Failure {
+                sent: match e {
+                    hyper::Error::Io(ref e) => !ConnectError::is(e),
+                    _ => true,
+                },
+                error: e.to_string(),
+            });
 
-        self.core.run(work).expect("Request error")
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return self.core.run(work),
+        };
+
+        let timer = Timeout::new(timeout, &self.core.handle()).map_err(|e| Failure {
+            error: e.to_string(),
+            sent: false,
+        })?;
+        let work = work.select2(timer).then(|res| match res {
+            Ok(Either::A((response, _))) => Ok(response),
+            Ok(Either::B(_)) => Err(Failure {
+                error: "Request timed out".to_string(),
+                sent: true,
+            }),
+            Err(Either::A((failure, _))) => Err(failure),
+            Err(Either::B((e, _))) => Err(Failure {
+                error: e.to_string(),
+                sent: true,
+            }),
+        });
+
+        self.core.run(work)
     }
 }
 
+// Connects through the proxy, TLS settings and connect timeout of `config`.
+pub fn connector(config: &HttpConfig, handle: &tokio_core::reactor::Handle) -> Result<TimeoutConnector<HttpsConnector<ProxyConnector>>, String> {
+    let mut http = HttpConnector::new(config.dns_threads, handle);
+    http.enforce_http(false);
+    let proxy = ProxyConnector::new(http, config.proxy.clone());
+    let https = tls::connector(proxy, &config.tls)?;
+
+    Ok(TimeoutConnector::new(https, config.connect_timeout, handle))
+}
+
+// Pushing messages or reserving them twice isn't the same as once.
+fn idempotent(method: &Method, path: &str) -> bool {
+    let path = path.split('?').next().unwrap_or(path);
+
+    *method != Method::Post || !(path.ends_with("/messages") || path.ends_with("/reservations") || path.ends_with("/webhook"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use super::proxy::{Proxy, ProxyConfig};
+    use super::rate_limit::{Limit, RateLimitMode};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{mpsc, Mutex};
+    use std::thread;
+    use test_server::{closed_port, FakeServer, Response};
+
+    fn local_server(status: &'static str, body: &'static str) -> String {
+        FakeServer::constant(status, body).addr()
+    }
+
+    #[test]
+    fn fails_over_to_next_host() {
+        let down = closed_port();
+        let broken = local_server("503 Service Unavailable", "{}");
+        let up = local_server("200 OK", "{\"msg\":\"ok\"}");
+
+        let mut http_client = HttpClient::new("some-token".to_string());
+        http_client.set_hosts(HostPool::new(vec![down.clone(), broken.clone(), up.clone()]).unwrap());
+
+        let res = http_client.request(Method::Get, format!("http://{}/3/projects/id/queues", down), String::new());
+
+        assert_eq!(&res[..], b"{\"msg\":\"ok\"}");
+        assert_eq!(http_client.last_host(), Some(up));
+        assert_eq!(http_client.hosts().unwrap().failures(&broken), Some(1));
+    }
+
+    #[test]
+    fn fails_over_pushes_only_when_connecting_failed() {
+        let down = closed_port();
+        let up = FakeServer::constant("200 OK", "{\"ids\":[\"1\"]}");
+
+        let mut http_client = HttpClient::new("some-token".to_string());
+        http_client.set_hosts(HostPool::new(vec![down.clone(), up.addr()]).unwrap());
+
+        let res = http_client.request(Method::Post, format!("http://{}/3/projects/id/queues/q/messages", down), "{}".to_string());
+
+        assert_eq!(&res[..], b"{\"ids\":[\"1\"]}");
+        assert_eq!(up.lines(), vec!["POST /3/projects/id/queues/q/messages"]);
+    }
+
+    #[test]
+    fn does_not_resend_pushes_after_timeout() {
+        let hung = FakeServer::start(|_| Response::ok("{}").delayed(Duration::from_secs(5)));
+        let up = FakeServer::constant("200 OK", "{\"ids\":[\"1\"]}");
+
+        let mut config = HttpConfig::new();
+        config.request_timeout(Some(Duration::from_millis(200)));
+        let mut http_client = HttpClient::with_config(Box::new(StaticCredentials::new("some-token".to_string())), config);
+        http_client.set_hosts(HostPool::new(vec![hung.addr(), up.addr()]).unwrap());
+
+        let res = http_client.request(Method::Post, format!("http://{}/3/projects/id/queues/q/messages", hung.addr()), "{}".to_string());
+        let v: serde_json::Value = serde_json::from_slice(&res).unwrap();
+
+        assert_eq!(v["msg"], "Request error: Request timed out");
+        assert_eq!(hung.requests().len(), 1);
+        assert!(up.requests().is_empty());
+    }
+
+    // Hands out `old` until refreshed, then `new`, counting refreshes.
+    struct Rotating {
+        refreshed: Arc<Mutex<u32>>,
+    }
+
+    impl CredentialProvider for Rotating {
+        fn token(&mut self) -> Result<String, String> {
+            Ok(if *self.refreshed.lock().unwrap() == 0 { "old" } else { "new" }.to_string())
+        }
+
+        fn refresh(&mut self) -> Result<String, String> {
+            *self.refreshed.lock().unwrap() += 1;
+            self.token()
+        }
+    }
+
+    struct Broken;
+
+    impl CredentialProvider for Broken {
+        fn token(&mut self) -> Result<String, String> {
+            Err("token file missing".to_string())
+        }
+    }
+
+    #[test]
+    fn refreshes_rejected_tokens_once() {
+        let server = FakeServer::start(|request| match request.header("Authorization") {
+            Some("OAuth new") => Response::ok("{\"msg\":\"ok\"}"),
+            _ => Response::status("401 Unauthorized", "{\"msg\":\"Invalid token\"}"),
+        });
+        let refreshed = Arc::new(Mutex::new(0));
+        let mut http_client = HttpClient::with_credentials(Box::new(Rotating { refreshed: refreshed.clone() }));
+
+        let res = http_client.request(Method::Get, format!("http://{}/", server.addr()), String::new());
+
+        assert_eq!(&res[..], b"{\"msg\":\"ok\"}");
+        assert_eq!(*refreshed.lock().unwrap(), 1);
+        let tokens: Vec<String> = server.requests().iter().map(|r| r.header("Authorization").unwrap_or("").to_string()).collect();
+        assert_eq!(tokens, vec!["OAuth old", "OAuth new"]);
+    }
+
+    #[test]
+    fn returns_second_401() {
+        let server = FakeServer::constant("401 Unauthorized", "{\"msg\":\"Invalid token\"}");
+        let refreshed = Arc::new(Mutex::new(0));
+        let mut http_client = HttpClient::with_credentials(Box::new(Rotating { refreshed: refreshed.clone() }));
+
+        let res = http_client.request(Method::Get, format!("http://{}/", server.addr()), String::new());
+
+        assert_eq!(&res[..], b"{\"msg\":\"Invalid token\"}");
+        assert_eq!(*refreshed.lock().unwrap(), 1);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn reports_credential_errors_as_msg() {
+        let server = FakeServer::constant("200 OK", "{}");
+        let mut http_client = HttpClient::with_credentials(Box::new(Broken));
+
+        let res = http_client.request(Method::Get, format!("http://{}/", server.addr()), String::new());
+        let v: serde_json::Value = serde_json::from_slice(&res).unwrap();
+
+        assert_eq!(v["msg"], "Credentials error: token file missing");
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn times_out_hung_requests() {
+        let hung = FakeServer::start(|_| Response::ok("{}").delayed(Duration::from_secs(5)));
+
+        let mut config = HttpConfig::new();
+        config.request_timeout(Some(Duration::from_millis(200)));
+        let mut http_client = HttpClient::with_config(Box::new(StaticCredentials::new("some-token".to_string())), config);
+
+        let res = http_client.request(Method::Get, format!("http://{}/", hung.addr()), String::new());
+        let v: serde_json::Value = serde_json::from_slice(&res).unwrap();
+
+        assert_eq!(v["msg"], "Request error: Request timed out");
+    }
+
+    #[test]
+    fn reports_connection_errors_as_msg() {
+        let down = closed_port();
+        let mut http_client = HttpClient::new("some-token".to_string());
+
+        let res = http_client.request(Method::Get, format!("http://{}/", down), String::new());
+        let v: serde_json::Value = serde_json::from_slice(&res).unwrap();
+
+        assert!(v["msg"].as_str().unwrap().starts_with("Request error"));
+    }
+
+    #[test]
+    fn tunnels_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::channel();
+
+        // Accepts the CONNECT and then plays the destination server itself.
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).unwrap();
+            tx.send(String::from_utf8_lossy(&buf[..n]).into_owned()).unwrap();
+
+            stream.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n").unwrap();
+            let _ = stream.read(&mut buf);
+            let body = "{\"msg\":\"tunnelled\"}";
+            let _ = write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+        });
+
+        let mut proxies = ProxyConfig::new();
+        proxies.all(Proxy::new(&format!("http://user:secret@{}", addr)).unwrap());
+        let mut config = HttpConfig::new();
+        config.proxy(proxies);
+        let mut http_client = HttpClient::with_config(Box::new(StaticCredentials::new("some-token".to_string())), config);
+
+        let res = http_client.request(Method::Get, "http://mq.example.invalid/3/projects/id/queues".to_string(), String::new());
+        let connect = rx.recv().unwrap();
+
+        assert_eq!(&res[..], b"{\"msg\":\"tunnelled\"}");
+        assert!(connect.starts_with("CONNECT mq.example.invalid:80 HTTP/1.1\r\n"));
+        assert!(connect.contains("Proxy-Authorization: Basic dXNlcjpzZWNyZXQ=\r\n"));
+    }
+
+    #[test]
+    fn bypasses_proxy_for_no_proxy_hosts() {
+        let up = local_server("200 OK", "{\"msg\":\"direct\"}");
+
+        let mut proxies = ProxyConfig::new();
+        proxies.all(Proxy::new(&closed_port()).unwrap()).no_proxy("localhost,127.0.0.1");
+        let mut config = HttpConfig::new();
+        config.proxy(proxies);
+        let mut http_client = HttpClient::with_config(Box::new(StaticCredentials::new("some-token".to_string())), config);
+
+        let res = http_client.request(Method::Get, format!("http://{}/", up), String::new());
+
+        assert_eq!(&res[..], b"{\"msg\":\"direct\"}");
+    }
+
+    #[test]
+    fn rate_limits_after_429() {
+        let up = local_server("429 Too Many Requests", "{\"msg\":\"Too many requests\"}");
+        let limiter = Arc::new(RateLimiter::new(RateLimitMode::Fail));
+        limiter.set_queue_limit(Some(Limit::per_second(1.0).unwrap().burst(5.0).unwrap()));
+
+        let mut http_client = HttpClient::new("some-token".to_string());
+        http_client.set_rate_limiter(Some(limiter.clone()));
+        let url = format!("http://{}/3/projects/p/queues/q/messages", up);
+
+        http_client.request(Method::Get, url.clone(), String::new());
+        assert!(limiter.factor(Some("p"), Some("q")) < 1.0);
+
+        let res = http_client.request(Method::Get, url, String::new());
+        let v: serde_json::Value = serde_json::from_slice(&res).unwrap();
+        assert_eq!(v["msg"], "Rate limited");
+    }
+
+    #[test]
+    fn fails_fast_while_circuit_is_open() {
+        let down = closed_port();
+        let mut breaker = CircuitBreaker::new();
+        breaker.failure_threshold(2);
+
+        let mut http_client = HttpClient::new("some-token".to_string());
+        http_client.set_circuit_breaker(Some(breaker));
+        let url = format!("http://{}/3/projects/p/queues/q/reservations", down);
+
+        for _ in 0..2 {
+            http_client.request(Method::Post, url.clone(), String::new());
+        }
+        let res = http_client.request(Method::Post, url, String::new());
+        let v: serde_json::Value = serde_json::from_slice(&res).unwrap();
+
+        assert_eq!(v["msg"], "Request error: Circuit open");
+        assert_eq!(http_client.circuit_breaker().unwrap().state(&down), circuit_breaker::CircuitState::Open);
+    }
+
+    struct Layer {
+        name: &'static str,
+        calls: std::rc::Rc<std::cell::RefCell<Vec<String>>>,
+        answer: bool,
+    }
+
+    impl Middleware for Layer {
+        fn on_request(&mut self, request: &mut HttpRequest) -> Option<HttpResponse> {
+            self.calls.borrow_mut().push(format!("{} request", self.name));
+            request.set_header("X-Layer", self.name);
+
+            if self.answer {
+                Some(HttpResponse::new(StatusCode::Ok, "{\"msg\":\"cached\"}"))
+            } else {
+                None
+            }
+        }
+
+        fn on_response(&mut self, request: &HttpRequest, response: &mut HttpResponse) {
+            self.calls.borrow_mut().push(format!("{} response {}", self.name, request.header("x-layer").unwrap()));
+            response.body.extend_from_slice(self.name.as_bytes());
+        }
+    }
+
+    #[test]
+    fn runs_middleware_in_order() {
+        let calls = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut http_client = HttpClient::new("some-token".to_string());
+        for &(name, answer) in &[("outer", false), ("inner", true), ("skipped", false)] {
+            http_client.add_middleware(Box::new(Layer { name, calls: calls.clone(), answer }));
+        }
+
+        let res = http_client.request(Method::Get, format!("http://{}/", closed_port()), String::new());
+
+        assert_eq!(&res[..], &b"{\"msg\":\"cached\"}innerouter"[..]);
+        assert_eq!(
+            *calls.borrow(),
+            vec!["outer request", "inner request", "inner response inner", "outer response inner"]
+        );
+    }
+
+    #[test]
+    fn middleware_modifies_requests() {
+        let server = FakeServer::constant("200 OK", "{}");
+
+        let calls = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+        let mut http_client = HttpClient::new("some-token".to_string());
+        http_client.add_middleware(Box::new(Layer { name: "signer", calls, answer: false }));
+        http_client.request(Method::Get, format!("http://{}/", server.addr()), String::new());
+
+        assert_eq!(server.requests()[0].header("X-Layer"), Some("signer"));
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    struct Capture(std::sync::Mutex<Vec<String>>);
+
+    #[cfg(not(feature = "tracing"))]
+    impl log::Log for Capture {
+        fn enabled(&self, _: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            if record.target() != logging::TARGET {
+                return;
+            }
+            self.0.lock().unwrap_or_else(|e| e.into_inner()).push(format!("{} {}", record.level(), record.args()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[test]
+    fn logs_requests_without_token() {
+        static LOGS: Capture = Capture(std::sync::Mutex::new(Vec::new()));
+        log::set_logger(&LOGS).unwrap();
+        log::set_max_level(log::LevelFilter::Trace);
+
+        let up = local_server("200 OK", "{\"msg\":\"logged\"}");
+        let url = format!("http://{}/3/projects/id/queues/q/webhook?oauth=secret-token", up);
+        let mut http_client = HttpClient::new("secret-token".to_string());
+        http_client.request(Method::Post, url, "{\"body\":\"secret-token\"}".to_string());
+
+        let logs = LOGS.0.lock().unwrap();
+        let request = format!("DEBUG POST http://{}/3/projects/id/queues/q/webhook?oauth=[REDACTED] 200", up);
+
+        assert!(logs.iter().any(|l| l.starts_with(&request) && l.ends_with("request_bytes=23 response_bytes=16 retries=0")));
+        assert!(logs.iter().any(|l| l == "TRACE request body: {\"body\":\"[REDACTED]\"}"));
+        assert!(logs.iter().any(|l| l == "TRACE response body: {\"msg\":\"logged\"}"));
+        assert!(logs.iter().all(|l| !l.contains("secret-token")));
+    }
 
     #[test]
     fn https_request() {
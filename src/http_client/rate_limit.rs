@@ -0,0 +1,354 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// After a 429 the allowed rate of the buckets the request took from is
+// halved (down to this fraction) and then doubles again every `RECOVERY`
+// seconds without another 429.
+const MIN_FACTOR: f64 = 1.0 / 16.0;
+const RECOVERY: f64 = 10.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimitMode {
+    // Block until a token is available.
+    Wait,
+    // Fail at once with a "Rate limited" `msg`.
+    Fail,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    rate: f64,
+    burst: f64,
+}
+
+impl Limit {
+    // `rate` requests per second, with bursts of up to `rate` requests.
+    // `rate` must be positive and finite.
+    pub fn per_second(rate: f64) -> Result<Limit, String> {
+        if !(rate > 0.0 && rate.is_finite()) {
+            return Err(format!("Invalid rate limit {}", rate));
+        }
+
+        Ok(Limit {
+            rate,
+            burst: rate.max(1.0),
+        })
+    }
+
+    // `burst` must be finite.
+    pub fn burst(mut self, burst: f64) -> Result<Limit, String> {
+        if !burst.is_finite() {
+            return Err(format!("Invalid burst {}", burst));
+        }
+        self.burst = burst.max(1.0);
+
+        Ok(self)
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    penalty: f64,
+    throttled_at: Option<Instant>,
+}
+
+impl Bucket {
+    fn new(limit: Limit, now: Instant) -> Bucket {
+        Bucket {
+            tokens: limit.burst,
+            updated: now,
+            penalty: 1.0,
+            throttled_at: None,
+        }
+    }
+
+    fn factor(&self, now: Instant) -> f64 {
+        match self.throttled_at {
+            Some(at) => (self.penalty * 2f64.powf(seconds(now.duration_since(at)) / RECOVERY)).min(1.0),
+            None => 1.0,
+        }
+    }
+
+    fn refill(&mut self, limit: Limit, now: Instant) {
+        let elapsed = seconds(now.duration_since(self.updated));
+        self.tokens = (self.tokens + elapsed * limit.rate * self.factor(now)).min(limit.burst);
+        self.updated = now;
+    }
+
+    // Time until a whole token is available.
+    fn wait(&self, limit: Limit, now: Instant) -> Duration {
+        if self.tokens >= 1.0 {
+            return Duration::from_secs(0);
+        }
+
+        let seconds = (1.0 - self.tokens) / (limit.rate * self.factor(now));
+        Duration::new(seconds as u64, (seconds.fract() * 1e9) as u32)
+    }
+
+    // Slows down and drops the tokens saved up.
+    fn throttle(&mut self, now: Instant) {
+        self.penalty = (self.factor(now) / 2.0).max(MIN_FACTOR);
+        self.throttled_at = Some(now);
+        self.tokens = self.tokens.min(0.0);
+    }
+}
+
+struct State {
+    mode: RateLimitMode,
+    project_limit: Option<Limit>,
+    queue_limit: Option<Limit>,
+    queue_limits: HashMap<String, Limit>,
+    projects: HashMap<String, Bucket>,
+    queues: HashMap<String, Bucket>,
+}
+
+impl State {
+    // Buckets a request to `queue` of `project` takes from.
+    fn buckets(&mut self, project: Option<&str>, queue: Option<&str>) -> Vec<&mut Bucket> {
+        let State { ref mut projects, ref mut queues, .. } = *self;
+        let mut buckets = Vec::new();
+
+        if let Some(project) = project {
+            if let Some(bucket) = projects.get_mut(project) {
+                buckets.push(bucket);
+            }
+        }
+        if let Some(queue) = queue {
+            if let Some(bucket) = queues.get_mut(&format!("{}/{}", project.unwrap_or(""), queue)) {
+                buckets.push(bucket);
+            }
+        }
+
+        buckets
+    }
+
+    fn limit_for(&self, queue: &str) -> Option<Limit> {
+        self.queue_limits.get(queue).cloned().or(self.queue_limit)
+    }
+
+    // Takes a token from the project and queue buckets, or tells how long
+    // to wait for both to have one.
+    fn try_acquire(&mut self, project: Option<&str>, queue: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = Vec::new();
+
+        if let (Some(project), Some(limit)) = (project, self.project_limit) {
+            buckets.push((true, project.to_string(), limit));
+        }
+        if let Some(queue) = queue {
+            if let Some(limit) = self.limit_for(queue) {
+                buckets.push((false, format!("{}/{}", project.unwrap_or(""), queue), limit));
+            }
+        }
+
+        let mut wait = Duration::from_secs(0);
+        for &(is_project, ref key, limit) in &buckets {
+            let map = if is_project { &mut self.projects } else { &mut self.queues };
+            let bucket = map.entry(key.clone()).or_insert_with(|| Bucket::new(limit, now));
+            bucket.refill(limit, now);
+            wait = wait.max(bucket.wait(limit, now));
+        }
+
+        if wait > Duration::from_secs(0) {
+            return Err(wait);
+        }
+
+        for (is_project, key, _) in buckets {
+            let map = if is_project { &mut self.projects } else { &mut self.queues };
+            if let Some(bucket) = map.get_mut(&key) {
+                bucket.tokens -= 1.0;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Token buckets per project and per queue, shared by every client it's
+// set on. Limits and the mode can be changed while it's in use.
+pub struct RateLimiter {
+    state: Mutex<State>,
+}
+
+impl RateLimiter {
+    pub fn new(mode: RateLimitMode) -> RateLimiter {
+        RateLimiter {
+            state: Mutex::new(State {
+                mode,
+                project_limit: None,
+                queue_limit: None,
+                queue_limits: HashMap::new(),
+                projects: HashMap::new(),
+                queues: HashMap::new(),
+            }),
+        }
+    }
+
+    pub fn set_mode(&self, mode: RateLimitMode) {
+        self.state.lock().unwrap().mode = mode;
+    }
+
+    pub fn set_project_limit(&self, limit: Option<Limit>) {
+        let mut state = self.state.lock().unwrap();
+        state.project_limit = limit;
+        state.projects.clear();
+    }
+
+    // Limit of every queue without a limit of its own.
+    pub fn set_queue_limit(&self, limit: Option<Limit>) {
+        let mut state = self.state.lock().unwrap();
+        state.queue_limit = limit;
+        state.queues.clear();
+    }
+
+    pub fn set_limit_for(&self, queue: &str, limit: Option<Limit>) {
+        let mut state = self.state.lock().unwrap();
+        match limit {
+            Some(limit) => state.queue_limits.insert(queue.to_string(), limit),
+            None => state.queue_limits.remove(queue),
+        };
+        let suffix = format!("/{}", queue);
+        state.queues.retain(|key, _| !key.ends_with(&suffix));
+    }
+
+    // Fraction of the configured rates currently allowed for requests to
+    // `queue` of `project`, below 1 after the server answered them 429.
+    pub fn factor(&self, project: Option<&str>, queue: Option<&str>) -> f64 {
+        let now = Instant::now();
+
+        self.state
+            .lock()
+            .unwrap()
+            .buckets(project, queue)
+            .iter()
+            .map(|bucket| bucket.factor(now))
+            .fold(1.0, f64::min)
+    }
+
+    // Returns false if the request may not be sent (in `Fail` mode).
+    pub fn acquire(&self, project: Option<&str>, queue: Option<&str>) -> bool {
+        loop {
+            let (wait, mode) = {
+                let mut state = self.state.lock().unwrap();
+                match state.try_acquire(project, queue) {
+                    Ok(()) => return true,
+                    Err(wait) => (wait, state.mode),
+                }
+            };
+
+            if mode == RateLimitMode::Fail {
+                return false;
+            }
+            thread::sleep(wait);
+        }
+    }
+
+    // The server answered 429: slow down the buckets the request took from.
+    pub fn throttled(&self, project: Option<&str>, queue: Option<&str>) {
+        let now = Instant::now();
+
+        for bucket in self.state.lock().unwrap().buckets(project, queue) {
+            bucket.throttle(now);
+        }
+    }
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) / 1e9
+}
+
+// Project id and queue name of an API url such as
+// `https://host/3/projects/{project}/queues/{queue}/messages`.
+pub fn target(url: &str) -> (Option<String>, Option<String>) {
+    let segment = |name: &str| {
+        url.find(name).map(|i| {
+            let rest = &url[i + name.len()..];
+            let end = rest.find(['/', '?']).unwrap_or(rest.len());
+            rest[..end].to_string()
+        })
+    };
+
+    (segment("/projects/"), segment("/queues/").filter(|queue| !queue.is_empty()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_targets() {
+        assert_eq!(
+            target("https://mq.iron.io/3/projects/p1/queues/jobs/messages?n=1"),
+            (Some("p1".to_string()), Some("jobs".to_string()))
+        );
+        assert_eq!(target("https://mq.iron.io/3/projects/p1/queues?prefix=a"), (Some("p1".to_string()), None));
+    }
+
+    #[test]
+    fn limits_per_queue() {
+        let limiter = RateLimiter::new(RateLimitMode::Fail);
+        limiter.set_queue_limit(Some(Limit::per_second(1.0).unwrap().burst(2.0).unwrap()));
+
+        assert!(limiter.acquire(Some("p"), Some("a")));
+        assert!(limiter.acquire(Some("p"), Some("a")));
+        assert!(!limiter.acquire(Some("p"), Some("a")));
+        assert!(limiter.acquire(Some("p"), Some("b")));
+
+        limiter.set_limit_for("a", Some(Limit::per_second(100.0).unwrap()));
+        assert!(limiter.acquire(Some("p"), Some("a")));
+    }
+
+    #[test]
+    fn waits_for_tokens() {
+        let limiter = RateLimiter::new(RateLimitMode::Wait);
+        limiter.set_project_limit(Some(Limit::per_second(20.0).unwrap().burst(1.0).unwrap()));
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire(Some("p"), None));
+        }
+
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn backs_off_after_429() {
+        let limiter = RateLimiter::new(RateLimitMode::Fail);
+        limiter.set_project_limit(Some(Limit::per_second(10.0).unwrap()));
+        assert!(limiter.acquire(Some("p"), None));
+
+        limiter.throttled(Some("p"), None);
+        limiter.throttled(Some("p"), None);
+
+        assert!(limiter.factor(Some("p"), None) < 0.26);
+        assert!(!limiter.acquire(Some("p"), None));
+    }
+
+    #[test]
+    fn backs_off_per_bucket() {
+        let limiter = RateLimiter::new(RateLimitMode::Fail);
+        limiter.set_queue_limit(Some(Limit::per_second(10.0).unwrap()));
+        assert!(limiter.acquire(Some("p"), Some("a")));
+        assert!(limiter.acquire(Some("p"), Some("b")));
+
+        limiter.throttled(Some("p"), Some("a"));
+
+        assert!(limiter.factor(Some("p"), Some("a")) < 0.51);
+        assert_eq!(limiter.factor(Some("p"), Some("b")), 1.0);
+        assert!(limiter.acquire(Some("p"), Some("b")));
+    }
+
+    #[test]
+    fn rejects_zero_rate() {
+        assert_eq!(Limit::per_second(0.0), Err("Invalid rate limit 0".to_string()));
+    }
+
+    #[test]
+    fn rejects_nan_rate() {
+        assert!(Limit::per_second(f64::NAN).is_err());
+        assert!(Limit::per_second(1.0).unwrap().burst(f64::INFINITY).is_err());
+    }
+}
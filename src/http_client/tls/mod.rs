@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+#[cfg(not(any(feature = "default-tls", feature = "rustls-tls")))]
+compile_error!("Either the `default-tls` or the `rustls-tls` feature must be enabled");
+
+#[cfg(all(feature = "default-tls", not(feature = "rustls-tls")))]
+mod native;
+#[cfg(all(feature = "default-tls", not(feature = "rustls-tls")))]
+pub use self::native::{connector, HttpsConnector};
+
+#[cfg(feature = "rustls-tls")]
+mod pure_rust;
+#[cfg(feature = "rustls-tls")]
+pub use self::pure_rust::{connector, HttpsConnector, MaybeHttpsStream};
+
+// TLS settings of the HTTPS connector. The backend is picked at build time:
+// native-tls with the default `default-tls` feature, or rustls with
+// `rustls-tls` (which takes precedence when both are enabled).
+#[derive(Debug, Clone, Default)]
+pub struct TlsConfig {
+    pub root_certificates: Vec<PathBuf>,
+    pub client_certificate: Option<ClientCertificate>,
+    pub insecure: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientCertificate {
+    // PKCS#12 archive, supported by the native-tls backend.
+    Pkcs12 { path: PathBuf, password: String },
+    // PEM certificate chain and private key, supported by the rustls backend.
+    Pem { certificate: PathBuf, key: PathBuf },
+}
+
+impl TlsConfig {
+    pub fn new() -> TlsConfig {
+        TlsConfig::default()
+    }
+
+    // Trusts the certificates of a PEM file (which may be a bundle) on top
+    // of the default roots.
+    pub fn root_certificate<P: AsRef<Path>>(&mut self, path: P) -> &mut TlsConfig {
+        self.root_certificates.push(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    pub fn pkcs12<P: AsRef<Path>>(&mut self, path: P, password: &str) -> &mut TlsConfig {
+        self.client_certificate = Some(ClientCertificate::Pkcs12 {
+            path: path.as_ref().to_path_buf(),
+            password: password.to_string(),
+        });
+
+        self
+    }
+
+    pub fn pem_identity<P: AsRef<Path>>(&mut self, certificate: P, key: P) -> &mut TlsConfig {
+        self.client_certificate = Some(ClientCertificate::Pem {
+            certificate: certificate.as_ref().to_path_buf(),
+            key: key.as_ref().to_path_buf(),
+        });
+
+        self
+    }
+
+    // For local testing only. Skips the check that the server certificate
+    // was issued for the host, on both backends. The chain is still verified,
+    // so a self-signed certificate has to be added as a root certificate.
+    // rustls can only reach hosts given as IP addresses in this mode.
+    pub fn insecure(&mut self, insecure: bool) -> &mut TlsConfig {
+        self.insecure = insecure;
+
+        self
+    }
+}
+
+fn read(path: &Path) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+
+    Ok(data)
+}
+
+// Splits a PEM bundle into its certificates.
+pub fn pem_certificates(pem: &str) -> Vec<String> {
+    const BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+    const END: &str = "-----END CERTIFICATE-----";
+
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+
+    while let Some(start) = rest.find(BEGIN) {
+        let end = match rest[start..].find(END) {
+            Some(end) => start + end + END.len(),
+            None => break,
+        };
+        certificates.push(format!("{}\n", &rest[start..end]));
+        rest = &rest[end..];
+    }
+
+    certificates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::client::HttpConnector;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn splits_pem_bundles() {
+        let bundle = "# internal root\n-----BEGIN CERTIFICATE-----\nAAA=\n-----END CERTIFICATE-----\n\
+                      -----BEGIN CERTIFICATE-----\nBBB=\n-----END CERTIFICATE-----\n";
+        let certificates = pem_certificates(bundle);
+
+        assert_eq!(certificates.len(), 2);
+        assert_eq!(certificates[1], "-----BEGIN CERTIFICATE-----\nBBB=\n-----END CERTIFICATE-----\n");
+    }
+
+    #[test]
+    fn reports_missing_files() {
+        let mut config = TlsConfig::new();
+        config.root_certificate("/nonexistent/ca.pem");
+
+        let core = Core::new().unwrap();
+        match connector(HttpConnector::new(1, &core.handle()), &config) {
+            Err(e) => assert!(e.contains("/nonexistent/ca.pem")),
+            Ok(_) => panic!("Connector built from a missing certificate"),
+        }
+    }
+}
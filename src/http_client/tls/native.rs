@@ -0,0 +1,37 @@
+use hyper::client::Connect;
+use native_tls::{Certificate, Pkcs12, TlsConnector};
+
+use super::{pem_certificates, read, ClientCertificate, TlsConfig};
+
+pub type HttpsConnector<T> = ::hyper_tls::HttpsConnector<T>;
+
+pub fn connector<T: Connect>(connector: T, config: &TlsConfig) -> Result<HttpsConnector<T>, String> {
+    let mut builder = TlsConnector::builder().map_err(|e| e.to_string())?;
+
+    for path in &config.root_certificates {
+        let pem = String::from_utf8(read(path)?).map_err(|_| format!("Invalid PEM file: {}", path.display()))?;
+        for pem in pem_certificates(&pem) {
+            let certificate = Certificate::from_pem(pem.as_bytes())
+                .map_err(|e| format!("Invalid certificate in {}: {}", path.display(), e))?;
+            builder.add_root_certificate(certificate).map_err(|e| e.to_string())?;
+        }
+    }
+
+    match config.client_certificate {
+        Some(ClientCertificate::Pkcs12 { ref path, ref password }) => {
+            let identity = Pkcs12::from_der(&read(path)?, password)
+                .map_err(|e| format!("Invalid client certificate {}: {}", path.display(), e))?;
+            builder.identity(identity).map_err(|e| e.to_string())?;
+        }
+        Some(ClientCertificate::Pem { .. }) => {
+            return Err("The native-tls backend needs a PKCS#12 client certificate".to_string());
+        }
+        None => {}
+    }
+
+    let tls = builder.build().map_err(|e| e.to_string())?;
+    let mut https = HttpsConnector::from((connector, tls));
+    https.danger_disable_hostname_verification(config.insecure);
+
+    Ok(https)
+}
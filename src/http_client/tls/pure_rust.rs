@@ -0,0 +1,230 @@
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use futures::{future, Future, Poll};
+use hyper::Uri;
+use hyper::client::Connect;
+use rustls::{Certificate, ClientConfig, ClientSession, RootCertStore, ServerCertVerified, ServerCertVerifier, TLSError};
+use rustls::internal::pemfile;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{ClientConfigExt, TlsStream};
+use tokio_service::Service;
+use untrusted::Input;
+use webpki::{DNSName, DNSNameRef, EndEntityCert, SignatureAlgorithm, TLSServerTrustAnchors, Time};
+
+use super::{read, ClientCertificate, TlsConfig};
+
+// Signature algorithms accepted in server certificate chains, the same as
+// rustls' own verifier.
+static SIGNATURE_ALGORITHMS: &[&SignatureAlgorithm] = &[
+    &::webpki::ECDSA_P256_SHA256,
+    &::webpki::ECDSA_P256_SHA384,
+    &::webpki::ECDSA_P384_SHA256,
+    &::webpki::ECDSA_P384_SHA384,
+    &::webpki::RSA_PSS_2048_8192_SHA256_LEGACY_KEY,
+    &::webpki::RSA_PSS_2048_8192_SHA384_LEGACY_KEY,
+    &::webpki::RSA_PSS_2048_8192_SHA512_LEGACY_KEY,
+    &::webpki::RSA_PKCS1_2048_8192_SHA1,
+    &::webpki::RSA_PKCS1_2048_8192_SHA256,
+    &::webpki::RSA_PKCS1_2048_8192_SHA384,
+    &::webpki::RSA_PKCS1_2048_8192_SHA512,
+    &::webpki::RSA_PKCS1_3072_8192_SHA384,
+];
+
+// Stands in for IP address hosts, which have no DNS name to send (SNI is off
+// for them) and aren't checked against the certificate in insecure mode.
+const IP_HOST_NAME: &str = "ip.invalid";
+
+pub struct HttpsConnector<T> {
+    connector: T,
+    config: Arc<ClientConfig>,
+    // Used for IP address hosts, only available in insecure mode.
+    ip_config: Option<Arc<ClientConfig>>,
+}
+
+pub fn connector<T: Connect>(connector: T, config: &TlsConfig) -> Result<HttpsConnector<T>, String> {
+    let mut tls = ClientConfig::new();
+    tls.root_store.add_server_trust_anchors(&::webpki_roots::TLS_SERVER_ROOTS);
+
+    for path in &config.root_certificates {
+        tls.root_store
+            .add_pem_file(&mut &read(path)?[..])
+            .map_err(|_| format!("Invalid PEM file: {}", path.display()))?;
+    }
+
+    match config.client_certificate {
+        Some(ClientCertificate::Pem { ref certificate, ref key }) => {
+            let chain = pemfile::certs(&mut &read(certificate)?[..])
+                .map_err(|_| format!("Invalid client certificate: {}", certificate.display()))?;
+            let pem = read(key)?;
+            let mut keys = pemfile::rsa_private_keys(&mut &pem[..]).unwrap_or_default();
+            if keys.is_empty() {
+                keys = pemfile::pkcs8_private_keys(&mut &pem[..]).unwrap_or_default();
+            }
+            let key = keys
+                .into_iter()
+                .next()
+                .ok_or_else(|| format!("No private key in {}", key.display()))?;
+            tls.set_single_client_cert(chain, key);
+        }
+        Some(ClientCertificate::Pkcs12 { .. }) => {
+            return Err("The rustls backend needs a PEM client certificate and key".to_string());
+        }
+        None => {}
+    }
+
+    let mut ip_config = None;
+    if config.insecure {
+        tls.dangerous().set_certificate_verifier(Arc::new(NoHostnameVerification));
+        let mut ip_tls = tls.clone();
+        ip_tls.enable_sni = false;
+        ip_config = Some(Arc::new(ip_tls));
+    }
+
+    Ok(HttpsConnector {
+        connector,
+        config: Arc::new(tls),
+        ip_config,
+    })
+}
+
+// Verifies the certificate chain against the roots like rustls does, but not
+// that the certificate was issued for the host (the native-tls meaning of
+// insecure).
+struct NoHostnameVerification;
+
+impl ServerCertVerifier for NoHostnameVerification {
+    fn verify_server_cert(&self, roots: &RootCertStore, presented_certs: &[Certificate], _dns_name: DNSNameRef, _ocsp_response: &[u8]) -> Result<ServerCertVerified, TLSError> {
+        let (certificate, intermediates) = presented_certs.split_first().ok_or(TLSError::NoCertificatesPresented)?;
+        let anchors: Vec<_> = roots.roots.iter().map(|root| root.to_trust_anchor()).collect();
+        let chain: Vec<_> = intermediates.iter().map(|certificate| Input::from(&certificate.0)).collect();
+        let now = Time::try_from(SystemTime::now()).map_err(|_| TLSError::FailedToGetCurrentTime)?;
+
+        EndEntityCert::from(Input::from(&certificate.0))
+            .and_then(|certificate| {
+                certificate.verify_is_valid_tls_server_cert(SIGNATURE_ALGORITHMS, &TLSServerTrustAnchors(&anchors), &chain, now)
+            })
+            .map_err(TLSError::WebPKIError)?;
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+// Picks the TLS config and name to connect to a host with.
+fn session(host: &str, config: &Arc<ClientConfig>, ip_config: &Option<Arc<ClientConfig>>) -> Result<(Arc<ClientConfig>, DNSName), String> {
+    if host.trim_matches(|c| c == '[' || c == ']').parse::<IpAddr>().is_ok() {
+        let config = ip_config.clone().ok_or_else(|| {
+            format!("The rustls backend can't verify a certificate for the IP address {}, use a host name, insecure mode or the native-tls backend", host)
+        })?;
+        let name = DNSNameRef::try_from_ascii_str(IP_HOST_NAME).map_err(|_| "Invalid host name".to_string())?;
+        return Ok((config, name.into()));
+    }
+
+    let name = DNSNameRef::try_from_ascii_str(host).map_err(|_| format!("Invalid host name {}", host))?;
+
+    Ok((config.clone(), name.into()))
+}
+
+impl<T: Connect> Service for HttpsConnector<T> {
+    type Request = Uri;
+    type Response = MaybeHttpsStream<T::Output>;
+    type Error = io::Error;
+    type Future = Box<dyn Future<Item = MaybeHttpsStream<T::Output>, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Box<dyn Future<Item = MaybeHttpsStream<T::Output>, Error = io::Error>> {
+        if uri.scheme() != Some("https") {
+            return Box::new(self.connector.connect(uri).map(MaybeHttpsStream::Http));
+        }
+
+        let (config, host) = match session(uri.host().unwrap_or(""), &self.config, &self.ip_config) {
+            Ok(session) => session,
+            Err(e) => return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidInput, e))),
+        };
+
+        Box::new(
+            self.connector
+                .connect(uri)
+                .and_then(move |tcp| config.connect_async(host.as_ref(), tcp))
+                .map(|tls| MaybeHttpsStream::Https(Box::new(tls))),
+        )
+    }
+}
+
+pub enum MaybeHttpsStream<S> {
+    Http(S),
+    Https(Box<TlsStream<S, ClientSession>>),
+}
+
+impl<S: AsyncRead + AsyncWrite> Read for MaybeHttpsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.read(buf),
+            MaybeHttpsStream::Https(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> Write for MaybeHttpsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.write(buf),
+            MaybeHttpsStream::Https(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.flush(),
+            MaybeHttpsStream::Https(ref mut s) => s.flush(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite> AsyncRead for MaybeHttpsStream<S> {}
+
+impl<S: AsyncRead + AsyncWrite> AsyncWrite for MaybeHttpsStream<S> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            MaybeHttpsStream::Http(ref mut s) => s.shutdown(),
+            MaybeHttpsStream::Https(ref mut s) => s.shutdown(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::client::HttpConnector;
+    use tokio_core::reactor::Core;
+
+    fn configs(insecure: bool) -> (Arc<ClientConfig>, Option<Arc<ClientConfig>>) {
+        let mut config = TlsConfig::new();
+        config.insecure(insecure);
+        let core = Core::new().unwrap();
+        let https = connector(HttpConnector::new(1, &core.handle()), &config).unwrap();
+
+        (https.config, https.ip_config)
+    }
+
+    #[test]
+    fn rejects_ip_hosts_unless_insecure() {
+        let (config, ip_config) = configs(false);
+        match session("127.0.0.1", &config, &ip_config) {
+            Err(e) => assert!(e.contains("127.0.0.1")),
+            Ok(_) => panic!("Verified an IP address host"),
+        }
+        assert!(session("mq-aws-us-east-1-1.iron.io", &config, &ip_config).is_ok());
+    }
+
+    #[test]
+    fn reaches_ip_hosts_without_sni_when_insecure() {
+        let (config, ip_config) = configs(true);
+        let (session_config, name) = session("[::1]", &config, &ip_config).unwrap();
+
+        assert!(!session_config.enable_sni);
+        assert_eq!(AsRef::<str>::as_ref(&name), IP_HOST_NAME);
+        assert!(session("localhost", &config, &ip_config).unwrap().0.enable_sni);
+    }
+}
@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{self, Value};
+
+use queue::message::Message;
+use worker::{Retry, WorkerPool, WorkerStats};
+use Client;
+
+const DEFAULT_MAX_RETRIES: u32 = 10;
+const DEFAULT_BACKOFF_BASE: u32 = 15;
+const DEFAULT_BACKOFF_MAX: u32 = 3600;
+// Longest delay IronMQ takes, 7 days.
+const MAX_DELAY: u32 = 604_800;
+
+type Perform = dyn Fn(Value) -> Result<(), String> + Send + Sync;
+
+// Delay before retrying a failed job, by the number of the attempt that
+// failed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backoff {
+    Constant(u32),
+    // `base` * 2^(attempt - 1) seconds, at most `max`.
+    Exponential { base: u32, max: u32 },
+}
+
+impl Backoff {
+    pub fn delay(&self, attempt: u32) -> u32 {
+        let delay = match *self {
+            Backoff::Constant(delay) => delay,
+            Backoff::Exponential { base, max } => {
+                let factor = 1u32.checked_shl(attempt.saturating_sub(1)).unwrap_or(u32::MAX);
+                base.saturating_mul(factor).min(max)
+            }
+        };
+
+        delay.min(MAX_DELAY)
+    }
+}
+
+// A kind of job. Jobs are pushed to, and consumed from, the queue named
+// after the job.
+pub trait Job {
+    type Payload: Serialize + DeserializeOwned;
+
+    const NAME: &'static str;
+
+    // Retries after the first attempt. A job that fails them all is given
+    // up on.
+    fn max_retries() -> u32 {
+        DEFAULT_MAX_RETRIES
+    }
+
+    fn backoff() -> Backoff {
+        Backoff::Exponential {
+            base: DEFAULT_BACKOFF_BASE,
+            max: DEFAULT_BACKOFF_MAX,
+        }
+    }
+}
+
+// Body of job messages.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct JobEnvelope {
+    pub job: String,
+    pub payload: Value,
+}
+
+impl Client {
+    pub fn enqueue<J: Job>(&mut self, payload: &J::Payload) -> Result<String, String> {
+        self.enqueue_in::<J>(payload, 0)
+    }
+
+    // Enqueues a job to run in `delay` seconds.
+    pub fn enqueue_in<J: Job>(&mut self, payload: &J::Payload, delay: u32) -> Result<String, String> {
+        let envelope = JobEnvelope {
+            job: J::NAME.to_string(),
+            payload: serde_json::to_value(payload).map_err(|e| e.to_string())?,
+        };
+        let body = serde_json::to_string(&envelope).map_err(|e| e.to_string())?;
+
+        self.queue(J::NAME.to_string()).push_message(Message::new(&body, delay))
+    }
+}
+
+struct Registration {
+    perform: Arc<Perform>,
+    max_retries: u32,
+    backoff: Backoff,
+}
+
+// Runs registered jobs on a `WorkerPool` consuming their queues. A failed
+// job is released with the delay of its backoff for the attempt, counted
+// by how often its message was reserved. Reservations that time out and
+// releases at shutdown count as attempts too.
+pub struct JobWorker {
+    pool: WorkerPool,
+    jobs: HashMap<String, Registration>,
+    dead_queue: Option<String>,
+}
+
+impl JobWorker {
+    pub fn new() -> JobWorker {
+        JobWorker {
+            pool: WorkerPool::new(&[]),
+            jobs: HashMap::new(),
+            dead_queue: None,
+        }
+    }
+
+    pub fn register<J, F>(&mut self, handler: F) -> &mut JobWorker
+    where
+        J: Job,
+        J::Payload: 'static,
+        F: Fn(J::Payload) -> Result<(), String> + Send + Sync + 'static,
+    {
+        let perform = move |payload: Value| {
+            let payload: J::Payload = serde_json::from_value(payload).map_err(|e| format!("Invalid payload: {}", e))?;
+            handler(payload)
+        };
+
+        self.pool.queue(J::NAME);
+        self.jobs.insert(
+            J::NAME.to_string(),
+            Registration {
+                perform: Arc::new(perform),
+                max_retries: J::max_retries(),
+                backoff: J::backoff(),
+            },
+        );
+
+        self
+    }
+
+    // Queue the bodies of jobs given up on are pushed to. Without one they
+    // are only logged.
+    pub fn dead_queue(&mut self, dead_queue: &str) -> &mut JobWorker {
+        self.dead_queue = Some(dead_queue.to_string());
+
+        self
+    }
+
+    // Threads, limits and shutdown of the underlying pool.
+    pub fn pool(&mut self) -> &mut WorkerPool {
+        &mut self.pool
+    }
+
+    // Runs until shut down, like `WorkerPool::run`.
+    pub fn run(&mut self, client: &mut Client) -> Result<WorkerStats, String> {
+        let performers: HashMap<String, Arc<Perform>> =
+            self.jobs.iter().map(|(name, job)| (name.clone(), job.perform.clone())).collect();
+        let retries: HashMap<String, (u32, Backoff)> =
+            self.jobs.iter().map(|(name, job)| (name.clone(), (job.max_retries, job.backoff))).collect();
+        let dead_queue = self.dead_queue.clone();
+
+        self.pool.retry_policy(move |queue, message| {
+            let attempt = message.reserved_count.unwrap_or(1).max(1);
+            match retries.get(queue) {
+                Some(&(max_retries, backoff)) if attempt <= max_retries => Retry::After(backoff.delay(attempt)),
+                _ => Retry::GiveUp(dead_queue.clone()),
+            }
+        });
+
+        self.pool.run(client, move |message| {
+            let envelope: JobEnvelope = serde_json::from_str(&message.body).map_err(|_| "Malformed job".to_string())?;
+            match performers.get(&envelope.job) {
+                Some(perform) => perform(envelope.payload),
+                None => Err(format!("No handler for job {}", envelope.job)),
+            }
+        })
+    }
+}
+
+impl Default for JobWorker {
+    fn default() -> JobWorker {
+        JobWorker::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use test_server::{FakeServer, Response};
+
+    struct SendEmail;
+
+    impl Job for SendEmail {
+        type Payload = String;
+
+        const NAME: &'static str = "send_email";
+
+        fn max_retries() -> u32 {
+            2
+        }
+    }
+
+    #[test]
+    fn backoff_delays() {
+        let backoff = Backoff::Exponential { base: 15, max: 100 };
+        assert_eq!(backoff.delay(1), 15);
+        assert_eq!(backoff.delay(3), 60);
+        assert_eq!(backoff.delay(4), 100);
+        assert_eq!(backoff.delay(40), 100);
+        assert_eq!(Backoff::Exponential { base: 1, max: u32::MAX }.delay(40), MAX_DELAY);
+        assert_eq!(Backoff::Constant(5).delay(9), 5);
+    }
+
+    #[test]
+    fn retries_failed_jobs_with_backoff() {
+        let mut reserved = false;
+        let server = FakeServer::start(move |request| {
+            if request.path.ends_with("/reservations") && !reserved {
+                reserved = true;
+                let messages: Vec<String> = [("1", "ok", 1), ("2", "fail", 2), ("3", "fail", 3)]
+                    .iter()
+                    .map(|&(id, to, count)| {
+                        let body = json!({ "job": "send_email", "payload": to }).to_string();
+                        json!({ "id": id, "body": body, "reservation_id": "r", "reserved_count": count }).to_string()
+                    })
+                    .collect();
+                Response::ok(&format!("{{\"messages\":[{}]}}", messages.join(",")))
+            } else if request.path.ends_with("/reservations") {
+                Response::ok("{\"messages\":[]}")
+            } else if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else {
+                Response::ok("{\"msg\":\"Deleted\"}")
+            }
+        });
+        let mut client = server.client();
+
+        let mut worker = JobWorker::new();
+        worker.pool().threads(1).wait(0).handle_signals(false);
+        let shutdown = worker.pool().shutdown_handle();
+        let handled = Arc::new(Mutex::new(0));
+        let counter = handled.clone();
+        worker.register::<SendEmail, _>(move |to: String| {
+            *counter.lock().unwrap() += 1;
+            if *counter.lock().unwrap() == 3 {
+                shutdown.shutdown();
+            }
+            if to == "fail" {
+                return Err("SMTP error".to_string());
+            }
+            Ok(())
+        });
+
+        let stats = worker.run(&mut client).unwrap();
+
+        assert_eq!(stats, WorkerStats { processed: 1, failed: 2, released: 0 });
+        let mut requests: Vec<String> = server
+            .requests()
+            .iter()
+            .filter(|request| request.method != "POST" || request.path.ends_with("/release"))
+            .map(|request| match request.json()["delay"].as_u64() {
+                Some(delay) => format!("release {}", delay),
+                None => request.line(),
+            })
+            .collect();
+        requests.sort();
+        assert_eq!(
+            requests,
+            vec![
+                "DELETE /3/projects/project/queues/send_email/messages/1",
+                "DELETE /3/projects/project/queues/send_email/messages/3",
+                "release 30",
+            ]
+        );
+    }
+}
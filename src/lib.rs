@@ -1,32 +1,68 @@
+pub mod bench;
+pub mod config;
 pub mod http_client;
+pub mod jobs;
+pub mod metrics;
+pub mod outbox;
 pub mod queue;
+pub mod worker;
+#[cfg(test)]
+mod test_server;
 
 extern crate futures;
 extern crate hyper;
+#[cfg(feature = "default-tls")]
 extern crate hyper_tls;
+#[cfg(feature = "default-tls")]
+extern crate native_tls;
+#[cfg(feature = "rustls-tls")]
+extern crate rustls;
+#[cfg(feature = "rustls-tls")]
+extern crate tokio_rustls;
+#[cfg(feature = "rustls-tls")]
+extern crate webpki;
+#[cfg(feature = "rustls-tls")]
+extern crate webpki_roots;
+#[cfg(feature = "rustls-tls")]
+extern crate untrusted;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_service;
 #[macro_use]
 extern crate serde_json;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+extern crate log;
+extern crate signal_hook;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
 use hyper::{ Method };
 use serde_json::{Value};
-use std::{
-    env,
-    fs::File
-};
+use std::env;
+use std::sync::Arc;
 
 use queue::*;
 use queue::queue_info::*;
 use http_client::*;
+use http_client::circuit_breaker::CircuitBreaker;
+use http_client::config::HttpConfig;
+use http_client::credentials::CredentialProvider;
+use http_client::hosts::HostPool;
+use http_client::keystone::KeystoneCredentials;
+use http_client::middleware::Middleware;
+use http_client::rate_limit::RateLimiter;
+use metrics::{Metrics, NoopMetrics};
+
+pub use config::Config;
 
 const PER_PAGE: u8 = 30;
 
 pub struct Client {
     pub base_path: String,
-    http_client: HttpClient
+    http_client: HttpClient,
+    metrics: Arc<dyn Metrics>
 }
 
 impl Client {
@@ -37,14 +73,122 @@ impl Client {
         Client {
             base_path,
             http_client,
+            metrics: Arc::new(NoopMetrics),
+        }
+    }
+
+    pub fn with_credentials(host: String, project_id: String, credentials: Box<dyn CredentialProvider>) -> Client {
+        let base_path = format!("https://{}/3/projects/{}/", host, project_id);
+        let http_client = HttpClient::with_credentials(credentials);
+
+        Client {
+            base_path,
+            http_client,
+            metrics: Arc::new(NoopMetrics),
         }
     }
 
+    pub fn with_hosts(hosts: Vec<String>, project_id: String, token: String) -> Result<Client, String> {
+        let primary = match hosts.first() {
+            Some(primary) => primary.clone(),
+            None => return Err("No hosts given".to_string()),
+        };
+
+        let mut client = Client::new(primary, project_id, token);
+        client.failover(hosts)?;
+
+        Ok(client)
+    }
+
+    pub fn failover(&mut self, hosts: Vec<String>) -> Result<&mut HostPool, String> {
+        self.http_client.set_hosts(HostPool::new(hosts)?);
+
+        self.http_client.hosts().ok_or_else(|| "No hosts given".to_string())
+    }
+
+    pub fn last_host(&self) -> Option<String> {
+        self.http_client.last_host()
+    }
+
+    // HTTP status of the last response, e.g. to tell a rejected request
+    // from a failing server. `None` if the last request got no response.
+    pub fn last_status(&self) -> Option<u16> {
+        self.http_client.last_status().map(|status| status.as_u16())
+    }
+
+    pub fn http_config(&mut self, config: HttpConfig) -> &mut Client {
+        self.http_client.set_config(config);
+
+        self
+    }
+
+    pub fn middleware(&mut self, middleware: Box<dyn Middleware>) -> &mut Client {
+        self.http_client.add_middleware(middleware);
+
+        self
+    }
+
+    pub fn rate_limiter(&mut self, rate_limiter: Arc<RateLimiter>) -> &mut Client {
+        self.http_client.set_rate_limiter(Some(rate_limiter));
+
+        self
+    }
+
+    pub fn circuit_breaker(&mut self, circuit_breaker: CircuitBreaker) -> &mut CircuitBreaker {
+        self.http_client.set_circuit_breaker(Some(circuit_breaker));
+
+        self.http_client.circuit_breaker().unwrap()
+    }
+
+    pub fn metrics(&mut self, metrics: Arc<dyn Metrics>) -> &mut Client {
+        self.metrics = metrics;
+
+        self
+    }
+
     pub fn from_file() -> Client {
-        let reader = File::open("iron.json").expect("iron.json not found");
-        let config: Config = serde_json::from_reader(reader).expect("Json parsing error");
-        
-        Client::new(config.host, config.project_id, config.token)
+        let profile = env::var("IRON_PROFILE").unwrap_or_else(|_| config::DEFAULT_PROFILE.to_string());
+
+        Client::from_profile(&profile)
+    }
+
+    pub fn from_profile(profile: &str) -> Client {
+        Client::try_from_profile(profile).expect("Configuration error")
+    }
+
+    pub fn try_from_profile(profile: &str) -> Result<Client, String> {
+        let config = Config::from_file(config::CONFIG_FILE, profile)?;
+
+        Ok(Client::from_config(config))
+    }
+
+    pub fn from_config(config: Config) -> Client {
+        let host = config.host.clone();
+        let mut client = match config.keystone {
+            // Keystone is reached with the settings later given to
+            // `http_config` as well.
+            Some(keystone) => {
+                let credentials = Box::new(KeystoneCredentials::new(keystone));
+                Client::with_credentials(config.host, config.project_id, credentials)
+            }
+            None => {
+                let token = config.token.expect("Missed token");
+                Client::new(config.host, config.project_id, token)
+            }
+        };
+
+        if let Some(alternates) = config.alternates {
+            let mut hosts = vec![host];
+            hosts.extend(alternates);
+            // Never empty, it holds `host`.
+            let _ = client.failover(hosts);
+        }
+
+        client
+    }
+
+    pub fn profiles() -> Vec<String> {
+        Config::profiles(config::CONFIG_FILE).expect("Configuration error")
     }
 
     pub fn from_env() -> Client {
@@ -90,7 +234,11 @@ impl Client {
         self.queue_list("", "", PER_PAGE)
     }
 
-    pub fn create_queue_with_config(&mut self, name: &String, config: &QueueInfo) -> QueueInfo {
+    pub fn create_queue_with_config(&mut self, name: &str, config: &QueueInfo) -> QueueInfo {
+        self.try_create_queue_with_config(name, config).unwrap()
+    }
+
+    pub fn try_create_queue_with_config(&mut self, name: &str, config: &QueueInfo) -> Result<QueueInfo, String> {
         let path = format!("{}queues/{}", self.base_path, name);
         
         let body = json!({
@@ -99,10 +247,13 @@ impl Client {
 
         let res = self.http_client.request(Method::Put, path, body.to_string());
 
-        let v: Value = serde_json::from_slice(&res).unwrap();
-        let queue_info: QueueInfo = serde_json::from_value(v["queue"].clone()).unwrap();
+        let v: Value = serde_json::from_slice(&res).map_err(|e| format!("Json parsing error: {}", e))?;
+        let queue_info: QueueInfo = match serde_json::from_value(v["queue"].clone()) {
+            Ok(queue_info) => queue_info,
+            Err(_) => return Err(v["msg"].to_string()),
+        };
 
-        queue_info
+        Ok(queue_info)
     }
     
 }
@@ -117,10 +268,3 @@ fn get_from_env(variable: &str) -> String {
 
     var
 }
-
-#[derive(Debug, Deserialize, Serialize)]
-pub struct Config {
-    project_id: String,
-    token: String,
-    host: String,
-}
@@ -0,0 +1,184 @@
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
+
+use std::fmt;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Operation {
+    Push,
+    Reserve,
+    Delete,
+    Touch,
+    Release,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Operation::Push => "push",
+            Operation::Reserve => "reserve",
+            Operation::Delete => "delete",
+            Operation::Touch => "touch",
+            Operation::Release => "release",
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    // The request timed out.
+    Timeout,
+    // Connection, TLS or proxy failure.
+    Transport,
+    // The queue, message or reservation doesn't exist (any more).
+    NotFound,
+    // Failed fast because the circuit breaker is open.
+    CircuitOpen,
+    // Refused by the client-side rate limiter.
+    RateLimited,
+    // Any other error returned by IronMQ.
+    Api,
+}
+
+impl ErrorKind {
+    // Classifies the `msg` of a failed operation.
+    pub fn classify(msg: &str) -> ErrorKind {
+        let msg = msg.to_lowercase();
+
+        if msg.contains("circuit open") {
+            ErrorKind::CircuitOpen
+        } else if msg.contains("request error") {
+            if msg.contains("timed out") {
+                ErrorKind::Timeout
+            } else {
+                ErrorKind::Transport
+            }
+        } else if msg.contains("rate limited") {
+            ErrorKind::RateLimited
+        } else if msg.contains("not found") {
+            ErrorKind::NotFound
+        } else {
+            ErrorKind::Api
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Transport => "transport",
+            ErrorKind::NotFound => "not_found",
+            ErrorKind::CircuitOpen => "circuit_open",
+            ErrorKind::RateLimited => "rate_limited",
+            ErrorKind::Api => "api",
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SequenceAnomaly {
+    // Sequence numbers given up on after waiting for them.
+    Gap,
+    // A sequence number that was already handled or held.
+    Duplicate,
+    // A message that arrived before its predecessors.
+    Early,
+}
+
+impl SequenceAnomaly {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SequenceAnomaly::Gap => "gap",
+            SequenceAnomaly::Duplicate => "duplicate",
+            SequenceAnomaly::Early => "early",
+        }
+    }
+}
+
+impl fmt::Display for SequenceAnomaly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+// Called by `Queue` after every push, reserve, delete, touch and release
+// with the number of messages it handled, or the kind of error it failed
+// with. Implementations must be cheap; they run on the calling thread.
+pub trait Metrics: Send + Sync {
+    fn record(&self, _queue: &str, _operation: Operation, _latency: Duration, _messages: usize, _error: Option<ErrorKind>) {}
+
+    // Called by `OrderedConsumer` with the number of messages concerned.
+    fn record_sequence(&self, _queue: &str, _anomaly: SequenceAnomaly, _count: u64) {}
+}
+
+pub struct NoopMetrics;
+
+impl Metrics for NoopMetrics {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use test_server::{FakeServer, Response};
+
+    type Record = (String, Operation, usize, Option<ErrorKind>);
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<Record>>);
+
+    impl Metrics for Recorder {
+        fn record(&self, queue: &str, operation: Operation, _latency: Duration, messages: usize, error: Option<ErrorKind>) {
+            self.0.lock().unwrap().push((queue.to_string(), operation, messages, error));
+        }
+    }
+
+    #[test]
+    fn records_queue_operations() {
+        let server = FakeServer::start(|request| {
+            if request.path.ends_with("/messages") {
+                Response::ok("{\"ids\":[\"1\",\"2\"]}")
+            } else {
+                Response::ok("{\"msg\":\"Queue not found\"}")
+            }
+        });
+
+        let recorder = Arc::new(Recorder::default());
+        let mut client = server.client();
+        client.metrics(recorder.clone());
+
+        let mut queue = client.queue("jobs".to_string());
+        assert!(queue.push_strings(vec!["a", "b"]).is_ok());
+        assert!(queue.reserve_messages(2).is_err());
+
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                ("jobs".to_string(), Operation::Push, 2, None),
+                ("jobs".to_string(), Operation::Reserve, 0, Some(ErrorKind::NotFound)),
+            ]
+        );
+    }
+
+    #[test]
+    fn classifies_errors() {
+        assert_eq!(ErrorKind::classify("\"Request error: Request timed out\""), ErrorKind::Timeout);
+        assert_eq!(ErrorKind::classify("\"Request error: Connection refused\""), ErrorKind::Transport);
+        assert_eq!(ErrorKind::classify("\"Queue not found\""), ErrorKind::NotFound);
+        assert_eq!(ErrorKind::classify("\"Rate limited\""), ErrorKind::RateLimited);
+        assert_eq!(ErrorKind::classify("\"Request error: Circuit open\""), ErrorKind::CircuitOpen);
+        assert_eq!(ErrorKind::classify("\"Reservation has timed out\""), ErrorKind::Api);
+        assert_eq!(ErrorKind::classify("\"Invalid body\""), ErrorKind::Api);
+    }
+}
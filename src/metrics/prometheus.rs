@@ -0,0 +1,195 @@
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use super::{ErrorKind, Metrics, Operation, SequenceAnomaly};
+
+// Upper bounds, in seconds, of the latency histogram buckets.
+const BUCKETS: [f64; 12] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0];
+
+#[derive(Default)]
+struct Series {
+    count: u64,
+    messages: u64,
+    sum: f64,
+    buckets: [u64; 12],
+}
+
+#[derive(Default)]
+struct Registry {
+    operations: BTreeMap<(String, Operation), Series>,
+    errors: BTreeMap<(String, Operation, ErrorKind), u64>,
+    anomalies: BTreeMap<(String, SequenceAnomaly), u64>,
+}
+
+// Keeps counters and latency histograms in memory and renders them in the
+// Prometheus text format, labeled by queue and operation.
+#[derive(Default)]
+pub struct PrometheusMetrics {
+    registry: Mutex<Registry>,
+}
+
+impl PrometheusMetrics {
+    pub fn new() -> PrometheusMetrics {
+        PrometheusMetrics::default()
+    }
+
+    pub fn render(&self) -> String {
+        let registry = self.registry.lock().unwrap();
+        let mut out = String::new();
+
+        out.push_str("# HELP ironmq_operations_total Queue operations performed.\n");
+        out.push_str("# TYPE ironmq_operations_total counter\n");
+        for (&(ref queue, operation), series) in &registry.operations {
+            out.push_str(&format!("ironmq_operations_total{{{}}} {}\n", labels(queue, operation), series.count));
+        }
+
+        out.push_str("# HELP ironmq_messages_total Messages pushed, reserved, deleted, touched or released.\n");
+        out.push_str("# TYPE ironmq_messages_total counter\n");
+        for (&(ref queue, operation), series) in &registry.operations {
+            out.push_str(&format!("ironmq_messages_total{{{}}} {}\n", labels(queue, operation), series.messages));
+        }
+
+        out.push_str("# HELP ironmq_operation_errors_total Failed queue operations by error kind.\n");
+        out.push_str("# TYPE ironmq_operation_errors_total counter\n");
+        for (&(ref queue, operation, kind), count) in &registry.errors {
+            out.push_str(&format!(
+                "ironmq_operation_errors_total{{{},kind=\"{}\"}} {}\n",
+                labels(queue, operation),
+                kind,
+                count
+            ));
+        }
+
+        out.push_str("# HELP ironmq_sequence_anomalies_total Gaps, duplicates and early messages of ordered queues.\n");
+        out.push_str("# TYPE ironmq_sequence_anomalies_total counter\n");
+        for (&(ref queue, anomaly), count) in &registry.anomalies {
+            out.push_str(&format!(
+                "ironmq_sequence_anomalies_total{{queue=\"{}\",kind=\"{}\"}} {}\n",
+                escape(queue),
+                anomaly,
+                count
+            ));
+        }
+
+        out.push_str("# HELP ironmq_operation_duration_seconds Latency of queue operations.\n");
+        out.push_str("# TYPE ironmq_operation_duration_seconds histogram\n");
+        for (&(ref queue, operation), series) in &registry.operations {
+            let labels = labels(queue, operation);
+            for (le, count) in BUCKETS.iter().zip(series.buckets.iter()) {
+                out.push_str(&format!("ironmq_operation_duration_seconds_bucket{{{},le=\"{}\"}} {}\n", labels, le, count));
+            }
+            out.push_str(&format!("ironmq_operation_duration_seconds_bucket{{{},le=\"+Inf\"}} {}\n", labels, series.count));
+            out.push_str(&format!("ironmq_operation_duration_seconds_sum{{{}}} {}\n", labels, series.sum));
+            out.push_str(&format!("ironmq_operation_duration_seconds_count{{{}}} {}\n", labels, series.count));
+        }
+
+        out
+    }
+
+    // Serves `render()` to any HTTP request on `addr` from a background
+    // thread. Returns the bound address, useful with port 0.
+    pub fn serve(metrics: Arc<PrometheusMetrics>, addr: &str) -> Result<SocketAddr, String> {
+        let listener = TcpListener::bind(addr).map_err(|e| e.to_string())?;
+        let local_addr = listener.local_addr().map_err(|e| e.to_string())?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+
+                let body = metrics.render();
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+            }
+        });
+
+        Ok(local_addr)
+    }
+}
+
+impl Metrics for PrometheusMetrics {
+    fn record(&self, queue: &str, operation: Operation, latency: Duration, messages: usize, error: Option<ErrorKind>) {
+        let seconds = latency.as_secs() as f64 + f64::from(latency.subsec_nanos()) / 1e9;
+        let mut registry = self.registry.lock().unwrap();
+
+        {
+            let series = registry.operations.entry((queue.to_string(), operation)).or_default();
+            series.count += 1;
+            series.messages += messages as u64;
+            series.sum += seconds;
+            for (le, count) in BUCKETS.iter().zip(series.buckets.iter_mut()) {
+                if seconds <= *le {
+                    *count += 1;
+                }
+            }
+        }
+
+        if let Some(kind) = error {
+            *registry.errors.entry((queue.to_string(), operation, kind)).or_insert(0) += 1;
+        }
+    }
+
+    fn record_sequence(&self, queue: &str, anomaly: SequenceAnomaly, count: u64) {
+        let mut registry = self.registry.lock().unwrap();
+        *registry.anomalies.entry((queue.to_string(), anomaly)).or_insert(0) += count;
+    }
+}
+
+fn labels(queue: &str, operation: Operation) -> String {
+    format!("queue=\"{}\",operation=\"{}\"", escape(queue), operation)
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpStream;
+
+    #[test]
+    fn renders_text_format() {
+        let metrics = PrometheusMetrics::new();
+        metrics.record("jobs", Operation::Push, Duration::from_millis(20), 3, None);
+        metrics.record("jobs", Operation::Push, Duration::from_millis(200), 0, Some(ErrorKind::Transport));
+        metrics.record_sequence("jobs", SequenceAnomaly::Gap, 2);
+
+        let text = metrics.render();
+
+        assert!(text.contains("ironmq_operations_total{queue=\"jobs\",operation=\"push\"} 2\n"));
+        assert!(text.contains("ironmq_messages_total{queue=\"jobs\",operation=\"push\"} 3\n"));
+        assert!(text.contains("ironmq_operation_errors_total{queue=\"jobs\",operation=\"push\",kind=\"transport\"} 1\n"));
+        assert!(text.contains("ironmq_operation_duration_seconds_bucket{queue=\"jobs\",operation=\"push\",le=\"0.025\"} 1\n"));
+        assert!(text.contains("ironmq_operation_duration_seconds_bucket{queue=\"jobs\",operation=\"push\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("ironmq_sequence_anomalies_total{queue=\"jobs\",kind=\"gap\"} 2\n"));
+    }
+
+    #[test]
+    fn serves_metrics() {
+        let metrics = Arc::new(PrometheusMetrics::new());
+        metrics.record("jobs", Operation::Reserve, Duration::from_millis(5), 1, None);
+        let addr = PrometheusMetrics::serve(metrics.clone(), "127.0.0.1:0").unwrap();
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("ironmq_operations_total{queue=\"jobs\",operation=\"reserve\"} 1\n"));
+    }
+}
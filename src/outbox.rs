@@ -0,0 +1,868 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use queue::Queue;
+use queue::message::Message;
+use Client;
+
+const DEFAULT_MAX_BYTES: u64 = 64 << 20;
+const DEFAULT_RETRY_INTERVAL: u64 = 5;
+const BATCH_SIZE: usize = 100;
+// Delivered records are cut from a log once they make up half of it and
+// at least this much, or all of it.
+const COMPACT_BYTES: u64 = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FullPolicy {
+    // Fail the push.
+    Reject,
+    // Drop the oldest spooled messages of the same queue to make room.
+    DropOldest,
+    // Wait up to the given time for the replayer to make room.
+    Block(Duration),
+}
+
+#[derive(Clone)]
+pub struct OutboxOptions {
+    max_bytes: u64,
+    full_policy: FullPolicy,
+    retry_interval: Duration,
+    sync: bool,
+}
+
+impl OutboxOptions {
+    pub fn new() -> OutboxOptions {
+        OutboxOptions {
+            max_bytes: DEFAULT_MAX_BYTES,
+            full_policy: FullPolicy::Reject,
+            retry_interval: Duration::from_secs(DEFAULT_RETRY_INTERVAL),
+            sync: true,
+        }
+    }
+
+    // Size of the spooled messages not pushed yet, over all queues.
+    pub fn max_bytes(&mut self, max_bytes: u64) -> &mut OutboxOptions {
+        self.max_bytes = max_bytes;
+
+        self
+    }
+
+    pub fn full_policy(&mut self, full_policy: FullPolicy) -> &mut OutboxOptions {
+        self.full_policy = full_policy;
+
+        self
+    }
+
+    // How long the replayer waits after a failed push.
+    pub fn retry_interval(&mut self, retry_interval: Duration) -> &mut OutboxOptions {
+        self.retry_interval = retry_interval;
+
+        self
+    }
+
+    // Whether every spooled message is synced to disk before `push`
+    // returns. Without it a crash of the machine may lose the last ones.
+    pub fn sync(&mut self, sync: bool) -> &mut OutboxOptions {
+        self.sync = sync;
+
+        self
+    }
+}
+
+impl Default for OutboxOptions {
+    fn default() -> OutboxOptions {
+        OutboxOptions::new()
+    }
+}
+
+// Where the replayer is in a spool, kept in `{name}.offset` next to the
+// `{name}.{generation}.log` it refers to.
+#[derive(Serialize, Deserialize)]
+struct SpoolState {
+    queue: String,
+    generation: u64,
+    offset: u64,
+}
+
+// The messages spooled for one queue: an append-only log of JSON lines,
+// of which the part before `offset` is pushed. Compaction copies the rest
+// into the log of the next generation.
+struct Spool {
+    dir: PathBuf,
+    name: String,
+    queue: String,
+    generation: u64,
+    offset: u64,
+    len: u64,
+    file: File,
+}
+
+impl Spool {
+    fn create(dir: &Path, queue: &str) -> Result<Spool, String> {
+        let name = file_name(queue);
+        let state = SpoolState {
+            queue: queue.to_string(),
+            generation: 0,
+            offset: 0,
+        };
+        save_state(dir, &name, &state)?;
+
+        Spool::load(dir, name, state)
+    }
+
+    fn open(dir: &Path, name: &str) -> Result<Spool, String> {
+        let file = File::open(dir.join(format!("{}.offset", name))).map_err(|e| e.to_string())?;
+        let state: SpoolState = serde_json::from_reader(file).map_err(|e| e.to_string())?;
+        let mut spool = Spool::load(dir, name.to_string(), state)?;
+        spool.repair()?;
+
+        // Logs of other generations are left over from an interrupted
+        // compaction.
+        let prefix = format!("{}.", name);
+        for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let file_name = entry.map_err(|e| e.to_string())?.file_name().to_string_lossy().to_string();
+            if file_name.starts_with(&prefix) && file_name.ends_with(".log") && dir.join(&file_name) != spool.log_path() {
+                let _ = fs::remove_file(dir.join(file_name));
+            }
+        }
+
+        Ok(spool)
+    }
+
+    fn load(dir: &Path, name: String, state: SpoolState) -> Result<Spool, String> {
+        let path = dir.join(format!("{}.{}.log", name, state.generation));
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        let len = file.metadata().map_err(|e| e.to_string())?.len();
+
+        Ok(Spool {
+            dir: dir.to_path_buf(),
+            name,
+            queue: state.queue,
+            generation: state.generation,
+            offset: cmp::min(state.offset, len),
+            len,
+            file,
+        })
+    }
+
+    fn log_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.{}.log", self.name, self.generation))
+    }
+
+    fn pending(&self) -> u64 {
+        self.len - self.offset
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let state = SpoolState {
+            queue: self.queue.clone(),
+            generation: self.generation,
+            offset: self.offset,
+        };
+
+        save_state(&self.dir, &self.name, &state)
+    }
+
+    // Cuts a record left half-written by a crash.
+    fn repair(&mut self) -> Result<(), String> {
+        let mut tail = Vec::new();
+        self.reader()?.read_to_end(&mut tail).map_err(|e| e.to_string())?;
+
+        let complete = tail.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1) as u64;
+        if self.offset + complete < self.len {
+            self.len = self.offset + complete;
+            self.file.set_len(self.len).map_err(|e| e.to_string())?;
+        }
+
+        Ok(())
+    }
+
+    fn reader(&self) -> Result<BufReader<File>, String> {
+        let mut file = File::open(self.log_path()).map_err(|e| e.to_string())?;
+        file.seek(SeekFrom::Start(self.offset)).map_err(|e| e.to_string())?;
+
+        Ok(BufReader::new(file))
+    }
+
+    fn append(&mut self, record: &[u8], sync: bool) -> Result<(), String> {
+        self.file.write_all(record).map_err(|e| e.to_string())?;
+        if sync {
+            self.file.sync_data().map_err(|e| e.to_string())?;
+        }
+        self.len += record.len() as u64;
+
+        Ok(())
+    }
+
+    // Up to `count` messages from the offset, with the offset after them.
+    // Records that can't be read are skipped.
+    fn read(&self, count: usize) -> Result<(Vec<Message>, u64), String> {
+        let mut reader = self.reader()?;
+        let mut messages = Vec::new();
+        let mut end = self.offset;
+        let mut line = String::new();
+
+        while messages.len() < count && end < self.len {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            if read == 0 || !line.ends_with('\n') {
+                break;
+            }
+            end += read as u64;
+
+            match serde_json::from_str(&line) {
+                Ok(message) => messages.push(message),
+                Err(e) => ::log::warn!("Skipping unreadable record of outbox spool {}: {}", self.queue, e),
+            }
+        }
+
+        Ok((messages, end))
+    }
+
+    // Marks the records before `end` as pushed.
+    fn advance(&mut self, end: u64) -> Result<(), String> {
+        if end <= self.offset {
+            return Ok(());
+        }
+        self.offset = cmp::min(end, self.len);
+        self.save()
+    }
+
+    // Drops the oldest records until at least `bytes` are freed, or none
+    // are left. Returns the bytes freed.
+    fn drop_oldest(&mut self, bytes: u64) -> Result<u64, String> {
+        let mut reader = self.reader()?;
+        let mut freed = 0;
+        let mut count = 0;
+        let mut line = String::new();
+
+        while freed < bytes && self.offset + freed < self.len {
+            line.clear();
+            let read = reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            if read == 0 {
+                break;
+            }
+            freed += read as u64;
+            count += 1;
+        }
+
+        ::log::warn!("Outbox is full, dropped the {} oldest messages for {}", count, self.queue);
+        let end = self.offset + freed;
+        self.advance(end)?;
+
+        Ok(freed)
+    }
+
+    // Keeps a message IronMQ won't take, with the error, in
+    // `{name}.rejected` for someone to look at.
+    fn reject(&self, message: &Message, error: &str) -> Result<(), String> {
+        let path = self.dir.join(format!("{}.rejected", self.name));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+        let record = json!({ "message": message, "error": error });
+        writeln!(file, "{}", record).map_err(|e| e.to_string())?;
+        file.sync_data().map_err(|e| e.to_string())
+    }
+
+    fn compact(&mut self) -> Result<(), String> {
+        let drained = self.offset == self.len;
+        if self.offset == 0 || !(drained || (self.offset >= COMPACT_BYTES && self.offset * 2 >= self.len)) {
+            return Ok(());
+        }
+
+        let old = self.log_path();
+        let generation = self.generation + 1;
+        let path = self.dir.join(format!("{}.{}.log", self.name, generation));
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)
+            .map_err(|e| e.to_string())?;
+        io::copy(&mut self.reader()?, &mut file).map_err(|e| e.to_string())?;
+        file.sync_data().map_err(|e| e.to_string())?;
+
+        // The new log is only used once the state says so.
+        let len = self.len - self.offset;
+        let state = SpoolState {
+            queue: self.queue.clone(),
+            generation,
+            offset: 0,
+        };
+        save_state(&self.dir, &self.name, &state)?;
+
+        self.file = OpenOptions::new().append(true).open(&path).map_err(|e| e.to_string())?;
+        self.generation = generation;
+        self.offset = 0;
+        self.len = len;
+        let _ = fs::remove_file(old);
+
+        Ok(())
+    }
+}
+
+fn save_state(dir: &Path, name: &str, state: &SpoolState) -> Result<(), String> {
+    let path = dir.join(format!("{}.offset", name));
+    let tmp = dir.join(format!("{}.offset.tmp", name));
+
+    let mut file = File::create(&tmp).map_err(|e| e.to_string())?;
+    serde_json::to_writer(&mut file, state).map_err(|e| e.to_string())?;
+    file.sync_data().map_err(|e| e.to_string())?;
+    fs::rename(&tmp, &path).map_err(|e| e.to_string())
+}
+
+// Queue names may hold characters file names can't; dots are escaped too
+// as they separate the generation.
+fn file_name(queue: &str) -> String {
+    let mut name = String::new();
+    for byte in queue.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => name.push(byte as char),
+            _ => name.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    name
+}
+
+struct State {
+    spools: HashMap<String, Spool>,
+    closed: bool,
+}
+
+impl State {
+    fn pending(&self) -> u64 {
+        self.spools.values().map(Spool::pending).sum()
+    }
+
+    // The next batch to push, going round the queues. Queues being isolated
+    // are pushed one message at a time.
+    fn next_batch(&mut self, next: &mut usize, isolated: &HashMap<String, usize>) -> Option<(String, u64, u64, Vec<Message>)> {
+        let mut queues: Vec<String> = self.spools.keys().cloned().collect();
+        queues.sort();
+
+        for i in 0..queues.len() {
+            let queue = &queues[(*next + i) % queues.len()];
+            let spool = self.spools.get_mut(queue).unwrap();
+            if spool.pending() == 0 {
+                continue;
+            }
+
+            let count = if isolated.contains_key(queue) { 1 } else { BATCH_SIZE };
+            match spool.read(count) {
+                Ok((ref messages, end)) if messages.is_empty() => {
+                    if let Err(e) = spool.advance(end) {
+                        ::log::warn!("Saving outbox spool {} failed: {}", queue, e);
+                    }
+                }
+                Ok((messages, end)) => {
+                    *next += i + 1;
+                    return Some((queue.clone(), spool.generation, end, messages));
+                }
+                Err(e) => ::log::warn!("Reading outbox spool {} failed: {}", queue, e),
+            }
+        }
+
+        None
+    }
+}
+
+struct Shared {
+    dir: PathBuf,
+    options: OutboxOptions,
+    state: Mutex<State>,
+    // Signalled when messages are spooled, pushed or dropped, and on close.
+    changed: Condvar,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+// Spools messages on disk, in a log per queue, and pushes them from a
+// background thread with a client of its own from `client`. Messages are
+// pushed in the order they were spooled, at least once: a crash right
+// after a push may push its messages again when the outbox is reopened.
+pub struct Outbox {
+    shared: Arc<Shared>,
+    replayer: Option<JoinHandle<()>>,
+}
+
+impl Outbox {
+    pub fn open<P, F>(dir: P, options: OutboxOptions, client: F) -> Result<Outbox, String>
+    where
+        P: AsRef<Path>,
+        F: FnOnce() -> Client + Send + 'static,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+
+        let mut spools = HashMap::new();
+        for entry in fs::read_dir(&dir).map_err(|e| e.to_string())? {
+            let file_name = entry.map_err(|e| e.to_string())?.file_name().to_string_lossy().to_string();
+            if let Some(name) = file_name.strip_suffix(".offset") {
+                let spool = Spool::open(&dir, name)?;
+                spools.insert(spool.queue.clone(), spool);
+            }
+        }
+
+        let shared = Arc::new(Shared {
+            dir,
+            options,
+            state: Mutex::new(State { spools, closed: false }),
+            changed: Condvar::new(),
+        });
+
+        let replayer = {
+            let shared = shared.clone();
+            thread::spawn(move || {
+                let mut client = client();
+                replay(&shared, &mut client);
+            })
+        };
+
+        Ok(Outbox {
+            shared,
+            replayer: Some(replayer),
+        })
+    }
+
+    // Spools a message to be pushed to `queue`.
+    pub fn push(&self, queue: &str, message: Message) -> Result<(), String> {
+        // Only what a push sends is kept.
+        let message = match message.delay {
+            Some(delay) => Message::new(&message.body, delay),
+            None => Message::with_body(&message.body),
+        };
+        let mut record = serde_json::to_vec(&message).map_err(|e| e.to_string())?;
+        record.push(b'\n');
+        let size = record.len() as u64;
+        if size > self.shared.options.max_bytes {
+            return Err("Message is larger than the outbox".to_string());
+        }
+
+        let mut state = self.make_room(self.shared.lock(), queue, size)?;
+        if !state.spools.contains_key(queue) {
+            let spool = Spool::create(&self.shared.dir, queue)?;
+            state.spools.insert(queue.to_string(), spool);
+        }
+        state.spools.get_mut(queue).unwrap().append(&record, self.shared.options.sync)?;
+        self.shared.changed.notify_all();
+
+        Ok(())
+    }
+
+    // Pushes to the queue right away, unless it fails or earlier messages
+    // for it are still spooled: then the message is spooled and `None`
+    // returned. Errors rejecting the message itself (400, 413) are returned
+    // as they are.
+    pub fn push_or_spool(&self, queue: &mut Queue, message: Message) -> Result<Option<String>, String> {
+        if self.pending_for(&queue.name) == 0 {
+            match queue.push_message(message.clone()) {
+                Ok(id) => return Ok(Some(id)),
+                Err(e) => {
+                    if rejected(queue.client) {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        self.push(&queue.name, message).map(|_| None)
+    }
+
+    // Bytes spooled and not pushed yet.
+    pub fn pending(&self) -> u64 {
+        self.shared.lock().pending()
+    }
+
+    pub fn pending_for(&self, queue: &str) -> u64 {
+        self.shared.lock().spools.get(queue).map_or(0, Spool::pending)
+    }
+
+    // Waits up to `timeout` for everything spooled to be pushed. Returns
+    // whether it was.
+    pub fn drain(&self, timeout: Duration) -> bool {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.lock();
+
+        while state.pending() > 0 {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            state = match self.shared.changed.wait_timeout(state, deadline - now) {
+                Ok((state, _)) => state,
+                Err(e) => e.into_inner().0,
+            };
+        }
+
+        true
+    }
+
+    // Stops the replayer. Messages not pushed yet stay spooled for the next
+    // time the outbox is opened.
+    pub fn close(self) {}
+
+    fn make_room<'s>(&'s self, mut state: MutexGuard<'s, State>, queue: &str, size: u64) -> Result<MutexGuard<'s, State>, String> {
+        let max_bytes = self.shared.options.max_bytes;
+
+        match self.shared.options.full_policy {
+            FullPolicy::Reject => {
+                if state.pending() + size > max_bytes {
+                    return Err("Outbox is full".to_string());
+                }
+            }
+            FullPolicy::DropOldest => {
+                while state.pending() + size > max_bytes {
+                    let needed = state.pending() + size - max_bytes;
+                    let freed = match state.spools.get_mut(queue) {
+                        Some(spool) => spool.drop_oldest(needed)?,
+                        None => 0,
+                    };
+                    if freed == 0 {
+                        return Err("Outbox is full".to_string());
+                    }
+                }
+            }
+            FullPolicy::Block(timeout) => {
+                let deadline = Instant::now() + timeout;
+                while state.pending() + size > max_bytes {
+                    let now = Instant::now();
+                    if state.closed || now >= deadline {
+                        return Err("Outbox is full".to_string());
+                    }
+                    state = match self.shared.changed.wait_timeout(state, deadline - now) {
+                        Ok((state, _)) => state,
+                        Err(e) => e.into_inner().0,
+                    };
+                }
+            }
+        }
+
+        Ok(state)
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.changed.notify_all();
+
+        if let Some(replayer) = self.replayer.take() {
+            let _ = replayer.join();
+        }
+    }
+}
+
+fn replay(shared: &Shared, client: &mut Client) {
+    let mut next = 0;
+    // Queues whose batch IronMQ rejected, with how many messages of it are
+    // left: they are pushed one at a time, to set aside only the ones
+    // rejected.
+    let mut isolated: HashMap<String, usize> = HashMap::new();
+
+    loop {
+        let (queue, generation, end, messages) = {
+            let mut state = shared.lock();
+            loop {
+                if state.closed {
+                    return;
+                }
+                if let Some(batch) = state.next_batch(&mut next, &isolated) {
+                    break batch;
+                }
+                state = shared.changed.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+        };
+
+        let result = client.queue(queue.clone()).push_messages(messages.clone());
+        let rejected = rejected(client);
+
+        let mut state = shared.lock();
+        let e = match result {
+            Ok(_) => {
+                advance(&mut state, &queue, generation, end, None);
+                pushed(&mut isolated, &queue);
+                shared.changed.notify_all();
+                continue;
+            }
+            Err(e) => e,
+        };
+
+        if rejected && messages.len() > 1 {
+            ::log::warn!("Pushing spooled messages to {} failed: {}, pushing them one at a time", queue, e);
+            isolated.insert(queue, messages.len());
+            continue;
+        }
+        if rejected {
+            ::log::warn!("Setting aside a spooled message IronMQ rejected for {}: {}", queue, e);
+            advance(&mut state, &queue, generation, end, Some((&messages[0], &e)));
+            pushed(&mut isolated, &queue);
+            shared.changed.notify_all();
+            continue;
+        }
+
+        // Transport, auth and server errors are retried until they're over.
+        ::log::warn!("Pushing spooled messages to {} failed: {}", queue, e);
+        let deadline = Instant::now() + shared.options.retry_interval;
+        loop {
+            let now = Instant::now();
+            if state.closed || now >= deadline {
+                break;
+            }
+            state = match shared.changed.wait_timeout(state, deadline - now) {
+                Ok((state, _)) => state,
+                Err(e) => e.into_inner().0,
+            };
+        }
+    }
+}
+
+// Whether IronMQ rejected the last push because of what's in it (400 Bad
+// Request, 413 Payload Too Large): pushing it again won't help.
+fn rejected(client: &Client) -> bool {
+    matches!(client.last_status(), Some(400) | Some(413))
+}
+
+// Moves the spool of `queue` past a batch that was pushed, or past a
+// message that was rejected after keeping it aside.
+fn advance(state: &mut State, queue: &str, generation: u64, end: u64, rejected: Option<(&Message, &str)>) {
+    // The spool may have been compacted, or records dropped, in the
+    // meantime.
+    if let Some(spool) = state.spools.get_mut(queue) {
+        if spool.generation == generation {
+            let result = match rejected {
+                Some((message, error)) => spool.reject(message, error),
+                None => Ok(()),
+            };
+            if let Err(e) = result.and_then(|_| spool.advance(end)).and_then(|_| spool.compact()) {
+                ::log::warn!("Saving outbox spool {} failed: {}", queue, e);
+            }
+        }
+    }
+}
+
+fn pushed(isolated: &mut HashMap<String, usize>, queue: &str) {
+    let done = match isolated.get_mut(queue) {
+        Some(left) => {
+            *left -= 1;
+            *left == 0
+        }
+        None => false,
+    };
+    if done {
+        isolated.remove(queue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+    use test_server::{client, closed_port, FakeServer, Response};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("iron-outbox-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    // Accepts every push.
+    fn server() -> FakeServer {
+        FakeServer::start(|request| {
+            let count = request.json()["messages"].as_array().map_or(0, Vec::len);
+            let ids = vec!["\"id\""; count];
+            Response::ok(&format!("{{\"ids\":[{}]}}", ids.join(",")))
+        })
+    }
+
+    // The queue and bodies of each push.
+    fn pushes(server: &FakeServer) -> Vec<(String, Vec<String>)> {
+        server
+            .requests()
+            .iter()
+            .map(|request| {
+                let bodies = request.json()["messages"]
+                    .as_array()
+                    .map(|messages| messages.iter().map(|m| m["body"].as_str().unwrap_or("").to_string()).collect())
+                    .unwrap_or_default();
+                (request.queue().unwrap_or("").to_string(), bodies)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn replays_spooled_messages_in_order() {
+        let dir = test_dir("replay");
+        let server = server();
+        let addr = server.addr();
+
+        let outbox = Outbox::open(&dir, OutboxOptions::new(), move || client(&addr)).unwrap();
+        for body in &["1", "2", "3"] {
+            outbox.push("a", Message::with_body(body)).unwrap();
+        }
+        outbox.push("b.c", Message::with_body("4")).unwrap();
+
+        assert!(outbox.drain(Duration::from_secs(10)));
+        outbox.close();
+
+        let mut pushed: Vec<String> = Vec::new();
+        for (queue, bodies) in pushes(&server) {
+            for body in bodies {
+                pushed.push(format!("{}:{}", queue, body));
+            }
+        }
+        pushed.retain(|p| p.starts_with("a:"));
+        assert_eq!(pushed, vec!["a:1", "a:2", "a:3"]);
+        assert!(dir.join("b%2Ec.1.log").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn sets_aside_rejected_messages() {
+        let dir = test_dir("reject");
+        let server = FakeServer::start(|request| {
+            let messages = request.json()["messages"].as_array().cloned().unwrap_or_default();
+            if messages.iter().any(|m| m["body"] == "poison") {
+                return Response::status("400 Bad Request", r#"{"msg":"Message too big"}"#);
+            }
+            let ids = vec!["\"id\""; messages.len()];
+            Response::ok(&format!("{{\"ids\":[{}]}}", ids.join(",")))
+        });
+        let addr = server.addr();
+        let mut options = OutboxOptions::new();
+        options.retry_interval(Duration::from_millis(20));
+
+        let outbox = Outbox::open(&dir, options, move || client(&addr)).unwrap();
+        for body in &["1", "poison", "2"] {
+            outbox.push("a", Message::with_body(body)).unwrap();
+        }
+
+        assert!(outbox.drain(Duration::from_secs(10)));
+        outbox.close();
+
+        let accepted: Vec<String> = server
+            .requests()
+            .iter()
+            .zip(pushes(&server))
+            .filter(|&(request, _)| !request.json()["messages"].to_string().contains("poison"))
+            .flat_map(|(_, (_, bodies))| bodies)
+            .collect();
+        assert_eq!(accepted, vec!["1", "2"]);
+        let rejected = fs::read_to_string(dir.join("a.rejected")).unwrap();
+        let record: serde_json::Value = serde_json::from_str(rejected.trim()).unwrap();
+        assert_eq!(record["message"]["body"], "poison");
+        assert!(record["error"].as_str().unwrap().contains("Message too big"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn retries_server_errors_until_they_stop() {
+        let dir = test_dir("transient");
+        let mut failures = 0;
+        let server = FakeServer::start(move |request| {
+            failures += 1;
+            match failures {
+                1..=3 => Response::status("401 Unauthorized", r#"{"msg":"Invalid token"}"#),
+                4..=6 => Response::status("502 Bad Gateway", "<html>Bad Gateway</html>"),
+                7 => Response::status("500 Internal Server Error", r#"{"msg":"Internal error"}"#),
+                _ => {
+                    let count = request.json()["messages"].as_array().map_or(0, Vec::len);
+                    Response::ok(&format!("{{\"ids\":[{}]}}", vec!["\"id\""; count].join(",")))
+                }
+            }
+        });
+        let addr = server.addr();
+        let mut options = OutboxOptions::new();
+        options.retry_interval(Duration::from_millis(20));
+
+        let outbox = Outbox::open(&dir, options, move || client(&addr)).unwrap();
+        for body in &["1", "2"] {
+            outbox.push("a", Message::with_body(body)).unwrap();
+        }
+
+        assert!(outbox.drain(Duration::from_secs(10)));
+        outbox.close();
+
+        let pushed: Vec<String> = pushes(&server).into_iter().skip(7).flat_map(|(_, bodies)| bodies).collect();
+        assert_eq!(pushed, vec!["1", "2"]);
+        assert!(!dir.join("a.rejected").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn spools_pushes_failing_with_server_errors() {
+        let dir = test_dir("spool");
+        let server = FakeServer::start(|request| {
+            if request.json()["messages"][0]["body"] == "big" {
+                Response::status("413 Payload Too Large", r#"{"msg":"Message too big"}"#)
+            } else {
+                Response::status("503 Service Unavailable", "<html>Unavailable</html>")
+            }
+        });
+        let mut direct = server.client();
+        let mut queue = direct.queue("a".to_string());
+        let addr = closed_port();
+
+        let outbox = Outbox::open(&dir, OutboxOptions::new(), move || client(&addr)).unwrap();
+        assert!(outbox.push_or_spool(&mut queue, Message::with_body("big")).is_err());
+        assert_eq!(outbox.push_or_spool(&mut queue, Message::with_body("1")), Ok(None));
+        assert!(outbox.pending_for("a") > 0);
+        outbox.close();
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn keeps_messages_across_restarts_within_bounds() {
+        let dir = test_dir("restart");
+        let mut options = OutboxOptions::new();
+        // Room for three `{"body":"n"}` records.
+        options.max_bytes(39).retry_interval(Duration::from_millis(20));
+
+        let addr = closed_port();
+        let outbox = Outbox::open(&dir, options.clone(), move || client(&addr)).unwrap();
+        for body in &["1", "2", "3"] {
+            outbox.push("a", Message::with_body(body)).unwrap();
+        }
+        assert_eq!(outbox.push("a", Message::with_body("4")), Err("Outbox is full".to_string()));
+        outbox.close();
+
+        options.full_policy(FullPolicy::DropOldest);
+        let addr = closed_port();
+        let outbox = Outbox::open(&dir, options.clone(), move || client(&addr)).unwrap();
+        assert_eq!(outbox.pending_for("a"), 39);
+        outbox.push("a", Message::with_body("4")).unwrap();
+        outbox.close();
+
+        let server = server();
+        let addr = server.addr();
+        let outbox = Outbox::open(&dir, options, move || client(&addr)).unwrap();
+        assert!(outbox.drain(Duration::from_secs(10)));
+        outbox.close();
+
+        assert_eq!(
+            pushes(&server),
+            vec![("a".to_string(), vec!["2".to_string(), "3".to_string(), "4".to_string()])]
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,215 @@
+use std::io::{BufRead, Write};
+
+use serde_json;
+
+use super::Queue;
+use super::hold::HeldMessages;
+use super::message::Message;
+use super::queue_info::QueueInfo;
+use Client;
+
+const BATCH_SIZE: u8 = 100;
+const COPY_RESERVATION_TIMEOUT: u32 = 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExportMode {
+    Drain,
+    Copy,
+}
+
+// One line of a backup file. The queue configuration is always the first
+// record, every following line holds a single message.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum BackupRecord {
+    #[serde(rename = "queue")] Queue(QueueInfo),
+    #[serde(rename = "message")] Message(Message),
+}
+
+impl<'a> Queue<'a> {
+    pub fn export<W: Write>(&mut self, mut writer: W, mode: ExportMode) -> Result<usize, String> {
+        let mut info = self.get_info()?;
+        info.size = None;
+        info.total_messages = None;
+        write_record(&mut writer, &BackupRecord::Queue(info))?;
+
+        let timeout = match mode {
+            ExportMode::Drain => super::DEFAULT_TIMEOUT,
+            ExportMode::Copy => COPY_RESERVATION_TIMEOUT,
+        };
+        // Copied messages stay reserved until the whole queue was written.
+        let mut held = HeldMessages::new(timeout);
+        let mut released = 0;
+
+        let written = self.export_messages(&mut writer, mode, timeout, &mut held);
+        let release = held.release(self, &mut released);
+        let count = written?;
+        release?;
+
+        Ok(count)
+    }
+
+    fn export_messages<W: Write>(
+        &mut self,
+        writer: &mut W,
+        mode: ExportMode,
+        timeout: u32,
+        held: &mut HeldMessages,
+    ) -> Result<usize, String> {
+        let mut count = 0;
+
+        loop {
+            held.renew(self)?;
+
+            let messages = self.long_poll(BATCH_SIZE, timeout, 0, false)?;
+            if messages.is_empty() {
+                return Ok(count);
+            }
+            held.extend(messages.clone());
+
+            for message in &messages {
+                let mut record = message.clone();
+                record.reservation_id = None;
+                write_record(writer, &BackupRecord::Message(record))?;
+            }
+            writer.flush().map_err(|e| e.to_string())?;
+            count += messages.len();
+
+            match mode {
+                ExportMode::Drain => {
+                    let msg = self.delete_messages(messages);
+                    if !msg.contains("Deleted") {
+                        return Err(msg);
+                    }
+                    held.clear();
+                }
+                ExportMode::Copy => {}
+            }
+        }
+    }
+}
+
+impl Client {
+    pub fn import<R: BufRead>(&mut self, reader: R) -> Result<usize, String> {
+        self.import_queue(reader, None)
+    }
+
+    pub fn import_as<R: BufRead>(&mut self, reader: R, name: &str) -> Result<usize, String> {
+        self.import_queue(reader, Some(name))
+    }
+
+    fn import_queue<R: BufRead>(&mut self, reader: R, name: Option<&str>) -> Result<usize, String> {
+        let mut lines = reader.lines();
+
+        let mut config = match lines.next() {
+            Some(line) => match read_record(&line.map_err(|e| e.to_string())?)? {
+                BackupRecord::Queue(config) => config,
+                BackupRecord::Message(_) => return Err("Backup has no queue header".to_string()),
+            },
+            None => return Err("Backup is empty".to_string()),
+        };
+
+        if let Some(name) = name {
+            config.name = name.to_string();
+        }
+        config.project_id = None;
+        config.size = None;
+        config.total_messages = None;
+
+        let queue_name = config.name.clone();
+        self.try_create_queue_with_config(&queue_name, &config)?;
+
+        let mut queue = self.queue(queue_name);
+        let mut batch: Vec<Message> = Vec::new();
+        let mut count = 0;
+
+        for line in lines {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            match read_record(&line)? {
+                BackupRecord::Message(message) => batch.push(Message::with_body(&message.body)),
+                BackupRecord::Queue(_) => return Err("Unexpected queue header".to_string()),
+            }
+
+            if batch.len() == BATCH_SIZE as usize {
+                count += batch.len();
+                queue.push_messages(batch)?;
+                batch = Vec::new();
+            }
+        }
+
+        if !batch.is_empty() {
+            count += batch.len();
+            queue.push_messages(batch)?;
+        }
+
+        Ok(count)
+    }
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &BackupRecord) -> Result<(), String> {
+    serde_json::to_writer(&mut *writer, record).map_err(|e| e.to_string())?;
+    writer.write_all(b"\n").map_err(|e| e.to_string())
+}
+
+fn read_record(line: &str) -> Result<BackupRecord, String> {
+    serde_json::from_str(line).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{self, Cursor};
+    use test_server::{FakeServer, Response};
+
+    // Accepts the queue header, then fails every write.
+    struct FailingWriter {
+        lines: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if buf == b"\n" {
+                self.lines += 1;
+            } else if self.lines > 0 {
+                return Err(io::Error::other("disk full"));
+            }
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn releases_copied_messages_when_export_fails() {
+        let server = FakeServer::start(|request| {
+            if request.path.ends_with("/reservations") {
+                Response::ok(r#"{"messages":[{"id":"1","body":"b","reservation_id":"r"}]}"#)
+            } else if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else {
+                Response::ok(r#"{"queue":{"name":"orders"}}"#)
+            }
+        });
+        let mut client = server.client();
+
+        let result = client.queue("orders".to_string()).export(FailingWriter { lines: 0 }, ExportMode::Copy);
+
+        assert_eq!(result.unwrap_err(), "disk full");
+        assert_eq!(server.lines().last().unwrap(), "POST /3/projects/project/queues/orders/messages/1/release");
+    }
+
+    #[test]
+    fn import_fails_when_queue_cannot_be_created() {
+        let server = FakeServer::constant("403 Forbidden", "{\"msg\":\"Forbidden\"}");
+        let mut client = server.client();
+
+        let backup = Cursor::new("{\"queue\":{\"name\":\"orders\"}}\n");
+
+        assert_eq!(client.import(backup).unwrap_err(), "\"Forbidden\"");
+    }
+}
@@ -0,0 +1,64 @@
+use std::time::{Duration, Instant};
+
+use super::Queue;
+use super::message::Message;
+
+// Messages reserved while a queue is walked, kept so that the walk doesn't
+// get them again and that they are put back however it ends. Their
+// reservations are renewed before they could run out.
+pub struct HeldMessages {
+    timeout: u32,
+    messages: Vec<(Message, Instant)>,
+}
+
+impl HeldMessages {
+    pub fn new(timeout: u32) -> HeldMessages {
+        HeldMessages {
+            timeout,
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn extend(&mut self, messages: Vec<Message>) {
+        let now = Instant::now();
+        self.messages.extend(messages.into_iter().map(|m| (m, now)));
+    }
+
+    // Forgets the messages, once they were deleted.
+    pub fn clear(&mut self) {
+        self.messages.clear();
+    }
+
+    // Touches the messages reserved for more than half of the timeout.
+    pub fn renew(&mut self, queue: &mut Queue) -> Result<(), String> {
+        let due = Duration::from_secs(u64::from(self.timeout / 2));
+
+        for &mut (ref mut message, ref mut reserved) in &mut self.messages {
+            if reserved.elapsed() < due {
+                continue;
+            }
+            let reservation_id = queue.touch_message_with_timeout(message.clone(), self.timeout)?;
+            message.reservation_id = Some(reservation_id);
+            *reserved = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    // Releases every message, going on past failures and returning the
+    // first one.
+    pub fn release(&mut self, queue: &mut Queue, released: &mut usize) -> Result<(), String> {
+        let mut result = Ok(());
+
+        for (message, _) in self.messages.drain(..) {
+            let msg = queue.release_message(message, 0);
+            if msg.contains("Released") {
+                *released += 1;
+            } else if result.is_ok() {
+                result = Err(msg);
+            }
+        }
+
+        result
+    }
+}
@@ -0,0 +1,338 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use super::Queue;
+use super::hold::HeldMessages;
+use super::message::Message;
+
+const DEFAULT_BATCH_SIZE: u8 = 100;
+const DEFAULT_RESERVATION_TIMEOUT: u32 = 3600;
+
+type ProgressCallback = Box<dyn FnMut(&MigrationProgress)>;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct MigrationProgress {
+    pub reserved: usize,
+    pub pushed: usize,
+    pub skipped: usize,
+    pub deleted: usize,
+    pub released: usize,
+}
+
+pub struct MigrationOptions {
+    batch_size: u8,
+    timeout: u32,
+    checkpoint: Option<PathBuf>,
+    progress: Option<ProgressCallback>,
+}
+
+impl MigrationOptions {
+    pub fn new() -> MigrationOptions {
+        MigrationOptions {
+            batch_size: DEFAULT_BATCH_SIZE,
+            timeout: DEFAULT_RESERVATION_TIMEOUT,
+            checkpoint: None,
+            progress: None,
+        }
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut MigrationOptions {
+        self.batch_size = batch_size;
+
+        self
+    }
+
+    pub fn timeout(&mut self, timeout: u32) -> &mut MigrationOptions {
+        self.timeout = timeout;
+
+        self
+    }
+
+    pub fn checkpoint<P: AsRef<Path>>(&mut self, path: P) -> &mut MigrationOptions {
+        self.checkpoint = Some(path.as_ref().to_path_buf());
+
+        self
+    }
+
+    pub fn on_progress<F>(&mut self, progress: F) -> &mut MigrationOptions
+    where
+        F: FnMut(&MigrationProgress) + 'static,
+    {
+        self.progress = Some(Box::new(progress));
+
+        self
+    }
+}
+
+impl Default for MigrationOptions {
+    fn default() -> MigrationOptions {
+        MigrationOptions::new()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    Move,
+    Copy,
+}
+
+// Source message ids that were already pushed to the destination. Lets an
+// interrupted migration be restarted without pushing duplicates.
+struct Checkpoint {
+    path: Option<PathBuf>,
+    file: Option<File>,
+    ids: HashSet<String>,
+}
+
+impl Checkpoint {
+    fn open(path: Option<PathBuf>) -> Result<Checkpoint, String> {
+        let mut ids = HashSet::new();
+
+        let file = match path {
+            Some(ref path) => {
+                if path.exists() {
+                    let reader = File::open(path).map_err(|e| e.to_string())?;
+                    for line in BufReader::new(reader).lines() {
+                        let line = line.map_err(|e| e.to_string())?;
+                        if !line.is_empty() {
+                            ids.insert(line);
+                        }
+                    }
+                }
+
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| e.to_string())?;
+                Some(file)
+            }
+            None => None,
+        };
+
+        Ok(Checkpoint { path, file, ids })
+    }
+
+    fn contains(&self, message: &Message) -> bool {
+        match message.id {
+            Some(ref id) => self.ids.contains(id),
+            None => false,
+        }
+    }
+
+    fn record(&mut self, messages: &[Message]) -> Result<(), String> {
+        let ids: Vec<String> = messages.iter().filter_map(|m| m.id.clone()).collect();
+
+        if let Some(ref mut file) = self.file {
+            for id in &ids {
+                writeln!(file, "{}", id).map_err(|e| e.to_string())?;
+            }
+            file.sync_data().map_err(|e| e.to_string())?;
+        }
+        self.ids.extend(ids);
+
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), String> {
+        drop(self.file);
+        match self.path {
+            Some(path) => fs::remove_file(path).map_err(|e| e.to_string()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a> Queue<'a> {
+    pub fn move_to(&mut self, dest: &mut Queue) -> Result<MigrationProgress, String> {
+        self.move_to_with_options(dest, &mut MigrationOptions::new())
+    }
+
+    pub fn move_to_with_options(
+        &mut self,
+        dest: &mut Queue,
+        options: &mut MigrationOptions,
+    ) -> Result<MigrationProgress, String> {
+        self.migrate(dest, options, Mode::Move)
+    }
+
+    pub fn copy_to(&mut self, dest: &mut Queue) -> Result<MigrationProgress, String> {
+        self.copy_to_with_options(dest, &mut MigrationOptions::new())
+    }
+
+    pub fn copy_to_with_options(
+        &mut self,
+        dest: &mut Queue,
+        options: &mut MigrationOptions,
+    ) -> Result<MigrationProgress, String> {
+        self.migrate(dest, options, Mode::Copy)
+    }
+
+    fn migrate(
+        &mut self,
+        dest: &mut Queue,
+        options: &mut MigrationOptions,
+        mode: Mode,
+    ) -> Result<MigrationProgress, String> {
+        let mut checkpoint = Checkpoint::open(options.checkpoint.clone())?;
+        let mut progress = MigrationProgress::default();
+        // Copied messages stay reserved until the whole queue was walked,
+        // otherwise they would be handed out again by the next reservation.
+        // Messages not yet deleted are released too if the walk fails.
+        let mut held = HeldMessages::new(options.timeout);
+
+        let walked = self.walk(dest, options, mode, &mut checkpoint, &mut progress, &mut held);
+        let released = held.release(self, &mut progress.released);
+        walked?;
+        released?;
+
+        if mode == Mode::Copy {
+            if let Some(ref mut report) = options.progress {
+                report(&progress);
+            }
+        }
+
+        // Messages reserved by someone else, or still delayed, weren't seen.
+        // The checkpoint is kept so that a later run only picks those up.
+        let expected = match mode {
+            Mode::Move => 0,
+            Mode::Copy => progress.released,
+        };
+        let size = self.get_info()?.size.unwrap_or(0);
+        if size > expected {
+            return Err(format!(
+                "{} messages of queue {} were reserved or delayed, run again once they are available",
+                size - expected,
+                self.name
+            ));
+        }
+
+        checkpoint.finish()?;
+
+        Ok(progress)
+    }
+
+    fn walk(
+        &mut self,
+        dest: &mut Queue,
+        options: &mut MigrationOptions,
+        mode: Mode,
+        checkpoint: &mut Checkpoint,
+        progress: &mut MigrationProgress,
+        held: &mut HeldMessages,
+    ) -> Result<(), String> {
+        loop {
+            held.renew(self)?;
+
+            let messages = self.long_poll(options.batch_size, options.timeout, 0, false)?;
+            if messages.is_empty() {
+                return Ok(());
+            }
+            progress.reserved += messages.len();
+            held.extend(messages.clone());
+
+            let pending: Vec<Message> = messages
+                .iter()
+                .filter(|m| !checkpoint.contains(m))
+                .cloned()
+                .collect();
+            progress.skipped += messages.len() - pending.len();
+
+            if !pending.is_empty() {
+                let copies = pending.iter().map(|m| Message::with_body(&m.body)).collect();
+
+                dest.push_messages(copies)?;
+                checkpoint.record(&pending)?;
+                progress.pushed += pending.len();
+            }
+
+            match mode {
+                Mode::Move => {
+                    let count = messages.len();
+                    let msg = self.delete_messages(messages);
+                    if !msg.contains("Deleted") {
+                        return Err(msg);
+                    }
+                    held.clear();
+                    progress.deleted += count;
+                }
+                Mode::Copy => {}
+            }
+
+            if let Some(ref mut report) = options.progress {
+                report(progress);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use test_server::{FakeServer, Response};
+
+    // "src" hands out one message per reservation, `batches` times, and
+    // holds `size` messages. Pushes to "dst" fail after the first.
+    fn server(batches: usize, size: usize) -> FakeServer {
+        let (mut reserved, mut pushed) = (0, 0);
+
+        FakeServer::start(move |request| {
+            if request.path.ends_with("/reservations") {
+                reserved += 1;
+                if reserved > batches {
+                    return Response::ok("{\"messages\":[]}");
+                }
+                let message = json!({ "id": reserved.to_string(), "body": "b", "reservation_id": "r" });
+                Response::ok(&json!({ "messages": [message] }).to_string())
+            } else if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else if request.queue() == Some("dst") {
+                pushed += 1;
+                if pushed > 1 {
+                    return Response::status("500 Internal Server Error", "{\"msg\":\"Push failed\"}");
+                }
+                Response::ok("{\"ids\":[\"c\"]}")
+            } else {
+                Response::ok(&json!({ "queue": { "name": "src", "size": size } }).to_string())
+            }
+        })
+    }
+
+    #[test]
+    fn releases_held_messages_when_copy_fails() {
+        let server = server(2, 2);
+        let (mut source, mut dest) = (server.client(), server.client());
+
+        let result = source.queue("src".to_string()).copy_to(&mut dest.queue("dst".to_string()));
+
+        assert_eq!(result.unwrap_err(), "\"Push failed\"");
+        let releases: Vec<String> = server.lines().into_iter().filter(|line| line.ends_with("/release")).collect();
+        assert_eq!(
+            releases,
+            vec![
+                "POST /3/projects/project/queues/src/messages/1/release",
+                "POST /3/projects/project/queues/src/messages/2/release",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_checkpoint_while_messages_are_unseen() {
+        let path = env::temp_dir().join(format!("ironmq-migration-{}.checkpoint", std::process::id()));
+        let server = server(1, 3);
+        let (mut source, mut dest) = (server.client(), server.client());
+
+        let mut options = MigrationOptions::new();
+        options.checkpoint(&path);
+        let result = source
+            .queue("src".to_string())
+            .copy_to_with_options(&mut dest.queue("dst".to_string()), &mut options);
+
+        assert!(result.unwrap_err().contains("2 messages of queue src were reserved or delayed"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
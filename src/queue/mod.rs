@@ -1,10 +1,21 @@
 pub mod queue_info;
 pub mod message;
+pub mod backup;
+mod hold;
+pub mod migration;
+pub mod ordered;
+pub mod producer;
+pub mod rpc;
+pub mod stream;
+pub mod tail;
+pub mod topic;
 
 use serde_json::Value;
+use std::time::{Duration, Instant};
 
 use super::*;
 use message::{Message, ReservationConfig};
+use metrics::{ErrorKind, Operation};
 
 const DEFAULT_TIMEOUT: u32 = 60;
 const DEFAULT_COUNT: u8 = 1;
@@ -15,17 +26,33 @@ pub struct Queue<'a> {
 }
 
 impl<'a> Queue<'a> {
+    fn observe(&self, operation: Operation, started: Instant, result: Result<usize, &String>) {
+        let (messages, error) = match result {
+            Ok(messages) => (messages, None),
+            Err(msg) => (0, Some(ErrorKind::classify(msg))),
+        };
+
+        self.client.metrics.record(&self.name, operation, started.elapsed(), messages, error);
+    }
+
     pub fn info(&mut self) -> QueueInfo {
+        self.get_info().unwrap()
+    }
+
+    pub fn get_info(&mut self) -> Result<QueueInfo, String> {
         let path = format!("{}queues/{}", self.client.base_path, self.name);
 
         let res = self.client
             .http_client
             .request(Method::Get, path, String::new());
 
-        let v: Value = serde_json::from_slice(&res).unwrap();
-        let queue_info: QueueInfo = serde_json::from_value(v["queue"].clone()).unwrap();
+        let v: Value = serde_json::from_slice(&res).map_err(|e| format!("Json parsing error: {}", e))?;
+        let queue_info: QueueInfo = match serde_json::from_value(v["queue"].clone()) {
+            Ok(queue_info) => queue_info,
+            Err(_) => return Err(v["msg"].to_string()),
+        };
 
-        queue_info
+        Ok(queue_info)
     }
 
     pub fn push_message(&mut self, message: Message) -> Result<String, String> {
@@ -39,6 +66,7 @@ impl<'a> Queue<'a> {
     }
 
     pub fn push_messages(&mut self, messages: Vec<Message>) -> Result<Vec<String>, String> {
+        let started = Instant::now();
         let path = format!("{}queues/{}/messages", self.client.base_path, self.name);
 
         let message = json!({ "messages": messages });
@@ -47,13 +75,14 @@ impl<'a> Queue<'a> {
             .http_client
             .request(Method::Post, path, message.to_string());
 
-        let v: Value = serde_json::from_slice(&res).unwrap();
-        let ids: Vec<String> = match serde_json::from_value(v["ids"].clone()) {
-            Ok(ids) => ids,
-            Err(_) => return Err(v["msg"].to_string()),
+        // Proxies in front of IronMQ may answer with a page instead of JSON.
+        let ids: Result<Vec<String>, String> = match serde_json::from_slice::<Value>(&res) {
+            Ok(v) => serde_json::from_value(v["ids"].clone()).map_err(|_| v["msg"].to_string()),
+            Err(e) => Err(format!("Json parsing error: {}", e)),
         };
+        self.observe(Operation::Push, started, ids.as_ref().map(Vec::len));
 
-        Ok(ids)
+        ids
     }
 
     pub fn push_string(&mut self, body: &str) -> Result<String, String> {
@@ -89,6 +118,7 @@ impl<'a> Queue<'a> {
     }
 
     pub fn long_poll(&mut self, count: u8, timeout: u32, wait: u32, delete: bool) -> Result<Vec<Message>, String> {
+        let started = Instant::now();
         let path = format!("{}queues/{}/reservations", self.client.base_path, self.name);
 
         let reservation_config = json!(ReservationConfig::new(count, timeout, wait, delete));
@@ -96,15 +126,14 @@ impl<'a> Queue<'a> {
         let res =
             self.client
                 .http_client
-                .request(Method::Post, path, reservation_config.to_string());
+                .request_with_wait(Method::Post, path, reservation_config.to_string(), Duration::from_secs(wait as u64));
 
         let v: Value = serde_json::from_slice(&res).unwrap();
-        let messages: Vec<Message> = match serde_json::from_value(v["messages"].clone()) {
-            Ok(messages) => messages,
-            Err(_) => return Err(v["msg"].to_string()),
-        };
+        let messages: Result<Vec<Message>, String> =
+            serde_json::from_value(v["messages"].clone()).map_err(|_| v["msg"].to_string());
+        self.observe(Operation::Reserve, started, messages.as_ref().map(Vec::len));
 
-        Ok(messages)
+        messages
     }
 
     pub fn reserve_messages_with_timeout(
@@ -150,6 +179,7 @@ impl<'a> Queue<'a> {
     }
 
     pub fn release_message(&mut self, message: Message, delay: u32) -> String {
+        let started = Instant::now();
         let path = format!(
             "{}queues/{}/messages/{}/release",
             self.client.base_path,
@@ -169,11 +199,13 @@ impl<'a> Queue<'a> {
 
         let v: Value = serde_json::from_slice(&res).unwrap();
         let msg = v["msg"].to_string();
+        self.observe(Operation::Release, started, if msg.contains("Released") { Ok(1) } else { Err(&msg) });
 
         msg
     }
 
     pub fn delete_message(&mut self, message: Message) -> String {
+        let started = Instant::now();
         let message_id = message.id.expect("Missed message id");
         let path = format!(
             "{}queues/{}/messages/{}",
@@ -189,11 +221,14 @@ impl<'a> Queue<'a> {
 
         let v: Value = serde_json::from_slice(&res).unwrap();
         let msg = v["msg"].to_string();
+        self.observe(Operation::Delete, started, if msg.contains("Deleted") { Ok(1) } else { Err(&msg) });
 
         msg
     }
 
     pub fn delete_messages(&mut self, messages: Vec<Message>) -> String {
+        let started = Instant::now();
+        let count = messages.len();
         let path = format!("{}queues/{}/messages", self.client.base_path, self.name)
             .parse()
             .unwrap();
@@ -216,11 +251,13 @@ impl<'a> Queue<'a> {
 
         let v: Value = serde_json::from_slice(&res).unwrap();
         let msg = v["msg"].to_string();
+        self.observe(Operation::Delete, started, if msg.contains("Deleted") { Ok(count) } else { Err(&msg) });
 
         msg
     }
 
     pub fn touch_message_with_timeout(&mut self, message: Message, timeout: u32) -> Result<String, String> {
+        let started = Instant::now();
         let message_id = message.id.expect("Missed message id");
         let path = format!(
             "{}queues/{}/messages/{}/touch",
@@ -238,12 +275,11 @@ impl<'a> Queue<'a> {
             .request(Method::Post, path, body.to_string());
 
         let v: Value = serde_json::from_slice(&res).unwrap();
-        let new_reservation_id: String = match serde_json::from_value(v["reservation_id"].clone()) {
-            Ok(reservation_id) => reservation_id,
-            Err(_) => return Err(v["msg"].to_string()),
-        };
+        let new_reservation_id: Result<String, String> =
+            serde_json::from_value(v["reservation_id"].clone()).map_err(|_| v["msg"].to_string());
+        self.observe(Operation::Touch, started, new_reservation_id.as_ref().map(|_| 1));
 
-        Ok(new_reservation_id)
+        new_reservation_id
     }
 
     pub fn touch_message(&mut self, message: Message) -> Result<String, String> {
@@ -351,7 +387,7 @@ impl<'a> Queue<'a> {
             .http_client
             .request(Method::Patch, path, body.to_string());
 
-        let v: Value = serde_json::from_slice(&res).unwrap();
+        let v: Value = serde_json::from_slice(&res).map_err(|e| format!("Json parsing error: {}", e))?;
 
         let queue_info: QueueInfo = match serde_json::from_value(v["queue"].clone()) {
             Ok(queue_info) => queue_info,
@@ -377,10 +413,22 @@ impl<'a> Queue<'a> {
     }
 
     pub fn delete(&mut self) {
+        let _ = self.try_delete();
+    }
+
+    pub fn try_delete(&mut self) -> Result<(), String> {
         let path = format!("{}queues/{}", self.client.base_path, self.name);
 
-        let _res = self.client
+        let res = self.client
             .http_client
             .request(Method::Delete, path, String::new());
+
+        let v: Value = serde_json::from_slice(&res).map_err(|e| format!("Json parsing error: {}", e))?;
+        let msg = v["msg"].to_string();
+        if !msg.contains("Deleted") {
+            return Err(msg);
+        }
+
+        Ok(())
     }
 }
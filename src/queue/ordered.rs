@@ -0,0 +1,689 @@
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use super::message::Message;
+use metrics::SequenceAnomaly;
+use Client;
+
+const DEFAULT_BATCH_SIZE: u8 = 10;
+const DEFAULT_WAIT: u32 = 10;
+const DEFAULT_WINDOW: u64 = 100;
+const DEFAULT_EARLY_DELAY: u32 = 5;
+const DEFAULT_GAP_TIMEOUT: u64 = 30;
+const DEFAULT_IDLE_TIMEOUT: u64 = 3600;
+// Held messages are released this long, in seconds, before their
+// reservation could run out during the next reserve.
+const HOLD_MARGIN: u32 = 10;
+
+// Body of the messages of ordered queues. Sequence numbers start at 1 and
+// increase by one per message of a key.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OrderedEnvelope {
+    pub key: String,
+    pub seq: u64,
+    pub body: String,
+}
+
+// Pushes messages stamped with their key and the next sequence number of
+// the key. Sequence numbers are kept in memory: there must be a single
+// producer per key, and one that restarts has to `resume` its keys.
+pub struct OrderedProducer<'a> {
+    client: &'a mut Client,
+    queue: String,
+    sequences: HashMap<String, u64>,
+}
+
+impl<'a> OrderedProducer<'a> {
+    pub fn new(client: &'a mut Client, queue: &str) -> OrderedProducer<'a> {
+        OrderedProducer {
+            client,
+            queue: queue.to_string(),
+            sequences: HashMap::new(),
+        }
+    }
+
+    // Continues the sequence of `key` after `last`.
+    pub fn resume(&mut self, key: &str, last: u64) -> &mut OrderedProducer<'a> {
+        self.sequences.insert(key.to_string(), last);
+
+        self
+    }
+
+    // The last sequence number pushed for `key`.
+    pub fn last_sequence(&self, key: &str) -> Option<u64> {
+        self.sequences.get(key).cloned()
+    }
+
+    // A failed push doesn't use up its sequence number. If it did reach
+    // IronMQ after all, the consumer drops the retry as a duplicate.
+    pub fn push(&mut self, key: &str, body: &str) -> Result<String, String> {
+        let seq = self.sequences.get(key).map_or(1, |last| last + 1);
+        let envelope = OrderedEnvelope {
+            key: key.to_string(),
+            seq,
+            body: body.to_string(),
+        };
+        let envelope = serde_json::to_string(&envelope).map_err(|e| e.to_string())?;
+
+        let id = self.client.queue(self.queue.clone()).push_string(&envelope)?;
+        self.sequences.insert(key.to_string(), seq);
+
+        Ok(id)
+    }
+}
+
+struct Held {
+    envelope: OrderedEnvelope,
+    message: Message,
+    since: Instant,
+}
+
+struct KeyState {
+    next: u64,
+    // `next` is the lowest sequence number seen so far: none of the key was
+    // handled or `expect`ed yet.
+    guessed: bool,
+    held: BTreeMap<u64, Held>,
+    // When we started waiting for `next` with later messages around.
+    waiting: Option<Instant>,
+    // Sequence numbers released after the handler failed with them, and
+    // when it first did.
+    failed: BTreeMap<u64, Instant>,
+    // When a message of the key last arrived.
+    active: Instant,
+}
+
+impl KeyState {
+    fn new(next: u64) -> KeyState {
+        KeyState {
+            next,
+            guessed: false,
+            held: BTreeMap::new(),
+            waiting: None,
+            failed: BTreeMap::new(),
+            active: Instant::now(),
+        }
+    }
+
+    fn idle(&self, idle_timeout: Duration) -> bool {
+        self.held.is_empty() && self.waiting.is_none() && self.failed.is_empty() && self.active.elapsed() >= idle_timeout
+    }
+
+    // Moves on to the next sequence number after `next` was done with.
+    fn advance(&mut self) {
+        self.failed.remove(&self.next);
+        self.next += 1;
+        self.guessed = false;
+        self.waiting = if self.held.is_empty() { None } else { Some(Instant::now()) };
+    }
+
+    fn guess(seq: u64) -> KeyState {
+        KeyState {
+            guessed: true,
+            waiting: Some(Instant::now()),
+            ..KeyState::new(seq)
+        }
+    }
+}
+
+// Hands the messages of an ordered queue to the handler in sequence order
+// per key. Messages that arrive early are held, still reserved, until
+// their predecessors are handled; those `window` or more ahead are
+// released to come back after `early_delay`. A sequence number missing for
+// `gap_timeout` while later ones are around is skipped as a gap, and
+// numbers already handled are deleted as duplicates. Both, and early
+// messages, are reported to the client's metrics. A message the handler
+// failed with is never skipped: it's retried, holding up its key, unless a
+// `dead_letter_queue` is set.
+//
+// A key starts at the lowest sequence number seen within `gap_timeout` of
+// its first message, or right away at 1, unless told otherwise with
+// `expect`. Which numbers were handled is kept in memory, so a key needs a
+// single consumer. Keys without messages for `idle_timeout` are forgotten
+// and start over like new ones.
+pub struct OrderedConsumer<'a> {
+    client: &'a mut Client,
+    queue: String,
+    batch_size: u8,
+    reservation_timeout: u32,
+    wait: u32,
+    window: u64,
+    early_delay: u32,
+    gap_timeout: Duration,
+    idle_timeout: Duration,
+    dead_letter_queue: Option<String>,
+    keys: HashMap<String, KeyState>,
+}
+
+impl<'a> OrderedConsumer<'a> {
+    pub fn new(client: &'a mut Client, queue: &str) -> OrderedConsumer<'a> {
+        OrderedConsumer {
+            client,
+            queue: queue.to_string(),
+            batch_size: DEFAULT_BATCH_SIZE,
+            reservation_timeout: super::DEFAULT_TIMEOUT,
+            wait: DEFAULT_WAIT,
+            window: DEFAULT_WINDOW,
+            early_delay: DEFAULT_EARLY_DELAY,
+            gap_timeout: Duration::from_secs(DEFAULT_GAP_TIMEOUT),
+            idle_timeout: Duration::from_secs(DEFAULT_IDLE_TIMEOUT),
+            dead_letter_queue: None,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut OrderedConsumer<'a> {
+        self.batch_size = batch_size.max(1);
+
+        self
+    }
+
+    pub fn reservation_timeout(&mut self, reservation_timeout: u32) -> &mut OrderedConsumer<'a> {
+        self.reservation_timeout = reservation_timeout;
+
+        self
+    }
+
+    pub fn wait(&mut self, wait: u32) -> &mut OrderedConsumer<'a> {
+        self.wait = wait;
+
+        self
+    }
+
+    pub fn window(&mut self, window: u64) -> &mut OrderedConsumer<'a> {
+        self.window = window.max(1);
+
+        self
+    }
+
+    pub fn early_delay(&mut self, early_delay: u32) -> &mut OrderedConsumer<'a> {
+        self.early_delay = early_delay;
+
+        self
+    }
+
+    pub fn gap_timeout(&mut self, gap_timeout: Duration) -> &mut OrderedConsumer<'a> {
+        self.gap_timeout = gap_timeout;
+
+        self
+    }
+
+    // How long a key without held or failed messages is remembered after
+    // its last message. A duplicate older than that is handled again.
+    pub fn idle_timeout(&mut self, idle_timeout: Duration) -> &mut OrderedConsumer<'a> {
+        self.idle_timeout = idle_timeout;
+
+        self
+    }
+
+    // Messages the handler keeps failing with for longer than `gap_timeout`
+    // are pushed to this queue, as they are, and deleted so their key can
+    // move on.
+    pub fn dead_letter_queue(&mut self, dead_letter_queue: &str) -> &mut OrderedConsumer<'a> {
+        self.dead_letter_queue = Some(dead_letter_queue.to_string());
+
+        self
+    }
+
+    // Sets the next sequence number to handle for `key`, e.g. to resume
+    // from where a previous consumer stopped.
+    pub fn expect(&mut self, key: &str, next: u64) -> &mut OrderedConsumer<'a> {
+        let state = self.keys.entry(key.to_string()).or_insert_with(|| KeyState::new(next));
+        state.next = next;
+        state.guessed = false;
+
+        self
+    }
+
+    // The next sequence number to handle for `key`.
+    pub fn next_sequence(&self, key: &str) -> u64 {
+        self.keys.get(key).map_or(1, |state| state.next)
+    }
+
+    // Handles batches until reserving from the queue fails.
+    pub fn consume<F>(&mut self, mut handler: F) -> Result<(), String>
+    where
+        F: FnMut(&OrderedEnvelope) -> Result<(), String>,
+    {
+        loop {
+            self.consume_once(&mut handler)?;
+        }
+    }
+
+    // Reserves one batch and handles every message whose turn it is,
+    // returning how many were handled. A message the handler fails with
+    // is released to be retried after `early_delay`, or dead-lettered.
+    pub fn consume_once<F>(&mut self, handler: &mut F) -> Result<usize, String>
+    where
+        F: FnMut(&OrderedEnvelope) -> Result<(), String>,
+    {
+        self.release_stale();
+        let idle_timeout = self.idle_timeout;
+        self.keys.retain(|_, state| !state.idle(idle_timeout));
+
+        let messages = self
+            .client
+            .queue(self.queue.clone())
+            .long_poll(self.batch_size, self.reservation_timeout, self.wait, false)?;
+
+        let mut touched = Vec::new();
+        for message in messages {
+            if let Some(key) = self.arrive(message) {
+                if !touched.contains(&key) {
+                    touched.push(key);
+                }
+            }
+        }
+
+        let mut handled = 0;
+        for key in touched {
+            handled += self.deliver(&key, handler);
+        }
+
+        Ok(handled)
+    }
+
+    // Releases every held message, so they can be reserved again right
+    // away. Also done when the consumer is dropped.
+    pub fn release_held(&mut self) {
+        let held: Vec<Held> = self
+            .keys
+            .values_mut()
+            .flat_map(|state| ::std::mem::take(&mut state.held).into_values())
+            .collect();
+
+        for held in held {
+            self.release(held.message, 0);
+        }
+    }
+
+    // Sorts a reserved message into its key, returning the key if the
+    // message is held.
+    fn arrive(&mut self, message: Message) -> Option<String> {
+        let envelope: OrderedEnvelope = match serde_json::from_str(&message.body) {
+            Ok(envelope) => envelope,
+            Err(_) => {
+                ::log::warn!("Deleting malformed ordered message {:?} from {}", message.id, self.queue);
+                self.delete(message);
+                return None;
+            }
+        };
+
+        let window = self.window;
+        let state = self.keys.entry(envelope.key.clone()).or_insert_with(|| KeyState::guess(envelope.seq));
+        state.active = Instant::now();
+
+        if envelope.seq < state.next && state.guessed {
+            let early = state.held.len() as u64;
+            state.next = envelope.seq;
+            if early > 0 {
+                self.anomaly(SequenceAnomaly::Early, early);
+            }
+        }
+
+        let state = self.keys.get_mut(&envelope.key).unwrap();
+        if envelope.seq < state.next || state.held.contains_key(&envelope.seq) {
+            self.anomaly(SequenceAnomaly::Duplicate, 1);
+            self.delete(message);
+            return None;
+        }
+
+        if envelope.seq > state.next {
+            if state.waiting.is_none() {
+                state.waiting = Some(Instant::now());
+            }
+            let too_early = envelope.seq - state.next >= window;
+
+            self.anomaly(SequenceAnomaly::Early, 1);
+            if too_early {
+                let delay = self.early_delay;
+                self.release(message, delay);
+                return None;
+            }
+        }
+
+        let key = envelope.key.clone();
+        let state = self.keys.get_mut(&key).unwrap();
+        state.held.insert(
+            envelope.seq,
+            Held {
+                envelope,
+                message,
+                since: Instant::now(),
+            },
+        );
+
+        Some(key)
+    }
+
+    // Hands the held messages of `key` to the handler for as long as it's
+    // their turn, skipping a gap that has been waited for long enough
+    // unless the handler failed with the message missing.
+    fn deliver<F>(&mut self, key: &str, handler: &mut F) -> usize
+    where
+        F: FnMut(&OrderedEnvelope) -> Result<(), String>,
+    {
+        let mut handled = 0;
+
+        loop {
+            let gap_timeout = self.gap_timeout;
+            let (held, missing) = {
+                let state = match self.keys.get_mut(key) {
+                    Some(state) => state,
+                    None => return handled,
+                };
+
+                // A guessed start may still turn out to be too late.
+                if state.guessed && state.next > 1 && state.waiting.is_some_and(|since| since.elapsed() < gap_timeout) {
+                    return handled;
+                }
+
+                match state.held.remove(&state.next) {
+                    Some(held) => (held, 0),
+                    None => {
+                        let first = match state.held.keys().next() {
+                            Some(&first) => first,
+                            None => return handled,
+                        };
+                        if state.failed.contains_key(&state.next) || state.waiting.is_none_or(|since| since.elapsed() < gap_timeout) {
+                            return handled;
+                        }
+
+                        let missing = first - state.next;
+                        state.next = first;
+                        (state.held.remove(&first).unwrap(), missing)
+                    }
+                }
+            };
+
+            if missing > 0 {
+                ::log::warn!("Skipping {} missing messages of {} in {}", missing, key, self.queue);
+                self.anomaly(SequenceAnomaly::Gap, missing);
+            }
+
+            let result = handler(&held.envelope);
+            let state = self.keys.get_mut(key).unwrap();
+
+            let e = match result {
+                Ok(()) => {
+                    state.advance();
+                    handled += 1;
+                    self.delete(held.message);
+                    continue;
+                }
+                Err(e) => e,
+            };
+
+            ::log::warn!("Handling message {:?} of {} failed: {}", held.message.id, key, e);
+            let failing = state.failed.entry(held.envelope.seq).or_insert_with(Instant::now).elapsed();
+            if state.waiting.is_none() {
+                state.waiting = Some(Instant::now());
+            }
+
+            if failing >= gap_timeout {
+                if let Some(dead) = self.dead_letter_queue.clone() {
+                    if self.dead_letter(&dead, &held.message) {
+                        self.keys.get_mut(key).unwrap().advance();
+                        self.delete(held.message);
+                        continue;
+                    }
+                }
+            }
+
+            let delay = self.early_delay;
+            self.release(held.message, delay);
+            return handled;
+        }
+    }
+
+    // Pushes a message the handler keeps failing with to `dead`, returning
+    // whether it was.
+    fn dead_letter(&mut self, dead: &str, message: &Message) -> bool {
+        match self.client.queue(dead.to_string()).push_string(&message.body) {
+            Ok(_) => {
+                ::log::warn!("Moved message {:?} from {} to {}", message.id, self.queue, dead);
+                true
+            }
+            Err(e) => {
+                ::log::warn!("Pushing message {:?} from {} to {} failed: {}", message.id, self.queue, dead, e);
+                false
+            }
+        }
+    }
+
+    // Releases held messages whose reservation could run out before the
+    // next batch is handled.
+    fn release_stale(&mut self) {
+        let limit = Duration::from_secs(u64::from(self.reservation_timeout.saturating_sub(self.wait + HOLD_MARGIN)));
+        let mut stale = Vec::new();
+
+        for state in self.keys.values_mut() {
+            let seqs: Vec<u64> = state.held.iter().filter(|&(_, held)| held.since.elapsed() >= limit).map(|(&seq, _)| seq).collect();
+            for seq in seqs {
+                stale.push(state.held.remove(&seq).unwrap().message);
+            }
+        }
+
+        let delay = self.early_delay;
+        for message in stale {
+            self.release(message, delay);
+        }
+    }
+
+    fn delete(&mut self, message: Message) {
+        let id = message.id.clone();
+        let msg = self.client.queue(self.queue.clone()).delete_message(message);
+        if !msg.contains("Deleted") {
+            ::log::warn!("Deleting message {:?} from {} failed: {}", id, self.queue, msg);
+        }
+    }
+
+    fn release(&mut self, message: Message, delay: u32) {
+        let id = message.id.clone();
+        let msg = self.client.queue(self.queue.clone()).release_message(message, delay);
+        if !msg.contains("Released") {
+            ::log::warn!("Releasing message {:?} to {} failed: {}", id, self.queue, msg);
+        }
+    }
+
+    fn anomaly(&self, anomaly: SequenceAnomaly, count: u64) {
+        self.client.metrics.record_sequence(&self.queue, anomaly, count);
+    }
+}
+
+impl<'a> Drop for OrderedConsumer<'a> {
+    fn drop(&mut self) {
+        self.release_held();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics::{ErrorKind, Metrics, Operation};
+    use std::sync::{Arc, Mutex};
+    use test_server::{FakeServer, Response};
+
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<(SequenceAnomaly, u64)>>);
+
+    impl Metrics for Recorder {
+        fn record(&self, _queue: &str, _operation: Operation, _latency: Duration, _messages: usize, _error: Option<ErrorKind>) {}
+
+        fn record_sequence(&self, _queue: &str, anomaly: SequenceAnomaly, count: u64) {
+            self.0.lock().unwrap().push((anomaly, count));
+        }
+    }
+
+    fn reserved(id: usize, key: &str, seq: u64) -> String {
+        let envelope = OrderedEnvelope {
+            key: key.to_string(),
+            seq,
+            body: format!("{}{}", key, seq),
+        };
+        let body = serde_json::to_string(&envelope).unwrap();
+
+        json!({ "id": id.to_string(), "body": body, "reservation_id": "r" }).to_string()
+    }
+
+    // Hands out `batches` one reservation at a time.
+    fn server(batches: Vec<Vec<String>>) -> FakeServer {
+        let mut batches = batches.into_iter();
+        FakeServer::start(move |request| {
+            if request.path.ends_with("/reservations") {
+                Response::ok(&format!("{{\"messages\":[{}]}}", batches.next().unwrap_or_default().join(",")))
+            } else if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else if request.method == "POST" {
+                Response::ok("{\"ids\":[\"100\"],\"msg\":\"Messages put on queue.\"}")
+            } else {
+                Response::ok("{\"msg\":\"Deleted\"}")
+            }
+        })
+    }
+
+    #[test]
+    fn handles_keys_in_sequence_order() {
+        let server = server(vec![
+            vec![reserved(1, "a", 2), reserved(2, "a", 1), reserved(3, "a", 1), reserved(4, "b", 1), reserved(5, "a", 5)],
+            vec![reserved(6, "a", 4), reserved(7, "a", 5), reserved(8, "a", 2)],
+        ]);
+
+        let recorder = Arc::new(Recorder::default());
+        let mut client = server.client();
+        client.metrics(recorder.clone());
+
+        let mut consumer = OrderedConsumer::new(&mut client, "events");
+        consumer.wait(0).window(3).gap_timeout(Duration::from_secs(0));
+        let mut bodies = Vec::new();
+        let mut handler = |envelope: &OrderedEnvelope| {
+            bodies.push(envelope.body.clone());
+            Ok(())
+        };
+
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 3);
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 2);
+        assert_eq!(consumer.next_sequence("a"), 6);
+        drop(consumer);
+
+        assert_eq!(bodies, vec!["a1", "a2", "b1", "a4", "a5"]);
+        assert_eq!(server.lines().iter().filter(|line| line.ends_with("/release")).count(), 1);
+        assert_eq!(
+            *recorder.0.lock().unwrap(),
+            vec![
+                (SequenceAnomaly::Early, 1),
+                (SequenceAnomaly::Duplicate, 1),
+                (SequenceAnomaly::Early, 1),
+                (SequenceAnomaly::Early, 1),
+                (SequenceAnomaly::Early, 1),
+                (SequenceAnomaly::Duplicate, 1),
+                (SequenceAnomaly::Gap, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn starts_keys_where_their_messages_start() {
+        let server = server(vec![vec![reserved(1, "a", 50), reserved(2, "a", 52), reserved(3, "a", 51)]]);
+
+        let recorder = Arc::new(Recorder::default());
+        let mut client = server.client();
+        client.metrics(recorder.clone());
+
+        let mut consumer = OrderedConsumer::new(&mut client, "events");
+        consumer.wait(0).window(3).gap_timeout(Duration::from_secs(0));
+        let mut bodies = Vec::new();
+        let mut handler = |envelope: &OrderedEnvelope| {
+            bodies.push(envelope.body.clone());
+            Ok(())
+        };
+
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 3);
+        assert_eq!(consumer.next_sequence("a"), 53);
+        drop(consumer);
+
+        assert_eq!(bodies, vec!["a50", "a51", "a52"]);
+        assert_eq!(server.lines().iter().filter(|line| line.ends_with("/release")).count(), 0);
+        assert_eq!(*recorder.0.lock().unwrap(), vec![(SequenceAnomaly::Early, 1), (SequenceAnomaly::Early, 1)]);
+    }
+
+    #[test]
+    fn retries_failed_messages_instead_of_skipping_them() {
+        let server = server(vec![
+            vec![reserved(1, "a", 1), reserved(2, "a", 2)],
+            vec![reserved(3, "a", 3)],
+            vec![reserved(4, "a", 1)],
+        ]);
+
+        let recorder = Arc::new(Recorder::default());
+        let mut client = server.client();
+        client.metrics(recorder.clone());
+
+        let mut consumer = OrderedConsumer::new(&mut client, "events");
+        consumer.wait(0).gap_timeout(Duration::from_secs(0));
+        let mut failed = false;
+        let mut bodies = Vec::new();
+        let mut handler = |envelope: &OrderedEnvelope| {
+            if envelope.seq == 1 && !failed {
+                failed = true;
+                return Err("Failed".to_string());
+            }
+            bodies.push(envelope.body.clone());
+            Ok(())
+        };
+
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 0);
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 0);
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 3);
+        drop(consumer);
+
+        assert_eq!(bodies, vec!["a1", "a2", "a3"]);
+        assert_eq!(server.lines().iter().filter(|line| line.starts_with("DELETE")).count(), 3);
+        assert_eq!(*recorder.0.lock().unwrap(), vec![(SequenceAnomaly::Early, 1), (SequenceAnomaly::Early, 1)]);
+    }
+
+    #[test]
+    fn dead_letters_messages_that_keep_failing() {
+        let server = server(vec![vec![reserved(1, "a", 1), reserved(2, "a", 2)]]);
+        let mut client = server.client();
+
+        let mut consumer = OrderedConsumer::new(&mut client, "events");
+        consumer.wait(0).gap_timeout(Duration::from_secs(0)).dead_letter_queue("events-dead");
+        let mut bodies = Vec::new();
+        let mut handler = |envelope: &OrderedEnvelope| {
+            if envelope.seq == 1 {
+                return Err("Failed".to_string());
+            }
+            bodies.push(envelope.body.clone());
+            Ok(())
+        };
+
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 1);
+        assert_eq!(consumer.next_sequence("a"), 3);
+        drop(consumer);
+
+        assert_eq!(bodies, vec!["a2"]);
+        let requests = server.requests();
+        let dead = requests.iter().find(|request| request.queue() == Some("events-dead")).unwrap();
+        let envelope: OrderedEnvelope = serde_json::from_str(dead.json()["messages"][0]["body"].as_str().unwrap()).unwrap();
+        assert_eq!(envelope.seq, 1);
+        assert!(server.lines().contains(&"DELETE /3/projects/project/queues/events/messages/1".to_string()));
+    }
+
+    #[test]
+    fn forgets_idle_keys() {
+        let server = server(vec![vec![reserved(1, "a", 1)]]);
+        let mut client = server.client();
+
+        let mut consumer = OrderedConsumer::new(&mut client, "events");
+        consumer.wait(0).idle_timeout(Duration::from_secs(0));
+        let mut handler = |_: &OrderedEnvelope| Ok(());
+
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 1);
+        assert_eq!(consumer.next_sequence("a"), 2);
+        assert_eq!(consumer.consume_once(&mut handler).unwrap(), 0);
+        assert_eq!(consumer.next_sequence("a"), 1);
+    }
+}
@@ -0,0 +1,410 @@
+use std::mem;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use futures::sync::oneshot;
+use futures::task::{self, Task};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
+use serde_json;
+
+use super::message::Message;
+use Client;
+
+// Most messages IronMQ takes in one push.
+const MAX_BATCH: usize = 100;
+const DEFAULT_MAX_BYTES: usize = 1 << 20;
+const DEFAULT_LINGER: u64 = 50;
+const DEFAULT_CAPACITY: usize = 10 * MAX_BATCH;
+
+pub struct ProducerOptions {
+    max_messages: usize,
+    max_bytes: usize,
+    linger: Duration,
+    capacity: usize,
+}
+
+impl ProducerOptions {
+    pub fn new() -> ProducerOptions {
+        ProducerOptions {
+            max_messages: MAX_BATCH,
+            max_bytes: DEFAULT_MAX_BYTES,
+            linger: Duration::from_millis(DEFAULT_LINGER),
+            capacity: DEFAULT_CAPACITY,
+        }
+    }
+
+    pub fn max_messages(&mut self, max_messages: usize) -> &mut ProducerOptions {
+        self.max_messages = max_messages.clamp(1, MAX_BATCH);
+
+        self
+    }
+
+    // Total size of the JSON-encoded messages of a batch.
+    pub fn max_bytes(&mut self, max_bytes: usize) -> &mut ProducerOptions {
+        self.max_bytes = max_bytes;
+
+        self
+    }
+
+    // How long the first message of a batch waits for others.
+    pub fn linger(&mut self, linger: Duration) -> &mut ProducerOptions {
+        self.linger = linger;
+
+        self
+    }
+
+    // How many messages may wait for the background thread. When they
+    // are that many, pushing blocks and the `Sink` isn't ready.
+    pub fn capacity(&mut self, capacity: usize) -> &mut ProducerOptions {
+        self.capacity = capacity.max(1);
+
+        self
+    }
+}
+
+impl Default for ProducerOptions {
+    fn default() -> ProducerOptions {
+        ProducerOptions::new()
+    }
+}
+
+// Resolves to the id of a message once its batch is pushed.
+pub struct PendingId {
+    receiver: oneshot::Receiver<Result<String, String>>,
+}
+
+impl Future for PendingId {
+    type Item = String;
+    type Error = String;
+
+    fn poll(&mut self) -> Poll<String, String> {
+        match self.receiver.poll() {
+            Ok(Async::Ready(Ok(id))) => Ok(Async::Ready(id)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => Err("Producer stopped".to_string()),
+        }
+    }
+}
+
+enum Command {
+    Push(Message, oneshot::Sender<Result<String, String>>),
+    Flush(oneshot::Sender<Result<(), String>>),
+}
+
+// Buffers messages and pushes them in batches from a background thread,
+// with a client of its own from `client`. Messages still buffered when the
+// producer is dropped are not pushed: call `flush` or `close` first.
+pub struct BatchingProducer {
+    sender: SyncSender<Command>,
+    thread: JoinHandle<()>,
+    flushing: Option<oneshot::Receiver<Result<(), String>>>,
+    // The `Sink` task waiting for room in the channel.
+    waiting: Arc<Mutex<Option<Task>>>,
+}
+
+impl BatchingProducer {
+    pub fn new<F>(queue: &str, options: ProducerOptions, client: F) -> BatchingProducer
+    where
+        F: FnOnce() -> Client + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(options.capacity);
+        let queue = queue.to_string();
+        let waiting = Arc::new(Mutex::new(None));
+        let waiter = waiting.clone();
+        let thread = thread::spawn(move || {
+            let mut client = client();
+            Batcher {
+                client: &mut client,
+                queue,
+                options,
+                buffer: Vec::new(),
+                bytes: 0,
+                started: None,
+                error: None,
+                waiting: waiter,
+            }.run(receiver);
+        });
+
+        BatchingProducer {
+            sender,
+            thread,
+            flushing: None,
+            waiting,
+        }
+    }
+
+    pub fn push_message(&self, message: Message) -> PendingId {
+        let (sender, receiver) = oneshot::channel();
+        // If the thread is gone the receiver is cancelled, which the
+        // `PendingId` reports.
+        let _ = self.sender.send(Command::Push(message, sender));
+
+        PendingId { receiver }
+    }
+
+    pub fn push_string(&self, body: &str) -> PendingId {
+        self.push_message(Message::with_body(body))
+    }
+
+    // Pushes everything sent so far. Returns the first push error since
+    // the last flush, if any.
+    pub fn flush(&self) -> Result<(), String> {
+        self.request_flush().wait().unwrap_or_else(|_| Err("Producer stopped".to_string()))
+    }
+
+    // Flushes and stops the background thread.
+    pub fn close(self) -> Result<(), String> {
+        let result = BatchingProducer::flush(&self);
+
+        let BatchingProducer { sender, thread, .. } = self;
+        drop(sender);
+        thread.join().map_err(|_| "Producer thread panicked".to_string())?;
+
+        result
+    }
+
+    fn request_flush(&self) -> oneshot::Receiver<Result<(), String>> {
+        let (sender, receiver) = oneshot::channel();
+        let _ = self.sender.send(Command::Flush(sender));
+
+        receiver
+    }
+
+    // Sends without blocking. If the channel is full the command is given
+    // back, and the current task woken up once there is room.
+    fn try_send(&self, command: Command) -> Result<Option<Command>, String> {
+        let command = match self.sender.try_send(command) {
+            Ok(()) => return Ok(None),
+            Err(TrySendError::Full(command)) => command,
+            Err(TrySendError::Disconnected(_)) => return Err("Producer stopped".to_string()),
+        };
+
+        *self.waiting.lock().unwrap() = Some(task::current());
+        // Room may have been made before the task was stored.
+        match self.sender.try_send(command) {
+            Ok(()) => Ok(None),
+            Err(TrySendError::Full(command)) => Ok(Some(command)),
+            Err(TrySendError::Disconnected(_)) => Err("Producer stopped".to_string()),
+        }
+    }
+}
+
+// Ids are dropped when sent through the `Sink`; errors are reported by
+// `poll_complete`.
+impl Sink for BatchingProducer {
+    type SinkItem = Message;
+    type SinkError = String;
+
+    fn start_send(&mut self, message: Message) -> StartSend<Message, String> {
+        let (sender, _) = oneshot::channel();
+
+        match self.try_send(Command::Push(message, sender))? {
+            Some(Command::Push(message, _)) => Ok(AsyncSink::NotReady(message)),
+            _ => Ok(AsyncSink::Ready),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), String> {
+        if self.flushing.is_none() {
+            let (sender, receiver) = oneshot::channel();
+            if self.try_send(Command::Flush(sender))?.is_some() {
+                return Ok(Async::NotReady);
+            }
+            self.flushing = Some(receiver);
+        }
+
+        let result = match self.flushing.as_mut().unwrap().poll() {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(result)) => result,
+            Err(_) => Err("Producer stopped".to_string()),
+        };
+        self.flushing = None;
+
+        result.map(Async::Ready)
+    }
+
+    fn close(&mut self) -> Poll<(), String> {
+        self.poll_complete()
+    }
+}
+
+struct Batcher<'c> {
+    client: &'c mut Client,
+    queue: String,
+    options: ProducerOptions,
+    buffer: Vec<(Message, oneshot::Sender<Result<String, String>>)>,
+    bytes: usize,
+    // When the first message of the current batch came in.
+    started: Option<Instant>,
+    error: Option<String>,
+    waiting: Arc<Mutex<Option<Task>>>,
+}
+
+impl<'c> Batcher<'c> {
+    fn run(&mut self, commands: Receiver<Command>) {
+        loop {
+            let command = match self.started {
+                Some(started) => {
+                    let linger = (started + self.options.linger).saturating_duration_since(Instant::now());
+                    commands.recv_timeout(linger)
+                }
+                None => commands.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            if command.is_ok() {
+                if let Some(task) = self.waiting.lock().unwrap().take() {
+                    task.notify();
+                }
+            }
+
+            match command {
+                Ok(Command::Push(message, id)) => self.add(message, id),
+                Ok(Command::Flush(done)) => {
+                    self.push();
+                    let _ = done.send(self.error.take().map_or(Ok(()), Err));
+                }
+                Err(RecvTimeoutError::Timeout) => self.push(),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+
+        for (_, id) in self.buffer.drain(..) {
+            let _ = id.send(Err("Producer dropped without flushing".to_string()));
+        }
+    }
+
+    fn add(&mut self, message: Message, id: oneshot::Sender<Result<String, String>>) {
+        // Encoded as in the push request, including the separating comma.
+        let size = serde_json::to_string(&message).map_or(message.body.len(), |json| json.len()) + 1;
+        if !self.buffer.is_empty() && self.bytes + size > self.options.max_bytes {
+            self.push();
+        }
+
+        self.bytes += size;
+        self.buffer.push((message, id));
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+
+        if self.buffer.len() >= self.options.max_messages || self.bytes >= self.options.max_bytes {
+            self.push();
+        }
+    }
+
+    fn push(&mut self) {
+        self.bytes = 0;
+        self.started = None;
+        if self.buffer.is_empty() {
+            return;
+        }
+
+        let (messages, ids): (Vec<Message>, Vec<_>) = mem::take(&mut self.buffer).into_iter().unzip();
+        let count = messages.len();
+
+        let result = self.client.queue(self.queue.clone()).push_messages(messages);
+        let result = match result {
+            Ok(ref pushed) if pushed.len() != count => Err(format!("Pushed {} of {} messages", pushed.len(), count)),
+            result => result,
+        };
+
+        match result {
+            Ok(pushed) => {
+                for (id, pushed) in ids.into_iter().zip(pushed) {
+                    let _ = id.send(Ok(pushed));
+                }
+            }
+            Err(e) => {
+                if self.error.is_none() {
+                    self.error = Some(e.clone());
+                }
+                for id in ids {
+                    let _ = id.send(Err(e.clone()));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{self, FakeServer, Response};
+
+    #[test]
+    fn pushes_in_batches() {
+        let mut next = 0;
+        let server = FakeServer::start(move |request| {
+            let count = request.json()["messages"].as_array().map_or(0, Vec::len);
+            let ids: Vec<String> = (next..next + count).map(|i| format!("\"{}\"", i)).collect();
+            next += count;
+            Response::ok(&format!("{{\"ids\":[{}]}}", ids.join(",")))
+        });
+        let addr = server.addr();
+
+        let mut options = ProducerOptions::new();
+        options.max_messages(2).linger(Duration::from_secs(60));
+        let producer = BatchingProducer::new("events", options, move || test_server::client(&addr));
+
+        let first = producer.push_string("a");
+        let second = producer.push_string("b");
+        let third = producer.push_string("c");
+
+        assert_eq!(first.wait(), Ok("0".to_string()));
+        assert_eq!(second.wait(), Ok("1".to_string()));
+        producer.close().unwrap();
+        assert_eq!(third.wait(), Ok("2".to_string()));
+        let batches: Vec<usize> = server.requests().iter().map(|r| r.json()["messages"].as_array().unwrap().len()).collect();
+        assert_eq!(batches, vec![2, 1]);
+    }
+
+    #[test]
+    fn sink_applies_backpressure() {
+        let server = FakeServer::start(|request| {
+            let count = request.json()["messages"].as_array().map_or(0, Vec::len);
+            let ids: Vec<String> = (0..count).map(|i| format!("\"{}\"", i)).collect();
+            Response::ok(&format!("{{\"ids\":[{}]}}", ids.join(","))).delayed(Duration::from_millis(200))
+        });
+        let addr = server.addr();
+
+        let mut options = ProducerOptions::new();
+        options.max_messages(1).capacity(1);
+        let mut producer = BatchingProducer::new("events", options, move || test_server::client(&addr));
+
+        // The first message is being pushed, the second one waits for it.
+        futures::future::lazy(|| {
+            assert!(producer.start_send(Message::with_body("a")).unwrap().is_ready());
+            thread::sleep(Duration::from_millis(50));
+            assert!(producer.start_send(Message::with_body("b")).unwrap().is_ready());
+            assert!(producer.start_send(Message::with_body("c")).unwrap().is_not_ready());
+            Ok::<(), ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        let messages = (0..3).map(|i| Message::with_body(&i.to_string()));
+        let (producer, _) = producer.send_all(futures::stream::iter_ok::<_, String>(messages)).wait().unwrap();
+        producer.close().unwrap();
+        assert_eq!(server.requests().len(), 5);
+    }
+
+    #[test]
+    fn counts_encoded_size() {
+        let server = FakeServer::constant("200 OK", "{\"ids\":[\"1\"]}");
+        let addr = server.addr();
+
+        // Each message takes 24 bytes in the push request, three times its body.
+        let mut options = ProducerOptions::new();
+        options.max_bytes(40).linger(Duration::from_secs(60));
+        let producer = BatchingProducer::new("events", options, move || test_server::client(&addr));
+
+        producer.push_string("\"quoted\"");
+        producer.push_string("\"quoted\"");
+        producer.close().unwrap();
+
+        let batches: Vec<usize> = server.requests().iter().map(|r| r.json()["messages"].as_array().unwrap().len()).collect();
+        assert_eq!(batches, vec![1, 1]);
+    }
+}
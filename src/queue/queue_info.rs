@@ -79,15 +79,18 @@ impl QueueInfo {
 pub struct PushInfo {
     pub retries_delay: u32,
     pub retries: u32,
+    // Left out when empty, so an update keeps the current subscribers.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub subscribers: Vec<QueueSubscriber>,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub error_queue: String,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct QueueSubscriber {
-    name: String,
-    url: String,
-    #[serde(skip_serializing_if = "Option::is_none")] headers: Option<HashMap<String, String>>,
+    pub name: String,
+    pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")] pub headers: Option<HashMap<String, String>>,
 }
 
 impl QueueSubscriber {
@@ -152,7 +155,7 @@ impl Alert {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct PushStatus {
     pub subscriber_name: String,
     pub retries_remaining: u32,
@@ -0,0 +1,421 @@
+use std::collections::VecDeque;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json;
+
+use super::message::Message;
+use Client;
+
+const DEFAULT_CALL_TIMEOUT: u64 = 30;
+const DEFAULT_WAIT: u32 = 30;
+// How long reserved replies and requests stay invisible to others.
+const RESERVATION_TIMEOUT: u32 = 60;
+// Replies meant for another caller on a shared reply queue are put back
+// with this delay, so we don't reserve them again right away.
+const FOREIGN_REPLY_DELAY: u32 = 1;
+// A reply reserved this often without its caller taking it is orphaned.
+const MAX_FOREIGN_RESERVATIONS: u32 = 50;
+const EXPIRED_CAPACITY: usize = 1000;
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+// Body of RPC requests and replies. `reply_to` is set on requests, `error`
+// on replies to requests the handler failed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcEnvelope {
+    pub correlation_id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    pub body: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+fn correlation_id() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()))
+        .unwrap_or(0);
+
+    format!("{:x}-{:x}-{:x}", process::id(), nanos, CALLS.fetch_add(1, Ordering::SeqCst))
+}
+
+// Pushes requests to `queue` and waits for the matching reply on
+// `reply_queue`. A reply queue per caller is best; on a shared one replies
+// for other callers are released for them to pick up.
+pub struct RpcClient<'a> {
+    client: &'a mut Client,
+    queue: String,
+    reply_queue: String,
+    timeout: Duration,
+    expired: VecDeque<String>,
+}
+
+impl<'a> RpcClient<'a> {
+    pub fn new(client: &'a mut Client, queue: &str, reply_queue: &str) -> RpcClient<'a> {
+        RpcClient {
+            client,
+            queue: queue.to_string(),
+            reply_queue: reply_queue.to_string(),
+            timeout: Duration::from_secs(DEFAULT_CALL_TIMEOUT),
+            expired: VecDeque::new(),
+        }
+    }
+
+    pub fn timeout(&mut self, timeout: Duration) -> &mut RpcClient<'a> {
+        self.timeout = timeout;
+
+        self
+    }
+
+    pub fn call(&mut self, body: &str) -> Result<String, String> {
+        let id = correlation_id();
+        let request = RpcEnvelope {
+            correlation_id: id.clone(),
+            reply_to: Some(self.reply_queue.clone()),
+            body: body.to_string(),
+            error: None,
+        };
+        let request = serde_json::to_string(&request).map_err(|e| e.to_string())?;
+        self.client.queue(self.queue.clone()).push_string(&request)?;
+
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                self.expire(id);
+                return Err("RPC call timed out".to_string());
+            }
+            let remaining = deadline - now;
+            let wait = (remaining.as_secs() as u32 + 1).min(DEFAULT_WAIT);
+
+            let messages = match self
+                .client
+                .queue(self.reply_queue.clone())
+                .long_poll(1, RESERVATION_TIMEOUT, wait, false)
+            {
+                Ok(messages) => messages,
+                Err(e) => {
+                    // The reply may still come, to be dropped like any late one.
+                    self.expire(id);
+                    return Err(e);
+                }
+            };
+
+            let mut reply = None;
+            for message in messages {
+                if let Some(envelope) = self.handle_reply(message, &id) {
+                    reply = Some(envelope);
+                }
+            }
+
+            if let Some(reply) = reply {
+                return match reply.error {
+                    Some(error) => Err(error),
+                    None => Ok(reply.body),
+                };
+            }
+        }
+    }
+
+    // Returns the reply if it's the one we wait for, otherwise deletes or
+    // releases it.
+    fn handle_reply(&mut self, message: Message, id: &str) -> Option<RpcEnvelope> {
+        let mut queue = self.client.queue(self.reply_queue.clone());
+
+        let envelope: RpcEnvelope = match serde_json::from_str(&message.body) {
+            Ok(envelope) => envelope,
+            Err(_) => {
+                ::log::warn!("Deleting malformed RPC reply {:?} from {}", message.id, self.reply_queue);
+                queue.delete_message(message);
+                return None;
+            }
+        };
+
+        if envelope.correlation_id == id {
+            queue.delete_message(message);
+            return Some(envelope);
+        }
+
+        let orphaned = message.reserved_count.unwrap_or(0) >= MAX_FOREIGN_RESERVATIONS;
+        if orphaned || self.expired.contains(&envelope.correlation_id) {
+            queue.delete_message(message);
+        } else {
+            queue.release_message(message, FOREIGN_REPLY_DELAY);
+        }
+
+        None
+    }
+
+    // Late replies to calls that timed out are dropped when they show up.
+    fn expire(&mut self, id: String) {
+        if self.expired.len() == EXPIRED_CAPACITY {
+            self.expired.pop_front();
+        }
+        self.expired.push_back(id);
+    }
+}
+
+// Consumes requests from a queue and pushes the handler's result to the
+// requests' reply queues.
+pub struct RpcServer<'a> {
+    client: &'a mut Client,
+    queue: String,
+    batch_size: u8,
+    wait: u32,
+}
+
+impl<'a> RpcServer<'a> {
+    pub fn new(client: &'a mut Client, queue: &str) -> RpcServer<'a> {
+        RpcServer {
+            client,
+            queue: queue.to_string(),
+            batch_size: 1,
+            wait: DEFAULT_WAIT,
+        }
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut RpcServer<'a> {
+        self.batch_size = batch_size;
+
+        self
+    }
+
+    pub fn wait(&mut self, wait: u32) -> &mut RpcServer<'a> {
+        self.wait = wait;
+
+        self
+    }
+
+    // Handles requests until reserving from the queue fails.
+    pub fn serve<F>(&mut self, mut handler: F) -> Result<(), String>
+    where
+        F: FnMut(&str) -> Result<String, String>,
+    {
+        loop {
+            self.serve_once(&mut handler)?;
+        }
+    }
+
+    // Handles one batch of requests, returning how many were answered.
+    // A request whose reply can't be pushed is released to be retried.
+    pub fn serve_once<F>(&mut self, handler: &mut F) -> Result<usize, String>
+    where
+        F: FnMut(&str) -> Result<String, String>,
+    {
+        let requests = self
+            .client
+            .queue(self.queue.clone())
+            .long_poll(self.batch_size, RESERVATION_TIMEOUT, self.wait, false)?;
+        let mut answered = 0;
+
+        for message in requests {
+            let request: RpcEnvelope = match serde_json::from_str(&message.body) {
+                Ok(request) => request,
+                Err(_) => {
+                    ::log::warn!("Deleting malformed RPC request {:?} from {}", message.id, self.queue);
+                    self.client.queue(self.queue.clone()).delete_message(message);
+                    continue;
+                }
+            };
+
+            let result = handler(&request.body);
+
+            if let Some(reply_to) = request.reply_to {
+                let reply = RpcEnvelope {
+                    correlation_id: request.correlation_id,
+                    reply_to: None,
+                    body: result.clone().unwrap_or_default(),
+                    error: result.err(),
+                };
+                let reply = serde_json::to_string(&reply).map_err(|e| e.to_string())?;
+
+                if self.client.queue(reply_to).push_string(&reply).is_err() {
+                    self.client.queue(self.queue.clone()).release_message(message, 0);
+                    continue;
+                }
+                answered += 1;
+            }
+
+            self.client.queue(self.queue.clone()).delete_message(message);
+        }
+
+        Ok(answered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{FakeServer, Request, Response};
+
+    // Correlation id of a request pushed by `call`.
+    fn pushed_id(request: &Request) -> String {
+        let body = request.json()["messages"][0]["body"].as_str().unwrap_or("").to_string();
+        let envelope: RpcEnvelope = serde_json::from_str(&body).unwrap();
+
+        envelope.correlation_id
+    }
+
+    fn reserved(id: &str, envelope: &RpcEnvelope) -> Response {
+        let body = serde_json::to_string(envelope).unwrap();
+        let message = json!({"id": id, "body": body, "reservation_id": "r", "reserved_count": 1});
+
+        Response::ok(&json!({ "messages": [message] }).to_string())
+    }
+
+    fn reply(id: &str, correlation_id: &str, body: &str) -> Response {
+        reserved(
+            id,
+            &RpcEnvelope {
+                correlation_id: correlation_id.to_string(),
+                reply_to: None,
+                body: body.to_string(),
+                error: None,
+            },
+        )
+    }
+
+    // Answers the first call with `first`, and the second with the late
+    // reply to the first (message 10) followed by its own (message 11).
+    fn server(first: fn() -> Response) -> FakeServer {
+        let mut ids = Vec::new();
+        let mut late = false;
+
+        FakeServer::start(move |request| {
+            if request.method == "POST" && request.path.ends_with("/queues/requests/messages") {
+                ids.push(pushed_id(request));
+                Response::ok("{\"ids\":[\"1\"],\"msg\":\"Messages put on queue.\"}")
+            } else if request.path.ends_with("/reservations") && ids.len() == 1 {
+                first()
+            } else if request.path.ends_with("/reservations") && !late {
+                late = true;
+                reply("10", &ids[0], "late")
+            } else if request.path.ends_with("/reservations") {
+                reply("11", &ids[1], "pong")
+            } else if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else {
+                Response::ok("{\"msg\":\"Deleted\"}")
+            }
+        })
+    }
+
+    #[test]
+    fn envelopes() {
+        let request: RpcEnvelope =
+            serde_json::from_str("{\"correlation_id\":\"1\",\"reply_to\":\"replies\",\"body\":\"ping\"}").unwrap();
+        assert_eq!(request.reply_to, Some("replies".to_string()));
+        assert_eq!(request.error, None);
+
+        let reply = RpcEnvelope {
+            correlation_id: "1".to_string(),
+            reply_to: None,
+            body: "pong".to_string(),
+            error: None,
+        };
+        assert_eq!(serde_json::to_string(&reply).unwrap(), "{\"correlation_id\":\"1\",\"body\":\"pong\"}");
+
+        assert_ne!(correlation_id(), correlation_id());
+    }
+
+    #[test]
+    fn releases_replies_of_other_calls() {
+        let mut id = None;
+        let mut polls = 0;
+        let server = FakeServer::start(move |request| {
+            if request.method == "POST" && request.path.ends_with("/queues/requests/messages") {
+                id = Some(pushed_id(request));
+                return Response::ok("{\"ids\":[\"1\"],\"msg\":\"Messages put on queue.\"}");
+            }
+            if request.path.ends_with("/reservations") {
+                polls += 1;
+                return match polls {
+                    1 => reply("10", "other", "not ours"),
+                    _ => reply("11", id.as_ref().unwrap(), "pong"),
+                };
+            }
+            if request.path.ends_with("/release") {
+                return Response::ok("{\"msg\":\"Released\"}");
+            }
+            Response::ok("{\"msg\":\"Deleted\"}")
+        });
+        let mut client = server.client();
+
+        let reply = RpcClient::new(&mut client, "requests", "replies").call("ping");
+
+        assert_eq!(reply, Ok("pong".to_string()));
+        let lines = server.lines();
+        assert!(lines.contains(&"POST /3/projects/project/queues/replies/messages/10/release".to_string()));
+        assert!(!lines.contains(&"DELETE /3/projects/project/queues/replies/messages/10".to_string()));
+        assert!(lines.contains(&"DELETE /3/projects/project/queues/replies/messages/11".to_string()));
+    }
+
+    #[test]
+    fn drops_late_replies() {
+        let server = server(|| Response::ok("{\"messages\":[]}"));
+        let mut client = server.client();
+        let mut rpc = RpcClient::new(&mut client, "requests", "replies");
+        rpc.timeout(Duration::from_millis(50));
+
+        assert_eq!(rpc.call("first"), Err("RPC call timed out".to_string()));
+        rpc.timeout(Duration::from_secs(5));
+        assert_eq!(rpc.call("second"), Ok("pong".to_string()));
+
+        let lines = server.lines();
+        assert!(lines.contains(&"DELETE /3/projects/project/queues/replies/messages/10".to_string()));
+        assert!(!lines.iter().any(|line| line.ends_with("/release")));
+    }
+
+    #[test]
+    fn expires_calls_failing_to_reserve() {
+        let server = server(|| Response::status("500 Internal Server Error", "{\"msg\":\"Internal error\"}"));
+        let mut client = server.client();
+        let mut rpc = RpcClient::new(&mut client, "requests", "replies");
+
+        assert!(rpc.call("first").unwrap_err().contains("Internal error"));
+        assert_eq!(rpc.call("second"), Ok("pong".to_string()));
+
+        let lines = server.lines();
+        assert!(lines.contains(&"DELETE /3/projects/project/queues/replies/messages/10".to_string()));
+        assert!(!lines.iter().any(|line| line.ends_with("/release")));
+    }
+
+    #[test]
+    fn answers_requests() {
+        let server = FakeServer::start(|request| {
+            if request.path.ends_with("/queues/requests/reservations") {
+                reserved(
+                    "1",
+                    &RpcEnvelope {
+                        correlation_id: "c1".to_string(),
+                        reply_to: Some("replies".to_string()),
+                        body: "ping".to_string(),
+                        error: None,
+                    },
+                )
+            } else if request.method == "POST" {
+                Response::ok("{\"ids\":[\"2\"],\"msg\":\"Messages put on queue.\"}")
+            } else {
+                Response::ok("{\"msg\":\"Deleted\"}")
+            }
+        });
+        let mut client = server.client();
+
+        let answered = RpcServer::new(&mut client, "requests")
+            .wait(0)
+            .serve_once(&mut |body: &str| Ok(body.to_uppercase()))
+            .unwrap();
+
+        assert_eq!(answered, 1);
+        let requests = server.requests();
+        assert_eq!(requests[1].line(), "POST /3/projects/project/queues/replies/messages");
+        let reply: RpcEnvelope = serde_json::from_str(requests[1].json()["messages"][0]["body"].as_str().unwrap()).unwrap();
+        assert_eq!(reply.correlation_id, "c1");
+        assert_eq!(reply.body, "PING");
+        assert_eq!(requests[2].line(), "DELETE /3/projects/project/queues/requests/messages/1");
+    }
+}
@@ -0,0 +1,478 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use futures::task::{self, Task};
+use futures::{Async, Poll, Stream};
+
+use super::Queue;
+use super::message::Message;
+
+const DEFAULT_BATCH_SIZE: u8 = 10;
+const DEFAULT_MAX_UNACKED: usize = 100;
+const DEFAULT_WAIT: u32 = 30;
+// Most messages a single delete request takes.
+const DELETE_BATCH: usize = 100;
+
+pub struct StreamOptions {
+    batch_size: u8,
+    max_unacked: usize,
+    reservation_timeout: u32,
+    wait: u32,
+}
+
+impl StreamOptions {
+    pub fn new() -> StreamOptions {
+        StreamOptions {
+            batch_size: DEFAULT_BATCH_SIZE,
+            max_unacked: DEFAULT_MAX_UNACKED,
+            reservation_timeout: super::DEFAULT_TIMEOUT,
+            wait: DEFAULT_WAIT,
+        }
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut StreamOptions {
+        self.batch_size = batch_size.max(1);
+
+        self
+    }
+
+    // Messages handed out and not yet acked or nacked. The stream doesn't
+    // reserve more until downstream catches up.
+    pub fn max_unacked(&mut self, max_unacked: usize) -> &mut StreamOptions {
+        self.max_unacked = max_unacked.max(1);
+
+        self
+    }
+
+    pub fn reservation_timeout(&mut self, reservation_timeout: u32) -> &mut StreamOptions {
+        self.reservation_timeout = reservation_timeout;
+
+        self
+    }
+
+    pub fn wait(&mut self, wait: u32) -> &mut StreamOptions {
+        self.wait = wait;
+
+        self
+    }
+}
+
+impl Default for StreamOptions {
+    fn default() -> StreamOptions {
+        StreamOptions::new()
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    deletes: Vec<Message>,
+    releases: Vec<(Message, u32)>,
+    unacked: usize,
+    task: Option<Task>,
+}
+
+// A reserved message from `Queue::stream`. It dereferences to the message.
+// Acks and nacks are sent by the stream on its next poll or `flush`; a
+// message dropped without either is left to its reservation timeout.
+pub struct ReservedMessage {
+    message: Option<Message>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl ReservedMessage {
+    pub fn message(&self) -> &Message {
+        self.message.as_ref().unwrap()
+    }
+
+    // Deletes the message from the queue.
+    pub fn ack(mut self) {
+        let message = self.message.take().unwrap();
+        self.settle(|pending| pending.deletes.push(message));
+    }
+
+    // Releases the message back to the queue after `delay` seconds.
+    pub fn nack(mut self, delay: u32) {
+        let message = self.message.take().unwrap();
+        self.settle(|pending| pending.releases.push((message, delay)));
+    }
+
+    fn settle<F: FnOnce(&mut Pending)>(&self, action: F) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        action(&mut pending);
+        pending.unacked -= 1;
+        if let Some(task) = pending.task.take() {
+            task.notify();
+        }
+    }
+}
+
+impl Deref for ReservedMessage {
+    type Target = Message;
+
+    fn deref(&self) -> &Message {
+        self.message()
+    }
+}
+
+impl Drop for ReservedMessage {
+    fn drop(&mut self) {
+        if self.message.is_some() {
+            self.settle(|_| {});
+        }
+    }
+}
+
+// Long-polls a queue for as long as it's polled. Polling blocks while
+// reserving, for up to the `wait` of the options, like any other call on
+// the client; run it on a thread of its own rather than on a reactor.
+// Messages reserved but not handed out are released when it's dropped.
+pub struct QueueStream<'q, 'a: 'q> {
+    queue: &'q mut Queue<'a>,
+    options: StreamOptions,
+    buffer: VecDeque<Message>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+impl<'a> Queue<'a> {
+    pub fn stream<'q>(&'q mut self, options: StreamOptions) -> QueueStream<'q, 'a> {
+        QueueStream {
+            queue: self,
+            options,
+            buffer: VecDeque::new(),
+            pending: Arc::new(Mutex::new(Pending::default())),
+        }
+    }
+}
+
+impl<'q, 'a> QueueStream<'q, 'a> {
+    // Deletes and releases the messages acked and nacked since the last
+    // poll. Call it after the last message is handled.
+    pub fn flush(&mut self) -> Result<(), String> {
+        let (deletes, releases) = {
+            let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+            (mem::take(&mut pending.deletes), mem::take(&mut pending.releases))
+        };
+
+        let mut deletes = deletes;
+        while !deletes.is_empty() {
+            let rest = deletes.split_off(deletes.len().min(DELETE_BATCH));
+            let msg = self.queue.delete_messages(deletes);
+            if !msg.contains("Deleted") {
+                return Err(msg);
+            }
+            deletes = rest;
+        }
+
+        for (message, delay) in releases {
+            let msg = self.queue.release_message(message, delay);
+            if !msg.contains("Released") {
+                return Err(msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wrap(&self, message: Message) -> ReservedMessage {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner()).unacked += 1;
+
+        ReservedMessage {
+            message: Some(message),
+            pending: self.pending.clone(),
+        }
+    }
+
+    // How many more messages may be handed out, registering the task to
+    // be woken up by an ack or nack if none.
+    fn capacity(&self) -> usize {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        let capacity = self.options.max_unacked.saturating_sub(pending.unacked);
+        if capacity == 0 {
+            pending.task = Some(task::current());
+        }
+
+        capacity
+    }
+}
+
+impl<'q, 'a> Stream for QueueStream<'q, 'a> {
+    type Item = ReservedMessage;
+    type Error = String;
+
+    fn poll(&mut self) -> Poll<Option<ReservedMessage>, String> {
+        self.flush()?;
+
+        loop {
+            let capacity = self.capacity();
+            if capacity == 0 {
+                return Ok(Async::NotReady);
+            }
+
+            if let Some(message) = self.buffer.pop_front() {
+                return Ok(Async::Ready(Some(self.wrap(message))));
+            }
+
+            let count = capacity.min(self.options.batch_size as usize) as u8;
+            let messages = self.queue.long_poll(count, self.options.reservation_timeout, self.options.wait, false)?;
+            if messages.is_empty() {
+                // Gives other tasks a turn before polling again.
+                task::current().notify();
+                return Ok(Async::NotReady);
+            }
+            self.buffer.extend(messages);
+        }
+    }
+}
+
+impl<'q, 'a> Drop for QueueStream<'q, 'a> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            ::log::warn!("Acking messages of {} failed: {}", self.queue.name, e);
+        }
+
+        // Reserved but never handed out, put back rather than left to
+        // their reservation timeout.
+        for message in mem::take(&mut self.buffer) {
+            let msg = self.queue.release_message(message, 0);
+            if !msg.contains("Released") {
+                ::log::warn!("Releasing message of {} failed: {}", self.queue.name, msg);
+            }
+        }
+    }
+}
+
+// Combinators for streams of reserved messages.
+pub trait ReservedStream: Stream<Item = ReservedMessage> + Sized {
+    // Acks every message as it's handed on: at-most-once delivery.
+    fn auto_ack(self) -> AutoAck<Self> {
+        AutoAck { stream: self }
+    }
+
+    // Acks the messages `handler` succeeds with and hands on its result.
+    // Messages it fails with are nacked, to be retried after `delay`.
+    fn process<F, T>(self, delay: u32, handler: F) -> Process<Self, F>
+    where
+        F: FnMut(&Message) -> Result<T, String>,
+    {
+        Process {
+            stream: self,
+            delay,
+            handler,
+        }
+    }
+}
+
+impl<S: Stream<Item = ReservedMessage>> ReservedStream for S {}
+
+pub struct AutoAck<S> {
+    stream: S,
+}
+
+impl<S: Stream<Item = ReservedMessage>> Stream for AutoAck<S> {
+    type Item = Message;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Message>, S::Error> {
+        match self.stream.poll()? {
+            Async::Ready(Some(reserved)) => {
+                let message = reserved.message().clone();
+                reserved.ack();
+                Ok(Async::Ready(Some(message)))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+pub struct Process<S, F> {
+    stream: S,
+    delay: u32,
+    handler: F,
+}
+
+impl<S, F, T> Stream for Process<S, F>
+where
+    S: Stream<Item = ReservedMessage>,
+    F: FnMut(&Message) -> Result<T, String>,
+{
+    type Item = T;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<T>, S::Error> {
+        loop {
+            let reserved = match self.stream.poll()? {
+                Async::Ready(Some(reserved)) => reserved,
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            match (self.handler)(&reserved) {
+                Ok(result) => {
+                    reserved.ack();
+                    return Ok(Async::Ready(Some(result)));
+                }
+                Err(e) => {
+                    ::log::warn!("Handling message {:?} failed: {}", reserved.id, e);
+                    reserved.nack(self.delay);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor::{self, Notify, NotifyHandle};
+    use http_client::middleware::{HttpRequest, HttpResponse, Middleware};
+    use test_server::{FakeServer, Response};
+
+    struct Noop;
+
+    impl Notify for Noop {
+        fn notify(&self, _id: usize) {}
+    }
+
+    #[derive(Default)]
+    struct Counter(Mutex<usize>);
+
+    impl Notify for Counter {
+        fn notify(&self, _id: usize) {
+            *self.0.lock().unwrap() += 1;
+        }
+    }
+
+    #[test]
+    fn reserves_as_messages_are_acked() {
+        let mut id = 0;
+        let server = FakeServer::start(move |request| {
+            if !request.path.ends_with("/reservations") {
+                return Response::ok("{\"msg\":\"Deleted\"}");
+            }
+
+            let count = request.json()["n"].as_u64().unwrap_or(1);
+            let messages: Vec<String> = (0..count)
+                .map(|_| {
+                    id += 1;
+                    format!("{{\"id\":\"{}\",\"body\":\"m{}\",\"reservation_id\":\"r\"}}", id, id)
+                })
+                .collect();
+            Response::ok(&format!("{{\"messages\":[{}]}}", messages.join(",")))
+        });
+
+        let mut client = server.client();
+        let mut queue = client.queue("jobs".to_string());
+        let mut options = StreamOptions::new();
+        options.max_unacked(2).wait(0);
+
+        let notify = NotifyHandle::from(Arc::new(Noop));
+        let mut stream = executor::spawn(queue.stream(options));
+        let mut next = || match stream.poll_stream_notify(&notify, 0).unwrap() {
+            Async::Ready(Some(message)) => Some(message),
+            _ => None,
+        };
+
+        let first = next().unwrap();
+        let second = next().unwrap();
+        assert_eq!(second.body, "m2");
+        assert!(next().is_none());
+
+        first.ack();
+        let third = next().unwrap();
+        assert_eq!(third.body, "m3");
+        drop(second);
+        drop(third);
+        drop(stream);
+
+        let requests: Vec<String> = server
+            .requests()
+            .iter()
+            .map(|request| format!("{} {}", request.method, request.json()["n"]))
+            .collect();
+        assert_eq!(requests, vec!["POST 2", "DELETE null", "POST 1"]);
+    }
+
+    #[test]
+    fn yields_after_empty_polls() {
+        let mut polls = 0;
+        let server = FakeServer::start(move |_| {
+            polls += 1;
+            if polls == 1 {
+                Response::ok("{\"messages\":[]}")
+            } else {
+                Response::ok("{\"messages\":[{\"id\":\"1\",\"body\":\"m\",\"reservation_id\":\"r\"}]}")
+            }
+        });
+        let mut client = server.client();
+        let mut queue = client.queue("jobs".to_string());
+        let mut options = StreamOptions::new();
+        options.wait(0);
+
+        let counter = Arc::new(Counter::default());
+        let notify = NotifyHandle::from(counter.clone());
+        let mut stream = executor::spawn(queue.stream(options).auto_ack());
+
+        assert!(stream.poll_stream_notify(&notify, 0).unwrap().is_not_ready());
+        assert_eq!(server.requests().len(), 1);
+        assert_eq!(*counter.0.lock().unwrap(), 1);
+        match stream.poll_stream_notify(&notify, 0).unwrap() {
+            Async::Ready(Some(message)) => assert_eq!(message.body, "m"),
+            _ => panic!("No message after the empty poll"),
+        }
+    }
+
+    #[test]
+    fn releases_buffered_messages_on_drop() {
+        let server = FakeServer::start(|request| {
+            if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else {
+                Response::ok("{\"messages\":[{\"id\":\"1\",\"body\":\"a\",\"reservation_id\":\"r1\"},{\"id\":\"2\",\"body\":\"b\",\"reservation_id\":\"r2\"}]}")
+            }
+        });
+        let mut client = server.client();
+        let mut queue = client.queue("jobs".to_string());
+
+        let notify = NotifyHandle::from(Arc::new(Noop));
+        let mut stream = executor::spawn(queue.stream(StreamOptions::new()));
+        let first = match stream.poll_stream_notify(&notify, 0).unwrap() {
+            Async::Ready(Some(message)) => message,
+            _ => panic!("No message"),
+        };
+        drop(stream);
+
+        assert_eq!(first.body, "a");
+        let lines = server.lines();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1], "POST /3/projects/project/queues/jobs/messages/2/release");
+    }
+
+    struct Tag;
+
+    impl Middleware for Tag {
+        fn on_request(&mut self, request: &mut HttpRequest) -> Option<HttpResponse> {
+            request.set_header("X-Tag", "stream");
+            None
+        }
+    }
+
+    #[test]
+    fn runs_middleware() {
+        let server = FakeServer::constant("200 OK", "{\"messages\":[{\"id\":\"1\",\"body\":\"m\",\"reservation_id\":\"r\"}]}");
+        let mut client = server.client();
+        client.middleware(Box::new(Tag));
+        let mut queue = client.queue("jobs".to_string());
+
+        let notify = NotifyHandle::from(Arc::new(Noop));
+        let mut stream = executor::spawn(queue.stream(StreamOptions::new()).auto_ack());
+        assert!(stream.poll_stream_notify(&notify, 0).unwrap().is_ready());
+        drop(stream);
+
+        let requests = server.requests();
+        assert!(requests.len() >= 2);
+        assert!(requests.iter().all(|request| request.header("X-Tag") == Some("stream")));
+    }
+}
@@ -0,0 +1,340 @@
+use std::collections::{HashSet, VecDeque};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use super::Queue;
+use super::message::Message;
+
+const DEFAULT_BATCH_SIZE: u8 = 100;
+const DEFAULT_INTERVAL: u64 = 1;
+const DEFAULT_WAIT: u32 = 30;
+const SEEN_CAPACITY: usize = 10_000;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TailMode {
+    Peek,
+    Reserve { delete: bool },
+}
+
+pub struct TailOptions {
+    mode: TailMode,
+    batch_size: u8,
+    interval: Duration,
+    wait: u32,
+    filter: Option<BodyFilter>,
+}
+
+impl TailOptions {
+    pub fn new(mode: TailMode) -> TailOptions {
+        TailOptions {
+            mode,
+            batch_size: DEFAULT_BATCH_SIZE,
+            interval: Duration::from_secs(DEFAULT_INTERVAL),
+            wait: DEFAULT_WAIT,
+            filter: None,
+        }
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut TailOptions {
+        self.batch_size = batch_size;
+
+        self
+    }
+
+    pub fn interval(&mut self, interval: Duration) -> &mut TailOptions {
+        self.interval = interval;
+
+        self
+    }
+
+    pub fn wait(&mut self, wait: u32) -> &mut TailOptions {
+        self.wait = wait;
+
+        self
+    }
+
+    pub fn filter(&mut self, filter: BodyFilter) -> &mut TailOptions {
+        self.filter = Some(filter);
+
+        self
+    }
+}
+
+// Matches message bodies by a JSON path such as `$.order.items[0].sku`,
+// optionally compared with a value: `$.status=failed`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BodyFilter {
+    path: Vec<Segment>,
+    value: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl BodyFilter {
+    pub fn parse(expression: &str) -> Result<BodyFilter, String> {
+        let (path, value) = match expression.find('=') {
+            Some(i) => (&expression[..i], Some(expression[i + 1..].to_string())),
+            None => (expression, None),
+        };
+
+        let path = path.trim();
+        let path = path.trim_start_matches('$').trim_start_matches('.');
+        let mut segments = Vec::new();
+
+        for part in path.split('.').filter(|p| !p.is_empty()) {
+            let mut rest = part;
+            if let Some(i) = rest.find('[') {
+                if i > 0 {
+                    segments.push(Segment::Key(rest[..i].to_string()));
+                }
+                rest = &rest[i..];
+                while rest.starts_with('[') {
+                    let end = rest
+                        .find(']')
+                        .ok_or_else(|| format!("Unclosed index in filter: {}", expression))?;
+                    let index = rest[1..end]
+                        .parse()
+                        .map_err(|_| format!("Invalid index in filter: {}", expression))?;
+                    segments.push(Segment::Index(index));
+                    rest = &rest[end + 1..];
+                }
+                if !rest.is_empty() {
+                    return Err(format!("Invalid filter: {}", expression));
+                }
+            } else {
+                segments.push(Segment::Key(rest.to_string()));
+            }
+        }
+
+        Ok(BodyFilter { path: segments, value })
+    }
+
+    pub fn matches(&self, body: &str) -> bool {
+        let json: Value = match serde_json::from_str(body) {
+            Ok(json) => json,
+            Err(_) => return false,
+        };
+
+        let mut current = &json;
+        for segment in &self.path {
+            let next = match *segment {
+                Segment::Key(ref key) => current.get(key.as_str()),
+                Segment::Index(index) => current.get(index),
+            };
+            current = match next {
+                Some(next) => next,
+                None => return false,
+            };
+        }
+
+        match self.value {
+            Some(ref expected) => match *current {
+                Value::String(ref s) => s == expected,
+                ref other => serde_json::from_str::<Value>(expected)
+                    .map(|v| v == *other)
+                    .unwrap_or(false),
+            },
+            None => !current.is_null(),
+        }
+    }
+}
+
+pub struct Tail<'q, 'a: 'q> {
+    queue: &'q mut Queue<'a>,
+    options: TailOptions,
+    buffer: VecDeque<Message>,
+    seen: HashSet<String>,
+    seen_order: VecDeque<String>,
+    polled: bool,
+}
+
+impl<'a> Queue<'a> {
+    pub fn tail<'q>(&'q mut self, options: TailOptions) -> Tail<'q, 'a> {
+        Tail {
+            queue: self,
+            options,
+            buffer: VecDeque::new(),
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            polled: false,
+        }
+    }
+}
+
+impl<'q, 'a> Tail<'q, 'a> {
+    fn is_match(&self, message: &Message) -> bool {
+        match self.options.filter {
+            Some(ref filter) => filter.matches(&message.body),
+            None => true,
+        }
+    }
+
+    fn remember(&mut self, id: String) -> bool {
+        if self.seen.contains(&id) {
+            return false;
+        }
+
+        if self.seen_order.len() == SEEN_CAPACITY {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        self.seen.insert(id.clone());
+        self.seen_order.push_back(id);
+
+        true
+    }
+
+    fn peek(&mut self) -> Result<(), String> {
+        if self.polled {
+            thread::sleep(self.options.interval);
+        }
+        self.polled = true;
+
+        for message in self.queue.peek_messages(self.options.batch_size)? {
+            let id = message.id.clone().unwrap_or_default();
+            if self.remember(id) && self.is_match(&message) {
+                self.buffer.push_back(message);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reserve(&mut self, delete: bool) -> Result<(), String> {
+        let messages = self.queue.long_poll(
+            self.options.batch_size,
+            super::DEFAULT_TIMEOUT,
+            self.options.wait,
+            false,
+        )?;
+
+        if messages.is_empty() && self.options.wait == 0 {
+            thread::sleep(self.options.interval);
+        }
+
+        // Messages the filter skips are left to their reservation timeout:
+        // releasing them right away would make the next poll reserve them again.
+        let matching: Vec<Message> = messages.into_iter().filter(|m| self.is_match(m)).collect();
+
+        if delete && !matching.is_empty() {
+            let msg = self.queue.delete_messages(matching.clone());
+            if !msg.contains("Deleted") {
+                return Err(msg);
+            }
+        }
+        self.buffer.extend(matching);
+
+        Ok(())
+    }
+}
+
+impl<'q, 'a> Iterator for Tail<'q, 'a> {
+    type Item = Result<Message, String>;
+
+    fn next(&mut self) -> Option<Result<Message, String>> {
+        loop {
+            if let Some(message) = self.buffer.pop_front() {
+                return Some(Ok(message));
+            }
+
+            let result = match self.options.mode {
+                TailMode::Peek => self.peek(),
+                TailMode::Reserve { delete } => self.reserve(delete),
+            };
+
+            if let Err(e) = result {
+                return Some(Err(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{FakeServer, Response};
+
+    fn message(id: &str, body: &str) -> Value {
+        json!({ "id": id, "body": body, "reservation_id": format!("r{}", id) })
+    }
+
+    // Answers peeks and reservations with `batches`, one per request.
+    fn server(batches: Vec<Vec<Value>>) -> FakeServer {
+        let mut batches = batches.into_iter();
+        FakeServer::start(move |request| {
+            if request.method == "DELETE" {
+                return Response::ok(r#"{"msg":"Deleted"}"#);
+            }
+            Response::ok(&json!({ "messages": batches.next().unwrap_or_default() }).to_string())
+        })
+    }
+
+    #[test]
+    fn body_filter() {
+        let body = r#"{"status": "failed", "order": {"items": [{"sku": "A-1"}], "total": 12}}"#;
+
+        assert!(BodyFilter::parse("$.status").unwrap().matches(body));
+        assert!(BodyFilter::parse("$.status=failed").unwrap().matches(body));
+        assert!(BodyFilter::parse("order.items[0].sku=A-1").unwrap().matches(body));
+        assert!(BodyFilter::parse("$.order.total=12").unwrap().matches(body));
+        assert!(!BodyFilter::parse("$.status=done").unwrap().matches(body));
+        assert!(!BodyFilter::parse("$.order.items[1]").unwrap().matches(body));
+        assert!(!BodyFilter::parse("$.status").unwrap().matches("not json"));
+        assert!(BodyFilter::parse("$.items[x]").is_err());
+    }
+
+    #[test]
+    fn peeks_every_message_once() {
+        let server = server(vec![
+            vec![message("1", "a"), message("2", "b")],
+            vec![message("1", "a"), message("2", "b"), message("3", "c")],
+            vec![message("3", "c"), message("4", "d")],
+        ]);
+        let mut client = server.client();
+        let mut queue = client.queue("events".to_string());
+        let mut options = TailOptions::new(TailMode::Peek);
+        options.interval(Duration::from_millis(0));
+
+        let ids: Vec<String> = queue.tail(options).take(4).map(|m| m.unwrap().id.unwrap()).collect();
+
+        assert_eq!(ids, vec!["1", "2", "3", "4"]);
+        assert!(server.lines().iter().all(|line| line.starts_with("GET")));
+    }
+
+    #[test]
+    fn deletes_what_it_yields() {
+        let server = server(vec![
+            vec![message("1", r#"{"keep":true}"#), message("2", r#"{"keep":false}"#)],
+            vec![],
+            vec![message("3", r#"{"keep":true}"#)],
+        ]);
+        let mut client = server.client();
+        let mut queue = client.queue("events".to_string());
+        let mut options = TailOptions::new(TailMode::Reserve { delete: true });
+        options
+            .wait(0)
+            .interval(Duration::from_millis(0))
+            .filter(BodyFilter::parse("$.keep=true").unwrap());
+
+        let ids: Vec<String> = queue.tail(options).take(2).map(|m| m.unwrap().id.unwrap()).collect();
+
+        assert_eq!(ids, vec!["1", "3"]);
+        let deleted: Vec<Value> = server
+            .requests()
+            .iter()
+            .filter(|request| request.method == "DELETE")
+            .flat_map(|request| request.json()["ids"].as_array().cloned().unwrap_or_default())
+            .collect();
+        assert_eq!(
+            deleted,
+            vec![json!({ "id": "1", "reservation_id": "r1" }), json!({ "id": "3", "reservation_id": "r3" })]
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use super::message::Message;
+use super::queue_info::{PushInfo, QueueInfo, QueueSubscriber, QueueType};
+use metrics::ErrorKind;
+use Client;
+
+// IronMQ's own defaults for push queues.
+const DEFAULT_RETRIES: u32 = 3;
+const DEFAULT_RETRIES_DELAY: u32 = 60;
+
+pub enum SubscriberTarget {
+    // Messages are posted to this URL.
+    Http(String),
+    // Messages are pushed into this pull queue of the same project by
+    // IronMQ itself, without credentials in the subscriber URL. The queue is
+    // created if it doesn't exist.
+    Queue(String),
+}
+
+// A multicast queue: every message published is pushed to all subscribers.
+// The queue is created with the first subscriber, since IronMQ doesn't
+// allow push queues without subscribers.
+pub struct Topic<'a> {
+    client: &'a mut Client,
+    name: String,
+    retries: Option<u32>,
+    retries_delay: Option<u32>,
+    error_queue: Option<String>,
+}
+
+impl Client {
+    pub fn topic(&mut self, name: &str) -> Topic<'_> {
+        Topic {
+            client: self,
+            name: name.to_string(),
+            retries: None,
+            retries_delay: None,
+            error_queue: None,
+        }
+    }
+}
+
+impl<'a> Topic<'a> {
+    // Push settings are applied by the next `subscribe` or `unsubscribe`.
+    pub fn retries(&mut self, retries: u32) -> &mut Topic<'a> {
+        self.retries = Some(retries);
+
+        self
+    }
+
+    pub fn retries_delay(&mut self, retries_delay: u32) -> &mut Topic<'a> {
+        self.retries_delay = Some(retries_delay);
+
+        self
+    }
+
+    pub fn error_queue(&mut self, error_queue: &str) -> &mut Topic<'a> {
+        self.error_queue = Some(error_queue.to_string());
+
+        self
+    }
+
+    pub fn publish(&mut self, body: &str) -> Result<String, String> {
+        self.client.queue(self.name.clone()).push_string(body)
+    }
+
+    pub fn publish_message(&mut self, message: Message) -> Result<String, String> {
+        self.client.queue(self.name.clone()).push_message(message)
+    }
+
+    // Adds a subscriber, or points an existing one with the same name at the
+    // new target. Once the topic exists, only this subscriber is sent, so
+    // concurrent changes to the others aren't lost.
+    pub fn subscribe(&mut self, name: &str, target: SubscriberTarget) -> Result<QueueSubscriber, String> {
+        let url = match target {
+            SubscriberTarget::Http(url) => url,
+            SubscriberTarget::Queue(queue) => {
+                if lookup(self.client, &queue)?.is_none() {
+                    self.client.try_create_queue_with_config(&queue, &QueueInfo::new(queue.clone()))?;
+                }
+                queue_url(&queue)
+            }
+        };
+        let subscriber = QueueSubscriber::new(name, &url);
+
+        let info = match lookup(self.client, &self.name)? {
+            Some(info) => info,
+            None => {
+                let config = self.config(None, vec![subscriber.clone()]);
+                self.client.try_create_queue_with_config(&self.name, &config)?;
+                return Ok(subscriber);
+            }
+        };
+        self.subscribers_of(&info)?;
+        self.update_settings(info)?;
+
+        let msg = self.client.queue(self.name.clone()).add_subscribers(vec![subscriber.clone()]);
+        if !msg.contains("Updated") {
+            return Err(msg);
+        }
+
+        Ok(subscriber)
+    }
+
+    // Fails if `name` is the last subscriber: remove the topic instead.
+    pub fn unsubscribe(&mut self, name: &str) -> Result<(), String> {
+        let info = match lookup(self.client, &self.name)? {
+            Some(info) => info,
+            None => return Err(format!("Topic {} not found", self.name)),
+        };
+        let subscribers = self.subscribers_of(&info)?;
+        if !subscribers.iter().any(|s| s.name == name) {
+            return Err(format!("Topic {} has no subscriber {}", self.name, name));
+        }
+        self.update_settings(info)?;
+
+        let msg = self.client.queue(self.name.clone()).remove_subscribers(vec![QueueSubscriber::new(name, "")]);
+        if !msg.contains("Updated") {
+            return Err(msg);
+        }
+
+        Ok(())
+    }
+
+    pub fn subscribers(&mut self) -> Result<Vec<QueueSubscriber>, String> {
+        match lookup(self.client, &self.name)? {
+            Some(info) => self.subscribers_of(&info),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn delete(&mut self) {
+        self.client.queue(self.name.clone()).delete();
+    }
+
+    fn subscribers_of(&self, info: &QueueInfo) -> Result<Vec<QueueSubscriber>, String> {
+        match info.queue_type {
+            Some(QueueType::Multicast) => Ok(info.push.as_ref().map(|p| p.subscribers.clone()).unwrap_or_default()),
+            _ => Err(format!("{} is not a multicast queue", self.name)),
+        }
+    }
+
+    // Applies the push settings set on the topic, if any. The subscribers
+    // are left out of the update.
+    fn update_settings(&mut self, current: QueueInfo) -> Result<(), String> {
+        if self.retries.is_none() && self.retries_delay.is_none() && self.error_queue.is_none() {
+            return Ok(());
+        }
+
+        let config = self.config(current.push, Vec::new());
+        self.client.queue(self.name.clone()).update(&config)?;
+
+        Ok(())
+    }
+
+    // Multicast queue config with the topic's push settings. Settings not
+    // set on the topic keep their current values.
+    fn config(&self, current: Option<PushInfo>, subscribers: Vec<QueueSubscriber>) -> QueueInfo {
+        let push = PushInfo {
+            retries: self.retries
+                .or_else(|| current.as_ref().map(|p| p.retries))
+                .unwrap_or(DEFAULT_RETRIES),
+            retries_delay: self.retries_delay
+                .or_else(|| current.as_ref().map(|p| p.retries_delay))
+                .unwrap_or(DEFAULT_RETRIES_DELAY),
+            subscribers,
+            error_queue: self.error_queue
+                .clone()
+                .or_else(|| current.as_ref().map(|p| p.error_queue.clone()))
+                .unwrap_or_default(),
+        };
+
+        let mut config = QueueInfo::new(self.name.clone());
+        config.queue_type(QueueType::Multicast).push(push);
+
+        config
+    }
+}
+
+// Queue info, or `None` if there is no such queue.
+fn lookup(client: &mut Client, name: &str) -> Result<Option<QueueInfo>, String> {
+    match client.queue(name.to_string()).get_info() {
+        Ok(info) => Ok(Some(info)),
+        Err(ref e) if ErrorKind::classify(e) == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// IronMQ's subscriber URL for a queue of the topic's own project.
+fn queue_url(queue: &str) -> String {
+    format!("ironmq:///{}", queue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{FakeServer, Response};
+
+    const FANOUT: &str = "{\"queue\":{\"name\":\"fanout\",\"type\":\"multicast\",\"push\":{\"retries\":3,\"retries_delay\":60,\
+                          \"subscribers\":[{\"name\":\"a\",\"url\":\"http://a\"}]}}}";
+
+    // Serves `fanout` as `existing` and every other queue as not found.
+    fn server(existing: Option<&'static str>) -> FakeServer {
+        FakeServer::start(move |request| match (request.method.as_str(), existing) {
+            ("GET", Some(info)) if request.path.ends_with("/queues/fanout") => Response::ok(info),
+            ("GET", _) => Response::status("404 Not Found", "{\"msg\":\"Queue not found\"}"),
+            ("PUT", _) => Response::ok(&format!("{{\"queue\":{}}}", request.json()["queue"])),
+            ("PATCH", _) => Response::ok(FANOUT),
+            _ => Response::ok("{\"msg\":\"Updated\"}"),
+        })
+    }
+
+    #[test]
+    fn creates_topic_with_first_subscriber() {
+        let server = server(None);
+        let mut client = server.client();
+
+        client.topic("fanout").subscribe("b", SubscriberTarget::Http("http://b".to_string())).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(server.lines(), vec!["GET /3/projects/project/queues/fanout", "PUT /3/projects/project/queues/fanout"]);
+        let queue = &requests[1].json()["queue"];
+        assert_eq!(queue["type"], "multicast");
+        assert_eq!(queue["push"]["subscribers"], json!([{"name": "b", "url": "http://b"}]));
+    }
+
+    #[test]
+    fn adds_subscribers_to_existing_topic() {
+        let server = server(Some(FANOUT));
+        let mut client = server.client();
+
+        client.topic("fanout").subscribe("b", SubscriberTarget::Http("http://b".to_string())).unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            server.lines(),
+            vec!["GET /3/projects/project/queues/fanout", "POST /3/projects/project/queues/fanout/subscribers"]
+        );
+        assert_eq!(requests[1].json(), json!({"subscribers": [{"name": "b", "url": "http://b"}]}));
+    }
+
+    #[test]
+    fn updates_settings_without_subscribers() {
+        let server = server(Some(FANOUT));
+        let mut client = server.client();
+
+        client.topic("fanout").retries(5).unsubscribe("a").unwrap();
+
+        let requests = server.requests();
+        assert_eq!(
+            server.lines(),
+            vec![
+                "GET /3/projects/project/queues/fanout",
+                "PATCH /3/projects/project/queues/fanout",
+                "DELETE /3/projects/project/queues/fanout/subscribers",
+            ]
+        );
+        assert_eq!(requests[1].json()["queue"]["push"], json!({"retries": 5, "retries_delay": 60}));
+        assert_eq!(requests[2].json()["subscribers"][0]["name"], "a");
+    }
+
+    #[test]
+    fn rejects_unknown_subscribers() {
+        let server = server(Some(FANOUT));
+        let mut client = server.client();
+
+        assert!(client.topic("fanout").unsubscribe("b").is_err());
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[test]
+    fn queue_urls() {
+        assert_eq!(queue_url("audit"), "ironmq:///audit");
+    }
+
+    #[test]
+    fn push_info_without_error_queue() {
+        let push: PushInfo =
+            serde_json::from_str("{\"retries\":3,\"retries_delay\":60,\"subscribers\":[{\"name\":\"a\",\"url\":\"http://a\"}]}")
+                .unwrap();
+
+        assert_eq!(push.error_queue, "");
+        assert_eq!(push.subscribers[0].name, "a");
+        assert!(!serde_json::to_string(&push).unwrap().contains("error_queue"));
+    }
+}
@@ -0,0 +1,217 @@
+// A fake IronMQ for unit tests. Every request is read in full, whatever
+// its framing, recorded and answered with what `respond` makes of it.
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use Client;
+
+#[derive(Debug, Clone, Default)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    // Method and path, e.g. "DELETE /3/projects/project/queues/q/messages/1".
+    pub fn line(&self) -> String {
+        format!("{} {}", self.method, self.path)
+    }
+
+    // The queue the request is about, if any.
+    pub fn queue(&self) -> Option<&str> {
+        self.path.split("/queues/").nth(1).and_then(|rest| rest.split(['/', '?']).next())
+    }
+
+    pub fn json(&self) -> Value {
+        serde_json::from_str(&self.body).unwrap_or_default()
+    }
+}
+
+pub struct Response {
+    status: String,
+    body: String,
+    delay: Option<Duration>,
+}
+
+impl Response {
+    pub fn ok(body: &str) -> Response {
+        Response::status("200 OK", body)
+    }
+
+    pub fn status(status: &str, body: &str) -> Response {
+        Response {
+            status: status.to_string(),
+            body: body.to_string(),
+            delay: None,
+        }
+    }
+
+    // Answers only after `delay`, e.g. to make requests time out.
+    pub fn delayed(mut self, delay: Duration) -> Response {
+        self.delay = Some(delay);
+
+        self
+    }
+}
+
+pub struct FakeServer {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl FakeServer {
+    pub fn start<F>(respond: F) -> FakeServer
+    where
+        F: FnMut(&Request) -> Response + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let respond = Arc::new(Mutex::new(respond));
+
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let seen = seen.clone();
+                let respond = respond.clone();
+                // A thread per connection, so delayed answers don't hold up
+                // other requests.
+                thread::spawn(move || handle(stream, &seen, &respond));
+            }
+        });
+
+        FakeServer { addr, requests }
+    }
+
+    // Answers every request with `status` and `body`.
+    pub fn constant(status: &'static str, body: &'static str) -> FakeServer {
+        FakeServer::start(move |_| Response::status(status, body))
+    }
+
+    pub fn addr(&self) -> String {
+        self.addr.to_string()
+    }
+
+    // A client whose requests go to this server.
+    pub fn client(&self) -> Client {
+        client(&self.addr())
+    }
+
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    // Method and path of every request so far.
+    pub fn lines(&self) -> Vec<String> {
+        self.requests().iter().map(Request::line).collect()
+    }
+}
+
+pub fn client(addr: &str) -> Client {
+    let mut client = Client::new(addr.to_string(), "project".to_string(), "token".to_string());
+    client.base_path = format!("http://{}/3/projects/project/", addr);
+
+    client
+}
+
+// An address nothing listens on.
+pub fn closed_port() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    listener.local_addr().unwrap().to_string()
+}
+
+fn handle<F>(mut stream: TcpStream, seen: &Mutex<Vec<Request>>, respond: &Mutex<F>)
+where
+    F: FnMut(&Request) -> Response,
+{
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(10)));
+    let mut reader = BufReader::new(match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(_) => return,
+    });
+    let request = match read_request(&mut reader) {
+        Some(request) => request,
+        None => return,
+    };
+
+    seen.lock().unwrap().push(request.clone());
+    let response = (*respond.lock().unwrap())(&request);
+    if let Some(delay) = response.delay {
+        thread::sleep(delay);
+    }
+
+    let _ = write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        response.status,
+        response.body.len(),
+        response.body
+    );
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Option<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_string();
+    let path = parts.next()?.to_string();
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(colon) = line.find(':') {
+            headers.push((line[..colon].trim().to_string(), line[colon + 1..].trim().to_string()));
+        }
+    }
+
+    let mut request = Request {
+        method,
+        path,
+        headers,
+        body: String::new(),
+    };
+
+    let mut body = Vec::new();
+    if request.header("Transfer-Encoding").is_some_and(|te| te.eq_ignore_ascii_case("chunked")) {
+        loop {
+            let mut size = String::new();
+            reader.read_line(&mut size).ok()?;
+            let size = usize::from_str_radix(size.trim().split(';').next()?, 16).ok()?;
+            let mut chunk = vec![0; size + 2];
+            reader.read_exact(&mut chunk).ok()?;
+            if size == 0 {
+                break;
+            }
+            body.extend_from_slice(&chunk[..size]);
+        }
+    } else if let Some(length) = request.header("Content-Length").and_then(|l| l.parse().ok()) {
+        body.resize(length, 0);
+        reader.read_exact(&mut body).ok()?;
+    }
+    request.body = String::from_utf8_lossy(&body).to_string();
+
+    Some(request)
+}
@@ -0,0 +1,497 @@
+extern crate num_cpus;
+
+use std::collections::HashMap;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use signal_hook;
+
+use queue::message::Message;
+use Client;
+
+const DEFAULT_BATCH_SIZE: u8 = 10;
+const DEFAULT_RESERVATION_TIMEOUT: u32 = 60;
+const DEFAULT_WAIT: u32 = 1;
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 30;
+// How often the pool checks for finished messages and shutdown while it
+// isn't reserving.
+const POLL_INTERVAL: u64 = 100;
+const ERROR_BACKOFF: u64 = 1;
+
+type Handler = dyn Fn(&Message) -> Result<(), String> + Send + Sync;
+type RetryPolicy = dyn Fn(&str, &Message) -> Retry + Send + Sync;
+
+// What becomes of a message the handler failed with.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Retry {
+    // Released, to be handled again after this many seconds.
+    After(u32),
+    // Deleted, after pushing its body to the given queue if any.
+    GiveUp(Option<String>),
+}
+
+// Stops a running pool: it stops reserving and waits for in-flight
+// messages, as on SIGTERM or SIGINT.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.flag.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct WorkerStats {
+    // Handled and deleted.
+    pub processed: usize,
+    // The handler failed, released for another try.
+    pub failed: usize,
+    // Unfinished when the shutdown timeout ran out, released.
+    pub released: usize,
+}
+
+// Reserves messages from one or more queues and hands them to handler
+// threads. Reservations, deletes and releases all go through one client,
+// so its connections are shared by every handler.
+pub struct WorkerPool {
+    queues: Vec<String>,
+    threads: usize,
+    max_in_flight: usize,
+    batch_size: u8,
+    reservation_timeout: u32,
+    wait: u32,
+    retry_delay: u32,
+    retry_policy: Option<Box<RetryPolicy>>,
+    shutdown_timeout: Duration,
+    handle_signals: bool,
+    shutdown: ShutdownHandle,
+}
+
+impl WorkerPool {
+    pub fn new(queues: &[&str]) -> WorkerPool {
+        let threads = num_cpus::get();
+
+        WorkerPool {
+            queues: queues.iter().map(|q| q.to_string()).collect(),
+            threads,
+            max_in_flight: threads,
+            batch_size: DEFAULT_BATCH_SIZE,
+            reservation_timeout: DEFAULT_RESERVATION_TIMEOUT,
+            wait: DEFAULT_WAIT,
+            retry_delay: 0,
+            retry_policy: None,
+            shutdown_timeout: Duration::from_secs(DEFAULT_SHUTDOWN_TIMEOUT),
+            handle_signals: false,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    // Also consumes `queue`.
+    pub fn queue(&mut self, queue: &str) -> &mut WorkerPool {
+        if !self.queues.iter().any(|q| q == queue) {
+            self.queues.push(queue.to_string());
+        }
+
+        self
+    }
+
+    // Also sets the in-flight limit to the number of threads; set
+    // `max_in_flight` after it to let messages wait for a free thread.
+    pub fn threads(&mut self, threads: usize) -> &mut WorkerPool {
+        self.threads = threads.max(1);
+        self.max_in_flight = self.threads;
+
+        self
+    }
+
+    // Messages reserved and not yet deleted or released, over all queues.
+    pub fn max_in_flight(&mut self, max_in_flight: usize) -> &mut WorkerPool {
+        self.max_in_flight = max_in_flight.max(1);
+
+        self
+    }
+
+    pub fn batch_size(&mut self, batch_size: u8) -> &mut WorkerPool {
+        self.batch_size = batch_size.max(1);
+
+        self
+    }
+
+    // Handlers should finish well within it, or the message is redelivered
+    // while it's still being handled.
+    pub fn reservation_timeout(&mut self, reservation_timeout: u32) -> &mut WorkerPool {
+        self.reservation_timeout = reservation_timeout;
+
+        self
+    }
+
+    // Long-poll wait of each reservation. Finished messages are deleted
+    // between reservations, so keep it short.
+    pub fn wait(&mut self, wait: u32) -> &mut WorkerPool {
+        self.wait = wait;
+
+        self
+    }
+
+    // Delay of messages released after the handler failed.
+    pub fn retry_delay(&mut self, retry_delay: u32) -> &mut WorkerPool {
+        self.retry_delay = retry_delay;
+
+        self
+    }
+
+    // Decides, from its queue and the message, what becomes of a message
+    // the handler failed with, instead of releasing it after `retry_delay`.
+    pub fn retry_policy<F>(&mut self, retry_policy: F) -> &mut WorkerPool
+    where
+        F: Fn(&str, &Message) -> Retry + Send + Sync + 'static,
+    {
+        self.retry_policy = Some(Box::new(retry_policy));
+
+        self
+    }
+
+    pub fn shutdown_timeout(&mut self, shutdown_timeout: Duration) -> &mut WorkerPool {
+        self.shutdown_timeout = shutdown_timeout;
+
+        self
+    }
+
+    // Whether SIGTERM and SIGINT shut the pool down while `run` is going,
+    // off by default. signal-hook can't restore the default disposition, so
+    // once enabled the process keeps ignoring both signals after `run`
+    // returns: exit the process, or handle them yourself, afterwards.
+    pub fn handle_signals(&mut self, handle_signals: bool) -> &mut WorkerPool {
+        self.handle_signals = handle_signals;
+
+        self
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // Runs until shut down. A message is deleted when `handler` returns
+    // `Ok` and released when it fails or panics. The pool can be run again
+    // after a shutdown; a shutdown before `run` starts is forgotten.
+    pub fn run<F>(&self, client: &mut Client, handler: F) -> Result<WorkerStats, String>
+    where
+        F: Fn(&Message) -> Result<(), String> + Send + Sync + 'static,
+    {
+        if self.queues.is_empty() {
+            return Err("No queues to consume".to_string());
+        }
+        self.shutdown.flag.store(false, Ordering::SeqCst);
+
+        let mut signals = Vec::new();
+        if self.handle_signals {
+            for &signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+                let id = signal_hook::flag::register(signal, self.shutdown.flag.clone()).map_err(|e| e.to_string())?;
+                signals.push(id);
+            }
+        }
+
+        let (jobs, job_receiver) = mpsc::channel();
+        let (done_sender, done) = mpsc::channel();
+        let abandoned = Arc::new(AtomicBool::new(false));
+        let threads = self.spawn_handlers(Arc::new(handler), job_receiver, done_sender, abandoned.clone());
+
+        let retry_delay = self.retry_delay;
+        let default_policy = move |_: &str, _: &Message| Retry::After(retry_delay);
+        let mut run = Run {
+            client,
+            in_flight: HashMap::new(),
+            stats: WorkerStats::default(),
+            retry_policy: self.retry_policy.as_deref().unwrap_or(&default_policy),
+        };
+        self.consume(&mut run, &jobs, &done);
+
+        // Handler threads still busy at the shutdown timeout are left to
+        // finish on their own, the others stop once the jobs are closed.
+        drop(jobs);
+        if run.in_flight.is_empty() {
+            for thread in threads {
+                let _ = thread.join();
+            }
+        } else {
+            abandoned.store(true, Ordering::SeqCst);
+            run.release_all();
+        }
+
+        for id in signals {
+            signal_hook::unregister(id);
+        }
+
+        Ok(run.stats)
+    }
+
+    fn spawn_handlers(
+        &self,
+        handler: Arc<Handler>,
+        jobs: Receiver<(String, Message)>,
+        done: Sender<(String, Result<(), String>)>,
+        abandoned: Arc<AtomicBool>,
+    ) -> Vec<JoinHandle<()>> {
+        let jobs = Arc::new(Mutex::new(jobs));
+
+        let mut threads = Vec::with_capacity(self.threads);
+
+        for _ in 0..self.threads {
+            let handler = handler.clone();
+            let jobs = jobs.clone();
+            let done = done.clone();
+            let abandoned = abandoned.clone();
+
+            threads.push(thread::spawn(move || loop {
+                let job = jobs.lock().unwrap_or_else(|e| e.into_inner()).recv();
+                let (id, message) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                // Released at the shutdown timeout, someone else may have it.
+                if abandoned.load(Ordering::SeqCst) {
+                    break;
+                }
+
+                let result = panic::catch_unwind(AssertUnwindSafe(|| handler(&message)))
+                    .unwrap_or_else(|_| Err("Handler panicked".to_string()));
+                if done.send((id, result)).is_err() {
+                    break;
+                }
+            }));
+        }
+
+        threads
+    }
+
+    fn consume(&self, run: &mut Run, jobs: &Sender<(String, Message)>, done: &Receiver<(String, Result<(), String>)>) {
+        let mut next_queue = 0;
+        let mut deadline = None;
+
+        loop {
+            while let Ok((id, result)) = done.try_recv() {
+                run.finish(id, result);
+            }
+
+            if self.shutdown.is_shutdown() {
+                let deadline = *deadline.get_or_insert_with(|| Instant::now() + self.shutdown_timeout);
+                if run.in_flight.is_empty() || Instant::now() >= deadline {
+                    return;
+                }
+                run.wait_for(done, Duration::from_millis(POLL_INTERVAL));
+                continue;
+            }
+
+            let free = self.max_in_flight.saturating_sub(run.in_flight.len());
+            if free == 0 {
+                run.wait_for(done, Duration::from_millis(POLL_INTERVAL));
+                continue;
+            }
+
+            let queue = self.queues[next_queue % self.queues.len()].clone();
+            next_queue += 1;
+            let count = free.min(self.batch_size as usize) as u8;
+
+            let reserved = run.client
+                .queue(queue.clone())
+                .long_poll(count, self.reservation_timeout, self.wait, false);
+            let messages = match reserved {
+                Ok(messages) => messages,
+                Err(e) => {
+                    ::log::warn!("Reserving from {} failed: {}", queue, e);
+                    run.wait_for(done, Duration::from_secs(ERROR_BACKOFF));
+                    continue;
+                }
+            };
+
+            for message in messages {
+                let id = match message.id.clone() {
+                    Some(id) => id,
+                    None => continue,
+                };
+                run.in_flight.insert(id.clone(), (queue.clone(), message.clone()));
+                let _ = jobs.send((id, message));
+            }
+        }
+    }
+}
+
+struct Run<'c> {
+    client: &'c mut Client,
+    // Reserved messages by id, with their queue.
+    in_flight: HashMap<String, (String, Message)>,
+    stats: WorkerStats,
+    retry_policy: &'c RetryPolicy,
+}
+
+impl<'c> Run<'c> {
+    fn wait_for(&mut self, done: &Receiver<(String, Result<(), String>)>, timeout: Duration) {
+        if let Ok((id, result)) = done.recv_timeout(timeout) {
+            self.finish(id, result);
+        }
+    }
+
+    fn finish(&mut self, id: String, result: Result<(), String>) {
+        let (queue, message) = match self.in_flight.remove(&id) {
+            Some(entry) => entry,
+            None => return,
+        };
+
+        match result {
+            Ok(()) => {
+                let msg = self.client.queue(queue.clone()).delete_message(message);
+                if !msg.contains("Deleted") {
+                    ::log::warn!("Deleting message {} from {} failed: {}", id, queue, msg);
+                }
+                self.stats.processed += 1;
+            }
+            Err(e) => {
+                ::log::warn!("Handling message {} from {} failed: {}", id, queue, e);
+                self.stats.failed += 1;
+                let retry = (self.retry_policy)(&queue, &message);
+                self.retry(id, queue, message, retry);
+            }
+        }
+    }
+
+    fn retry(&mut self, id: String, queue: String, message: Message, retry: Retry) {
+        let dead = match retry {
+            Retry::After(delay) => {
+                let msg = self.client.queue(queue.clone()).release_message(message, delay);
+                if !msg.contains("Released") {
+                    ::log::warn!("Releasing message {} to {} failed: {}", id, queue, msg);
+                }
+                return;
+            }
+            Retry::GiveUp(dead) => dead,
+        };
+
+        if let Some(dead) = dead {
+            if let Err(e) = self.client.queue(dead.clone()).push_string(&message.body) {
+                // Left to its reservation timeout rather than lost.
+                ::log::warn!("Pushing message {} from {} to {} failed: {}", id, queue, dead, e);
+                return;
+            }
+        }
+
+        ::log::warn!("Giving up on message {} from {}", id, queue);
+        let msg = self.client.queue(queue.clone()).delete_message(message);
+        if !msg.contains("Deleted") {
+            ::log::warn!("Deleting message {} from {} failed: {}", id, queue, msg);
+        }
+    }
+
+    // Puts unfinished messages back right away rather than letting their
+    // reservations time out.
+    fn release_all(&mut self) {
+        for (id, (queue, message)) in self.in_flight.drain() {
+            let msg = self.client.queue(queue.clone()).release_message(message, 0);
+            if !msg.contains("Released") {
+                ::log::warn!("Releasing message {} to {} failed: {}", id, queue, msg);
+            }
+            self.stats.released += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_server::{FakeServer, Response};
+
+    #[test]
+    fn releases_unfinished_messages_on_shutdown() {
+        let mut reserved = false;
+        let server = FakeServer::start(move |request| {
+            if request.path.ends_with("/reservations") && !reserved {
+                reserved = true;
+                Response::ok("{\"messages\":[{\"id\":\"1\",\"body\":\"fast\",\"reservation_id\":\"r1\"},{\"id\":\"2\",\"body\":\"slow\",\"reservation_id\":\"r2\"}]}")
+            } else if request.path.ends_with("/reservations") {
+                Response::ok("{\"messages\":[]}")
+            } else if request.path.ends_with("/release") {
+                Response::ok("{\"msg\":\"Released\"}")
+            } else {
+                Response::ok("{\"msg\":\"Deleted\"}")
+            }
+        });
+        let mut client = server.client();
+
+        let mut pool = WorkerPool::new(&["jobs"]);
+        pool.threads(2)
+            .wait(0)
+            .handle_signals(false)
+            .shutdown_timeout(Duration::from_millis(100));
+        let shutdown = pool.shutdown_handle();
+
+        let stats = pool
+            .run(&mut client, move |message| {
+                if message.body == "slow" {
+                    thread::sleep(Duration::from_millis(500));
+                } else {
+                    shutdown.shutdown();
+                }
+                Ok(())
+            })
+            .unwrap();
+
+        assert_eq!(stats, WorkerStats { processed: 1, failed: 0, released: 1 });
+        let lines = server.lines();
+        assert!(lines.contains(&"DELETE /3/projects/project/queues/jobs/messages/1".to_string()));
+        assert!(lines.contains(&"POST /3/projects/project/queues/jobs/messages/2/release".to_string()));
+    }
+
+    #[test]
+    fn runs_again_after_shutdown() {
+        let server = FakeServer::constant("200 OK", "{\"messages\":[{\"id\":\"1\",\"body\":\"b\",\"reservation_id\":\"r\"}],\"msg\":\"Deleted\"}");
+        let mut client = server.client();
+
+        let mut pool = WorkerPool::new(&["jobs"]);
+        pool.threads(1).wait(0);
+
+        for _ in 0..2 {
+            let shutdown = pool.shutdown_handle();
+            let stats = pool
+                .run(&mut client, move |_| {
+                    shutdown.shutdown();
+                    Ok(())
+                })
+                .unwrap();
+
+            assert_eq!(stats.processed, 1);
+        }
+    }
+
+    #[test]
+    fn joins_handler_threads() {
+        let server = FakeServer::constant("200 OK", "{\"messages\":[{\"id\":\"1\",\"body\":\"b\",\"reservation_id\":\"r\"}],\"msg\":\"Deleted\"}");
+        let mut client = server.client();
+
+        let mut pool = WorkerPool::new(&["jobs"]);
+        pool.threads(4).max_in_flight(1).wait(0);
+        let shutdown = pool.shutdown_handle();
+        // Every handler thread holds a clone of the handler, and with it of
+        // `alive`, until it stops.
+        let alive = Arc::new(());
+        let held = alive.clone();
+
+        pool.run(&mut client, move |_| {
+            let _ = &held;
+            shutdown.shutdown();
+            Ok(())
+        })
+        .unwrap();
+
+        assert_eq!(Arc::strong_count(&alive), 1);
+    }
+}
@@ -1,9 +1,19 @@
+extern crate futures;
 extern crate iron_mq_rust;
 use std::collections::HashMap;
+use futures::{Future, Sink, Stream};
 
 use iron_mq_rust::*;
 use iron_mq_rust::queue::queue_info::{ QueueInfo, Alert, AlertType, Direction, PushInfo, QueueSubscriber, QueueType };
 use iron_mq_rust::queue::message::Message;
+use iron_mq_rust::queue::backup::ExportMode;
+use iron_mq_rust::jobs::{Job, JobWorker};
+use iron_mq_rust::queue::ordered::{OrderedConsumer, OrderedEnvelope, OrderedProducer};
+use iron_mq_rust::queue::producer::{BatchingProducer, ProducerOptions};
+use iron_mq_rust::queue::rpc::{RpcClient, RpcServer};
+use iron_mq_rust::queue::stream::{ReservedStream, StreamOptions};
+use iron_mq_rust::queue::topic::SubscriberTarget;
+use iron_mq_rust::worker::WorkerPool;
 
 #[cfg(test)]
 mod tests {
@@ -416,4 +426,284 @@ mod tests {
         q.info();
     }
 
+    #[test]
+    fn move_queue() {
+        let mut source_mq = Client::from_env();
+        let mut dest_mq = Client::from_env();
+        let source_name = String::from("test-move-source");
+        let dest_name = String::from("test-move-dest");
+        source_mq.create_queue(&source_name);
+        dest_mq.create_queue(&dest_name);
+        let mut source = source_mq.queue(source_name);
+        let mut dest = dest_mq.queue(dest_name);
+        let _ids = source.push_strings(vec!["One", "Two", "Three"]).unwrap();
+
+        let progress = source.move_to(&mut dest).unwrap();
+
+        assert_eq!(progress.pushed, 3);
+        assert_eq!(progress.deleted, 3);
+        assert_eq!(source.peek_messages(10).unwrap().len(), 0);
+        assert_eq!(dest.peek_messages(10).unwrap().len(), 3);
+        source.delete();
+        dest.delete();
+    }
+
+    #[test]
+    fn copy_queue() {
+        let mut source_mq = Client::from_env();
+        let mut dest_mq = Client::from_env();
+        let source_name = String::from("test-copy-source");
+        let dest_name = String::from("test-copy-dest");
+        source_mq.create_queue(&source_name);
+        dest_mq.create_queue(&dest_name);
+        let mut source = source_mq.queue(source_name);
+        let mut dest = dest_mq.queue(dest_name);
+        let _ids = source.push_strings(vec!["One", "Two", "Three"]).unwrap();
+
+        let progress = source.copy_to(&mut dest).unwrap();
+
+        assert_eq!(progress.pushed, 3);
+        assert_eq!(progress.released, 3);
+        assert_eq!(source.peek_messages(10).unwrap().len(), 3);
+        assert_eq!(dest.peek_messages(10).unwrap().len(), 3);
+        source.delete();
+        dest.delete();
+    }
+
+    #[test]
+    fn export_and_import_queue() {
+        let mut mq = Client::from_env();
+        let queue_name = String::from("test-export");
+        mq.create_queue(&queue_name);
+        let mut backup: Vec<u8> = Vec::new();
+        {
+            let mut q = mq.queue(queue_name.clone());
+            let _ids = q.push_strings(vec!["One", "Two", "Three"]).unwrap();
+            let exported = q.export(&mut backup, ExportMode::Copy).unwrap();
+            assert_eq!(exported, 3);
+            assert_eq!(q.peek_messages(10).unwrap().len(), 3);
+        }
+
+        let imported = mq.import_as(&backup[..], "test-import").unwrap();
+        assert_eq!(imported, 3);
+
+        let mut restored = mq.queue(String::from("test-import"));
+        assert_eq!(restored.peek_messages(10).unwrap().len(), 3);
+        restored.delete();
+        mq.queue(queue_name).delete();
+    }
+
+    #[test]
+    fn rpc_call() {
+        let mut mq = Client::from_env();
+        mq.create_queue(&String::from("test-rpc-requests"));
+        mq.create_queue(&String::from("test-rpc-replies"));
+
+        let server = std::thread::spawn(|| {
+            let mut mq = Client::from_env();
+            let mut server = RpcServer::new(&mut mq, "test-rpc-requests");
+            server.wait(10);
+            server.serve_once(&mut |body: &str| Ok(body.to_uppercase())).unwrap()
+        });
+
+        let reply = RpcClient::new(&mut mq, "test-rpc-requests", "test-rpc-replies")
+            .timeout(std::time::Duration::from_secs(20))
+            .call("ping")
+            .unwrap();
+
+        assert_eq!(reply, "PING");
+        assert_eq!(server.join().unwrap(), 1);
+        mq.queue(String::from("test-rpc-requests")).delete();
+        mq.queue(String::from("test-rpc-replies")).delete();
+    }
+
+    #[test]
+    fn topic_fan_out() {
+        let mut mq = Client::from_env();
+
+        {
+            let mut topic = mq.topic("test-topic");
+            topic.retries(5);
+            topic.subscribe("http", SubscriberTarget::Http("http://www.subscriber1.com".to_string())).unwrap();
+            topic.subscribe("audit", SubscriberTarget::Queue("test-topic-audit".to_string())).unwrap();
+
+            let names: Vec<String> = topic.subscribers().unwrap().into_iter().map(|s| s.name).collect();
+            assert_eq!(names, vec!["http".to_string(), "audit".to_string()]);
+
+            topic.publish("event").unwrap();
+        }
+
+        let messages = mq.queue(String::from("test-topic-audit")).long_poll(1, 60, 10, true).unwrap();
+        assert_eq!(messages[0].body, "event");
+
+        {
+            let mut topic = mq.topic("test-topic");
+            topic.unsubscribe("http").unwrap();
+            assert_eq!(topic.subscribers().unwrap().len(), 1);
+            assert!(topic.unsubscribe("http").is_err());
+            assert_eq!(mq.queue(String::from("test-topic")).info().push.unwrap().retries, 5);
+        }
+
+        mq.topic("test-topic").delete();
+        mq.queue(String::from("test-topic-audit")).delete();
+    }
+
+    #[test]
+    fn worker_pool() {
+        let mut mq = Client::from_env();
+        let queue_name = String::from("test-worker-pool");
+        mq.create_queue(&queue_name);
+        mq.queue(queue_name.clone()).push_strings(vec!["1", "2", "3", "4", "5", "fail"]).unwrap();
+
+        let mut pool = WorkerPool::new(&["test-worker-pool"]);
+        pool.threads(3).handle_signals(false).retry_delay(60);
+        let shutdown = pool.shutdown_handle();
+        let handled = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = handled.clone();
+
+        let stats = pool.run(&mut mq, move |message| {
+            if counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 5 {
+                shutdown.shutdown();
+            }
+            if message.body == "fail" {
+                return Err("failed".to_string());
+            }
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(stats.processed, 5);
+        assert_eq!(stats.failed, 1);
+        assert_eq!(stats.released, 0);
+        mq.queue(queue_name).delete();
+    }
+
+    #[test]
+    fn stream_messages() {
+        let mut mq = Client::from_env();
+        let queue_name = String::from("test-stream");
+        mq.create_queue(&queue_name);
+        let mut q = mq.queue(queue_name);
+        q.push_strings(vec!["1", "2", "3"]).unwrap();
+
+        let mut bodies: Vec<String> = {
+            let mut options = StreamOptions::new();
+            options.max_unacked(2).wait(5);
+            q.stream(options)
+                .process(0, |message| Ok(message.body.clone()))
+                .take(3)
+                .wait()
+                .map(Result::unwrap)
+                .collect()
+        };
+        bodies.sort();
+
+        assert_eq!(bodies, vec!["1", "2", "3"]);
+        assert!(q.peek_messages(3).unwrap().is_empty());
+        q.delete();
+    }
+
+    #[test]
+    fn batching_producer() {
+        let mut mq = Client::from_env();
+        let queue_name = String::from("test-batching-producer");
+        mq.create_queue(&queue_name);
+
+        let producer = BatchingProducer::new("test-batching-producer", ProducerOptions::new(), Client::from_env);
+        let ids: Vec<_> = (0..150).map(|i| producer.push_string(&i.to_string())).collect();
+        producer.close().unwrap();
+        for id in ids {
+            assert!(!id.wait().unwrap().is_empty());
+        }
+
+        let producer = BatchingProducer::new("test-batching-producer", ProducerOptions::new(), Client::from_env);
+        let messages = (0..10).map(|i| Ok::<Message, String>(Message::with_body(&i.to_string())));
+        let (producer, _) = producer.send_all(futures::stream::iter_result(messages)).wait().unwrap();
+        producer.close().unwrap();
+
+        assert_eq!(mq.queue(queue_name.clone()).info().size, Some(160));
+        mq.queue(queue_name).delete();
+    }
+
+    #[test]
+    fn ordered_processing() {
+        let mut mq = Client::from_env();
+        let queue_name = String::from("test-ordered");
+        mq.create_queue(&queue_name);
+
+        {
+            let mut producer = OrderedProducer::new(&mut mq, "test-ordered");
+            for i in 1..4 {
+                producer.push("a", &i.to_string()).unwrap();
+                producer.push("b", &i.to_string()).unwrap();
+            }
+            assert_eq!(producer.last_sequence("a"), Some(3));
+        }
+
+        let mut handled: Vec<(String, String)> = Vec::new();
+        {
+            let mut consumer = OrderedConsumer::new(&mut mq, "test-ordered");
+            consumer.wait(5);
+            let mut handler = |envelope: &OrderedEnvelope| {
+                handled.push((envelope.key.clone(), envelope.body.clone()));
+                Ok(())
+            };
+            while consumer.next_sequence("a") < 4 || consumer.next_sequence("b") < 4 {
+                consumer.consume_once(&mut handler).unwrap();
+            }
+        }
+
+        for key in &["a", "b"] {
+            let bodies: Vec<&str> = handled.iter().filter(|h| h.0 == *key).map(|h| h.1.as_str()).collect();
+            assert_eq!(bodies, vec!["1", "2", "3"]);
+        }
+        mq.queue(queue_name).delete();
+    }
+
+    struct Greet;
+
+    impl Job for Greet {
+        type Payload = String;
+
+        const NAME: &'static str = "test-jobs-greet";
+
+        fn max_retries() -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn background_jobs() {
+        let mut mq = Client::from_env();
+        mq.create_queue(&String::from("test-jobs-greet"));
+        mq.enqueue::<Greet>(&"world".to_string()).unwrap();
+        mq.enqueue::<Greet>(&"nobody".to_string()).unwrap();
+
+        let mut worker = JobWorker::new();
+        worker.pool().threads(1).handle_signals(false);
+        worker.dead_queue("test-jobs-dead");
+        let shutdown = worker.pool().shutdown_handle();
+        let greeted = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = greeted.clone();
+        worker.register::<Greet, _>(move |name: String| {
+            let mut seen = seen.lock().unwrap();
+            seen.push(name.clone());
+            if seen.len() == 2 {
+                shutdown.shutdown();
+            }
+            if name == "nobody" {
+                return Err("no one to greet".to_string());
+            }
+            Ok(())
+        });
+
+        let stats = worker.run(&mut mq).unwrap();
+
+        assert_eq!(stats.processed, 1);
+        assert_eq!(stats.failed, 1);
+        let dead = mq.queue(String::from("test-jobs-dead")).pop_message().unwrap();
+        assert!(dead.body.contains("nobody"));
+        mq.queue(String::from("test-jobs-greet")).delete();
+        mq.queue(String::from("test-jobs-dead")).delete();
+    }
+
 }